@@ -0,0 +1,174 @@
+//! A mock HWI-compatible external signer, standing in for a hardware device.
+//!
+//! It answers `enumerate`, `getdescriptors` and `signtx` like HWI does, with
+//! keys derived from the hex seed in `MOCK_SIGNER_SEED`, or a fixed test seed.
+//! It signs for the BIP 84 account given with `--account`, 0 by default.
+//! Invalid arguments are answered with an HWI-style error object. Never use it
+//! with real funds.
+//!
+//! ```text
+//! $ cargo build --example mock_signer
+//! $ spacechains import-external-signer w1 target/debug/examples/mock_signer
+//! ```
+
+use std::str::FromStr;
+
+use bdk::{
+    bitcoin::{
+        psbt::PartiallySignedTransaction,
+        secp256k1::Secp256k1,
+        util::bip32::{DerivationPath, ExtendedPrivKey, ExtendedPubKey},
+        Network,
+    },
+    database::MemoryDatabase,
+    miniscript::{descriptor::DescriptorPublicKey, Descriptor},
+    SignOptions, Wallet,
+};
+use serde_json::{json, Value};
+
+const DEFAULT_SEED: &str = "000102030405060708090a0b0c0d0e0f";
+
+fn get_network(chain: &str) -> Result<Network, String> {
+    match chain {
+        "main" => Ok(Network::Bitcoin),
+        "test" => Ok(Network::Testnet),
+        "signet" => Ok(Network::Signet),
+        "regtest" => Ok(Network::Regtest),
+        _ => Err(format!("Unknown chain {}", chain)),
+    }
+}
+
+fn get_master_key(network: Network) -> Result<ExtendedPrivKey, String> {
+    let seed = std::env::var("MOCK_SIGNER_SEED").unwrap_or(DEFAULT_SEED.to_string());
+
+    let seed = hex::decode(seed).map_err(|err| format!("Invalid MOCK_SIGNER_SEED: {}", err))?;
+
+    ExtendedPrivKey::new_master(network, &seed)
+        .map_err(|err| format!("Invalid MOCK_SIGNER_SEED: {}", err))
+}
+
+/// Returns the private and public `wpkh` descriptors of a BIP 84 account
+/// keychain, with their key origin.
+fn get_account_descriptors(
+    network: Network,
+    account: u32,
+    keychain: u32,
+) -> Result<(String, String), String> {
+    let secp = Secp256k1::new();
+
+    let master_key = get_master_key(network)?;
+
+    let coin_type = match network {
+        Network::Bitcoin => 0,
+        _ => 1,
+    };
+
+    let path = format!("84h/{}h/{}h", coin_type, account);
+
+    let account_key = master_key
+        .derive_priv(
+            &secp,
+            &DerivationPath::from_str(&format!("m/{}", path)).unwrap(),
+        )
+        .unwrap();
+    let account_pubkey = ExtendedPubKey::from_priv(&secp, &account_key);
+
+    let origin = format!("[{}/{}]", master_key.fingerprint(&secp), path);
+
+    let private_descriptor = format!("wpkh({}{}/{}/*)", origin, account_key, keychain);
+
+    let public_descriptor = Descriptor::<DescriptorPublicKey>::from_str(&format!(
+        "wpkh({}{}/{}/*)",
+        origin, account_pubkey, keychain
+    ))
+    .unwrap();
+
+    Ok((private_descriptor, public_descriptor.to_string()))
+}
+
+fn enumerate(network: Network) -> Result<Value, String> {
+    let secp = Secp256k1::new();
+
+    let fingerprint = get_master_key(network)?.fingerprint(&secp);
+
+    Ok(json!([{"type": "mock", "model": "mock_signer", "fingerprint": fingerprint.to_string()}]))
+}
+
+fn get_descriptors(network: Network, account: u32) -> Result<Value, String> {
+    let (_, receive) = get_account_descriptors(network, account, 0)?;
+    let (_, internal) = get_account_descriptors(network, account, 1)?;
+
+    Ok(json!({"receive": [receive], "internal": [internal]}))
+}
+
+/// Signs every input derived from the keys of the account, without
+/// finalizing them.
+fn sign_tx(network: Network, account: u32, psbt: &str) -> Result<Value, String> {
+    let mut psbt = PartiallySignedTransaction::from_str(psbt)
+        .map_err(|err| format!("Invalid PSBT: {}", err))?;
+
+    let (receive, _) = get_account_descriptors(network, account, 0)?;
+    let (internal, _) = get_account_descriptors(network, account, 1)?;
+
+    let wallet = Wallet::new(
+        &receive,
+        Some(&internal),
+        network,
+        MemoryDatabase::default(),
+    )
+    .map_err(|err| err.to_string())?;
+
+    let sign_options = SignOptions {
+        try_finalize: false,
+        ..Default::default()
+    };
+
+    wallet
+        .sign(&mut psbt, sign_options)
+        .map_err(|err| err.to_string())?;
+
+    Ok(json!({"psbt": psbt.to_string()}))
+}
+
+fn run(args: Vec<String>) -> Result<Value, String> {
+    let mut chain = "main".to_string();
+    let mut account: u32 = 0;
+    let mut positional: Vec<String> = Vec::new();
+
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--chain" => chain = iter.next().ok_or("Missing value for --chain")?,
+            "--fingerprint" => {
+                iter.next().ok_or("Missing value for --fingerprint")?;
+            }
+            "--account" => {
+                let value = iter.next().ok_or("Missing value for --account")?;
+                account = value
+                    .parse()
+                    .map_err(|_| format!("Invalid account {}", value))?;
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let network = get_network(&chain)?;
+
+    match positional.first().map(String::as_str) {
+        Some("enumerate") => enumerate(network),
+        Some("getdescriptors") => get_descriptors(network, account),
+        Some("signtx") => {
+            let psbt = positional.get(1).ok_or("Missing PSBT for signtx")?;
+            sign_tx(network, account, psbt)
+        }
+        _ => Err("Unknown command".to_string()),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let result = run(args).unwrap_or_else(|err| json!({"error": err, "code": -1}));
+
+    println!("{}", result);
+}
@@ -0,0 +1,55 @@
+//! A mock ZMQ publisher, standing in for the `zmqpubhashblock` and
+//! `zmqpubrawtx` notifications of bitcoind.
+//!
+//! It binds to the given endpoint and publishes one notification per line
+//! of stdin, either `hashblock <block hash>` or `rawtx <transaction hex>`.
+//!
+//! ```text
+//! $ cargo build --example mock_zmq_publisher
+//! $ target/debug/examples/mock_zmq_publisher tcp://127.0.0.1:28332
+//! hashblock 000000000000000000024d2c6a1f3a6f1d2b...
+//! ```
+
+use std::io::BufRead;
+
+fn main() {
+    let endpoint = std::env::args()
+        .nth(1)
+        .unwrap_or("tcp://127.0.0.1:28332".to_string());
+
+    let context = zmq::Context::new();
+    let socket = context.socket(zmq::PUB).unwrap();
+    socket.bind(&endpoint).unwrap();
+
+    eprintln!("Publishing on {}", endpoint);
+
+    let mut sequence: u32 = 0;
+
+    for line in std::io::stdin().lock().lines() {
+        let line = line.unwrap();
+        let (topic, body) = match line.trim().split_once(' ') {
+            Some((topic, body)) => (topic.to_string(), body.trim().to_string()),
+            None => {
+                eprintln!("Expected `hashblock <hash>` or `rawtx <hex>`.");
+                continue;
+            }
+        };
+
+        let body = match hex::decode(&body) {
+            Ok(body) => body,
+            Err(err) => {
+                eprintln!("Invalid hex: {}", err);
+                continue;
+            }
+        };
+
+        let frames = [
+            topic.as_bytes().to_vec(),
+            body,
+            sequence.to_le_bytes().to_vec(),
+        ];
+        socket.send_multipart(frames, 0).unwrap();
+
+        sequence += 1;
+    }
+}
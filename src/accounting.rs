@@ -0,0 +1,218 @@
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bdk::bitcoin::Txid;
+use rusqlite::{params, Connection};
+use serde_json::{json, Value};
+
+use crate::config_file::ConfigFile;
+use crate::error::{Result, SpacechainError};
+use crate::explorer;
+
+pub const OUTCOME_BROADCAST: &str = "broadcast";
+pub const OUTCOME_COVENANT_NOT_FINAL: &str = "covenant_not_final";
+pub const OUTCOME_COVENANT_BROADCAST_FAILED: &str = "covenant_broadcast_failed";
+pub const OUTCOME_CPFP_BROADCAST_FAILED: &str = "cpfp_broadcast_failed";
+
+fn db_error(err: rusqlite::Error) -> SpacechainError {
+    SpacechainError::Keystore(err.to_string())
+}
+
+fn open_accounting_db(database_file: &PathBuf) -> Result<Connection> {
+    let conn = Connection::open(database_file).map_err(db_error)?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS mining_attempts (wallet_name TEXT NOT NULL, attempted_at INT NOT NULL, covenant_step INT NOT NULL, covenant_txid TEXT NOT NULL, cpfp_txid TEXT NOT NULL, fee_amount INT NOT NULL, outcome TEXT NOT NULL, won INT);",
+        [],
+    )
+    .map_err(db_error)?;
+
+    Ok(conn)
+}
+
+/// Records a `mine` attempt. Whether the CPFP transaction won is only known
+/// once the covenant step confirms, see `resolve_attempts`.
+pub fn record_attempt(
+    database_file: &PathBuf,
+    wallet_name: &str,
+    covenant_step: usize,
+    covenant_txid: &Txid,
+    cpfp_txid: &Txid,
+    fee_amount: u64,
+    outcome: &str,
+) -> Result<()> {
+    let conn = open_accounting_db(database_file)?;
+
+    let attempted_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+
+    conn.execute(
+        "INSERT INTO mining_attempts (wallet_name, attempted_at, covenant_step, covenant_txid, cpfp_txid, fee_amount, outcome) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            wallet_name,
+            attempted_at,
+            covenant_step,
+            covenant_txid.to_string(),
+            cpfp_txid.to_string(),
+            fee_amount,
+            outcome
+        ],
+    )
+    .map_err(db_error)?;
+
+    Ok(())
+}
+
+/// Marks the broadcast attempts whose covenant step has confirmed as won or
+/// lost, depending on whether our CPFP transaction is the one that confirmed.
+/// Steps whose CPFP transaction cannot be found are left unresolved.
+fn resolve_attempts(cfg: &ConfigFile, conn: &Connection) -> Result<()> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT DISTINCT covenant_step FROM mining_attempts WHERE outcome = ?1 AND won IS NULL",
+        )
+        .map_err(db_error)?;
+
+    let pending_steps: BTreeSet<usize> = stmt
+        .query_map([OUTCOME_BROADCAST], |row| row.get(0))
+        .map_err(db_error)?
+        .collect::<rusqlite::Result<_>>()
+        .map_err(db_error)?;
+
+    if pending_steps.is_empty() {
+        return Ok(());
+    }
+
+    for (step, cpfp_txid) in explorer::get_block_cpfp_txids(cfg, &pending_steps)? {
+        let cpfp_txid = match cpfp_txid {
+            Some(cpfp_txid) => cpfp_txid.to_string(),
+            None => continue,
+        };
+
+        conn.execute(
+            "UPDATE mining_attempts SET won = (cpfp_txid = ?1) WHERE covenant_step = ?2 AND outcome = ?3 AND won IS NULL",
+            params![cpfp_txid, step, OUTCOME_BROADCAST],
+        )
+        .map_err(db_error)?;
+    }
+
+    Ok(())
+}
+
+/// Marks the broadcast attempts of a covenant step as unresolved again,
+/// after a reorg changed the block that confirmed it.
+pub fn unresolve_attempts(database_file: &PathBuf, covenant_step: usize) -> Result<()> {
+    let conn = open_accounting_db(database_file)?;
+
+    conn.execute(
+        "UPDATE mining_attempts SET won = NULL WHERE covenant_step = ?1 AND outcome = ?2",
+        params![covenant_step, OUTCOME_BROADCAST],
+    )
+    .map_err(db_error)?;
+
+    Ok(())
+}
+
+/// Summarizes the `mine` attempts made between `since` and `until`
+/// (unix timestamps, inclusive), optionally for a single wallet.
+pub fn mining_report(
+    cfg: &ConfigFile,
+    database_file: &PathBuf,
+    wallet_name: &Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+) -> Result<Value> {
+    let conn = open_accounting_db(database_file)?;
+
+    resolve_attempts(cfg, &conn)?;
+
+    let mut stmt = conn
+        .prepare("SELECT outcome, won, fee_amount FROM mining_attempts WHERE attempted_at >= ?1 AND attempted_at <= ?2 AND (?3 IS NULL OR wallet_name = ?3)")
+        .map_err(db_error)?;
+
+    let attempts: Vec<(String, Option<bool>, u64)> = stmt
+        .query_map(
+            params![
+                since.unwrap_or(0),
+                until.unwrap_or(i64::MAX as u64),
+                wallet_name
+            ],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .map_err(db_error)?
+        .collect::<rusqlite::Result<_>>()
+        .map_err(db_error)?;
+
+    let attempt_count = attempts.len();
+    let mut broadcast: u64 = 0;
+    let mut wins: u64 = 0;
+    let mut losses: u64 = 0;
+    let mut pending: u64 = 0;
+    let mut total_fees_bid: u64 = 0;
+    let mut total_fees_paid: u64 = 0;
+
+    for (outcome, won, fee_amount) in attempts {
+        if outcome != OUTCOME_BROADCAST {
+            continue;
+        }
+
+        broadcast += 1;
+        total_fees_bid += fee_amount;
+
+        match won {
+            Some(true) => {
+                wins += 1;
+                total_fees_paid += fee_amount;
+            }
+            Some(false) => losses += 1,
+            None => pending += 1,
+        }
+    }
+
+    let average_winning_bid = total_fees_paid.checked_div(wins);
+
+    Ok(json!({
+        "attempts": attempt_count,
+        "broadcast": broadcast,
+        "wins": wins,
+        "losses": losses,
+        "pending": pending,
+        "total_fees_bid": total_fees_bid,
+        "total_fees_paid": total_fees_paid,
+        "average_winning_bid": average_winning_bid,
+    }))
+}
+
+/// Returns the wallet and CPFP transaction id of the broadcast attempts for
+/// a covenant step.
+pub fn load_broadcast_attempts(
+    database_file: &PathBuf,
+    covenant_step: usize,
+) -> Result<Vec<(String, Txid)>> {
+    let conn = open_accounting_db(database_file)?;
+
+    let mut stmt = conn
+        .prepare("SELECT wallet_name, cpfp_txid FROM mining_attempts WHERE covenant_step = ?1 AND outcome = ?2")
+        .map_err(db_error)?;
+
+    let attempts: Vec<(String, String)> = stmt
+        .query_map(params![covenant_step, OUTCOME_BROADCAST], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .map_err(db_error)?
+        .collect::<rusqlite::Result<_>>()
+        .map_err(db_error)?;
+
+    attempts
+        .into_iter()
+        .map(|(wallet_name, cpfp_txid)| {
+            let cpfp_txid = cpfp_txid.parse().map_err(|_| {
+                SpacechainError::Keystore(format!("Invalid CPFP transaction id {}.", cpfp_txid))
+            })?;
+            Ok((wallet_name, cpfp_txid))
+        })
+        .collect()
+}
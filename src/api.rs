@@ -0,0 +1,1056 @@
+//! Operations behind the CLI commands, for embedding the miner in other
+//! programs. Reports are returned as JSON values, as printed by the CLI.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bdk::{
+    bitcoin::{
+        hashes::hex::ToHex, psbt::PartiallySignedTransaction, secp256k1::Secp256k1,
+        util::bip32::Fingerprint, Address, Network, OutPoint, Transaction, Txid,
+    },
+    database::{Database, SqliteDatabase},
+    signer::SignerOrdering,
+    wallet::{export::FullyNodedExport, wallet_name_from_descriptor, AddressIndex},
+    KeychainKind, Wallet,
+};
+use serde_json::{json, Value};
+
+use crate::config_file::{ChainNetwork, ConfigFile};
+use crate::error::{BroadcastRejection, Result, SpacechainError};
+use crate::{
+    accounting, backup, covenant, explorer, external_signer, keystore, message, notifications,
+    utils, wallet_manager,
+};
+
+/// Number of times the CPFP transaction is broadcast while the node does not
+/// see the covenant transaction it spends.
+const CPFP_BROADCAST_ATTEMPTS: u32 = 6;
+
+/// Delay before broadcasting the CPFP transaction again, doubled after each
+/// attempt.
+const CPFP_BROADCAST_INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+/// Outcome of a mining attempt.
+#[derive(Debug)]
+pub enum MiningOutcome {
+    /// Every step of the covenant chain has been spent.
+    NoCovenantTransaction,
+    /// The previous covenant transaction is still unconfirmed.
+    CovenantNotFinal { previous_covenant_txid: Txid },
+    /// The CPFP transaction was not broadcast, because it was requested as a
+    /// PSBT or because it needs the signatures of other cosigners.
+    Psbt {
+        covenant_txid: Txid,
+        psbt: PartiallySignedTransaction,
+        complete: bool,
+    },
+    /// The covenant and CPFP transactions were broadcast.
+    Broadcast {
+        covenant_txid: Txid,
+        cpfp_txid: Txid,
+    },
+}
+
+/// Outcome of `broadcast_psbt`.
+#[derive(Debug)]
+pub enum BroadcastOutcome {
+    /// The PSBT was the CPFP transaction of a mining attempt.
+    Mining(MiningOutcome),
+    /// The PSBT was any other transaction.
+    Transaction { txid: Txid, fee: u64 },
+}
+
+/// Outcome of `consolidate`.
+#[derive(Debug)]
+pub enum ConsolidationOutcome {
+    /// The estimated fee rate, in sat/vB, is above the maximum.
+    FeeRateTooHigh {
+        fee_rate: f32,
+    },
+    /// There are fewer than two confirmed UTXOs below the threshold.
+    NothingToConsolidate,
+    Consolidated(Value),
+}
+
+/// Returns a mining outcome as JSON, with its `status`.
+pub fn mining_outcome_to_json(outcome: &MiningOutcome) -> Value {
+    match outcome {
+        MiningOutcome::NoCovenantTransaction => json!({"status": "no_covenant_transaction"}),
+        MiningOutcome::CovenantNotFinal {
+            previous_covenant_txid,
+        } => json!({
+            "status": "covenant_not_final",
+            "previous_covenant_transaction_id": previous_covenant_txid,
+        }),
+        MiningOutcome::Psbt {
+            covenant_txid,
+            psbt,
+            complete,
+        } => json!({
+            "status": "psbt",
+            "covenant_transaction_id": covenant_txid,
+            "cpfp_transaction_id": psbt.unsigned_tx.txid(),
+            "psbt": psbt.to_string(),
+            "complete": complete,
+        }),
+        MiningOutcome::Broadcast {
+            covenant_txid,
+            cpfp_txid,
+        } => json!({
+            "status": "broadcast",
+            "covenant_transaction_id": covenant_txid,
+            "cpfp_transaction_id": cpfp_txid,
+        }),
+    }
+}
+
+fn parse_address(address: &str) -> Result<Address> {
+    Address::from_str(address)
+        .map_err(|err| SpacechainError::InvalidInput(format!("Invalid address {}: {}", address, err)))
+}
+
+/// Loads a wallet from the keystore without syncing it. Returns the wallet,
+/// the name of its Bitcoin Core wallet and its birthdate.
+pub fn load_user_wallet(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+) -> Result<(Wallet<SqliteDatabase>, String, u64)> {
+    let path = utils::get_keystore_db_path(cfg)?;
+
+    let (external_descriptor, internal_descriptor, birthdate) =
+        keystore::load_descriptors(&path, &wallet_name.to_string())?
+            .ok_or_else(|| SpacechainError::WalletNotFound(wallet_name.to_string()))?;
+
+    let network = cfg.get_network();
+
+    let rpc_wallet_name = wallet_name_from_descriptor(
+        &external_descriptor,
+        internal_descriptor.as_ref(),
+        network,
+        &Secp256k1::new(),
+    )?;
+
+    let mut wallet =
+        wallet_manager::load_wallet(cfg, &external_descriptor, &internal_descriptor)?;
+
+    if let Some((command, fingerprint, account)) =
+        keystore::load_external_signer(&path, wallet_name)?
+    {
+        let signer = external_signer::ExternalSigner::new(&command, fingerprint, network, account);
+        wallet.add_signer(
+            KeychainKind::External,
+            SignerOrdering::default(),
+            Arc::new(signer),
+        );
+    }
+
+    Ok((wallet, rpc_wallet_name, birthdate))
+}
+
+/// Records the height and time the wallet was last synced to, so that the
+/// next RPC sync starts from there instead of the birthdate.
+fn write_sync_checkpoint(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    wallet: &Wallet<SqliteDatabase>,
+) -> Result<()> {
+    let sync_time = wallet.database().get_sync_time()?;
+
+    if let Some(sync_time) = sync_time {
+        keystore::write_sync_checkpoint(
+            &utils::get_keystore_db_path(cfg)?,
+            wallet_name,
+            sync_time.block_time.height,
+            sync_time.block_time.timestamp,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Loads a wallet from the keystore and syncs it, starting from its last
+/// sync checkpoint or its birthdate.
+pub fn get_user_wallet(cfg: &ConfigFile, wallet_name: &str) -> Result<Wallet<SqliteDatabase>> {
+    let (wallet, rpc_wallet_name, birthdate) = load_user_wallet(cfg, wallet_name)?;
+
+    let start_time = keystore::load_sync_checkpoint(&utils::get_keystore_db_path(cfg)?, wallet_name)?
+        .map(|(_, timestamp)| timestamp)
+        .unwrap_or(birthdate);
+
+    utils::sync_wallet(cfg, rpc_wallet_name.as_str(), &wallet, Some(start_time))?;
+
+    write_sync_checkpoint(cfg, wallet_name, &wallet)?;
+
+    Ok(wallet)
+}
+
+pub fn rescan(cfg: &ConfigFile, wallet_name: &str, from_height: Option<u32>) -> Result<Value> {
+    let (wallet, rpc_wallet_name, birthdate) = load_user_wallet(cfg, wallet_name)?;
+
+    let start_time = match from_height {
+        Some(height) => utils::get_block_time(cfg, height)?,
+        None => birthdate,
+    };
+
+    utils::rescan_wallet(cfg, rpc_wallet_name.as_str(), &wallet, start_time)?;
+
+    write_sync_checkpoint(cfg, wallet_name, &wallet)?;
+
+    Ok(
+        json!({"start_time": start_time, "sync_height": wallet.database().get_sync_time()?.map(|sync_time| sync_time.block_time.height)}),
+    )
+}
+
+/// Exports the descriptors of a wallet in the Bitcoin Core format.
+pub fn export_wallet(cfg: &ConfigFile, wallet_name: &str) -> Result<Value> {
+    let wallet = get_user_wallet(cfg, wallet_name)?;
+
+    let export = FullyNodedExport::export_wallet(&wallet, wallet_name, true)
+        .map_err(|err| SpacechainError::Wallet(bdk::Error::Generic(err.to_string())))?;
+
+    Ok(json!(export))
+}
+
+pub fn backup_to_file(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    file: &str,
+    encrypt: bool,
+) -> Result<Value> {
+    let wallet = get_user_wallet(cfg, wallet_name)?;
+
+    backup::write_backup(cfg, wallet_name, &wallet, file, encrypt)?;
+
+    Ok(json!({"file": file, "encrypted": encrypt}))
+}
+
+/// Restores a wallet from a backup bundle. Returns the name of the wallet.
+pub fn restore(cfg: &ConfigFile, file: &str, wallet_name: &Option<String>) -> Result<String> {
+    backup::restore_backup(cfg, file, wallet_name)
+}
+
+pub fn get_balance(cfg: &ConfigFile, wallet_name: &str) -> Result<Value> {
+    let wallet = get_user_wallet(cfg, wallet_name)?;
+
+    let balance = wallet.get_balance()?;
+
+    Ok(
+        json!({"immature": balance.immature, "trusted_pending": balance.trusted_pending,
+        "untrusted_pending": balance.untrusted_pending, "confirmed": balance.confirmed}),
+    )
+}
+
+pub fn get_new_address(cfg: &ConfigFile, wallet_name: &str) -> Result<Value> {
+    get_address(cfg, wallet_name, false)
+}
+
+/// Reveals a new address, or returns the last revealed address that has not
+/// received funds if `unused` is set.
+pub fn get_address(cfg: &ConfigFile, wallet_name: &str, unused: bool) -> Result<Value> {
+    let wallet = get_user_wallet(cfg, wallet_name)?;
+
+    let address_index = if unused {
+        AddressIndex::LastUnused
+    } else {
+        AddressIndex::New
+    };
+
+    let addr = wallet.get_address(address_index)?;
+
+    Ok(json!({"address": addr.address.to_string(), "index": addr.index}))
+}
+
+pub fn list_addresses(cfg: &ConfigFile, wallet_name: &str) -> Result<Value> {
+    let wallet = get_user_wallet(cfg, wallet_name)?;
+
+    let addresses: Vec<Value> = wallet_manager::list_addresses(&wallet)?
+        .iter()
+        .map(|(keychain, index, address, used, balance)| {
+            json!({"keychain": keychain, "index": index, "address": address.to_string(), "used": used, "balance": balance})
+        })
+        .collect();
+
+    Ok(json!(addresses))
+}
+
+pub fn verify_address(cfg: &ConfigFile, wallet_name: &str, address: &str) -> Result<Value> {
+    let address = parse_address(address)?;
+
+    let (wallet, _, _) = load_user_wallet(cfg, wallet_name)?;
+
+    let obj = match wallet_manager::find_address(&wallet, &address, cfg.gap_limit)? {
+        Some((keychain, index)) => {
+            json!({"address": address.to_string(), "is_mine": true, "keychain": keychain, "index": index})
+        }
+        None => json!({"address": address.to_string(), "is_mine": false}),
+    };
+
+    Ok(obj)
+}
+
+/// Signs a message with the key of an address, or of the address that
+/// funded a CPFP transaction if `address` is a transaction id.
+pub fn sign_message(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    address: &str,
+    message: &str,
+) -> Result<Value> {
+    // Only looking up a CPFP transaction requires a synced wallet.
+    let (wallet, address) = match Address::from_str(address) {
+        Ok(address) => (load_user_wallet(cfg, wallet_name)?.0, address),
+        Err(_) => {
+            let cpfp_txid: Txid = address.parse().map_err(|_| {
+                SpacechainError::InvalidInput(format!(
+                    "Invalid address or transaction id {}.",
+                    address
+                ))
+            })?;
+            let wallet = get_user_wallet(cfg, wallet_name)?;
+            let address = message::find_funding_address(&wallet, &cpfp_txid)?;
+            (wallet, address)
+        }
+    };
+
+    let signature = message::sign_message(&wallet, &address, message, cfg.gap_limit)?;
+
+    Ok(json!({"address": address.to_string(), "message": message, "signature": signature}))
+}
+
+pub fn verify_message(address: &str, message: &str, signature: &str) -> Result<Value> {
+    let address = parse_address(address)?;
+
+    let valid = message::verify_message(&address, message, signature)?;
+
+    Ok(json!({"address": address.to_string(), "message": message, "valid": valid}))
+}
+
+pub fn create_wallet(cfg: &ConfigFile, wallet_name: &str) -> Result<()> {
+    keystore::create_new_wallet_desc(
+        &utils::get_keystore_db_path(cfg)?,
+        wallet_name,
+        cfg.get_network(),
+    )
+}
+
+pub fn create_signer_key(cfg: &ConfigFile, key_name: &str) -> Result<Value> {
+    let public_key = keystore::create_signer_key(
+        &utils::get_keystore_db_path(cfg)?,
+        key_name,
+        cfg.get_network(),
+    )?;
+
+    Ok(json!({"key_name": key_name, "public_key": public_key}))
+}
+
+pub fn create_multisig_wallet(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    threshold: usize,
+    cosigners: &[String],
+    signer_key: &Option<String>,
+    taproot: bool,
+) -> Result<()> {
+    keystore::create_multisig_wallet_desc(
+        &utils::get_keystore_db_path(cfg)?,
+        wallet_name,
+        threshold,
+        signer_key,
+        cosigners,
+        taproot,
+        cfg.get_network(),
+    )
+}
+
+pub fn import_external_signer(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    command: &str,
+    fingerprint: &Option<String>,
+    account: u32,
+    birthdate: u64,
+) -> Result<Value> {
+    let network = cfg.get_network();
+    let path = utils::get_keystore_db_path(cfg)?;
+
+    let fingerprint = match fingerprint {
+        Some(fingerprint) => Fingerprint::from_str(fingerprint).map_err(|_| {
+            SpacechainError::InvalidInput(format!("Invalid fingerprint {}.", fingerprint))
+        })?,
+        None => {
+            let fingerprints = external_signer::enumerate(command, network)?;
+            if fingerprints.len() != 1 {
+                return Err(SpacechainError::InvalidInput(format!(
+                    "The external signer {} sees {} devices. Please choose one with --fingerprint.",
+                    command,
+                    fingerprints.len()
+                )));
+            }
+            fingerprints[0]
+        }
+    };
+
+    let (external_descriptor, internal_descriptor) =
+        external_signer::get_descriptors(command, &fingerprint, network, account)?;
+
+    keystore::import_wallet_desc(
+        &path,
+        wallet_name,
+        &external_descriptor,
+        &Some(internal_descriptor),
+        birthdate,
+    )?;
+
+    keystore::write_external_signer(
+        &path,
+        wallet_name,
+        command,
+        &fingerprint,
+        account,
+    )?;
+
+    Ok(
+        json!({"wallet_name": wallet_name, "fingerprint": fingerprint.to_string(), "external_descriptor": external_descriptor}),
+    )
+}
+
+pub fn import_wallet(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    external_descriptor: &String,
+    internal_descriptor: &Option<String>,
+    birthdate: u64,
+) -> Result<()> {
+    keystore::import_wallet_desc(
+        &utils::get_keystore_db_path(cfg)?,
+        wallet_name,
+        external_descriptor,
+        internal_descriptor,
+        birthdate,
+    )
+}
+
+/// Sends `amount` to `address`, or the whole balance of the selected coins
+/// if `amount` is `None`. Reserved mining UTXOs are only spent when given
+/// in `utxos`. With `dry_run`, returns the signed PSBT instead.
+#[allow(clippy::too_many_arguments)]
+pub fn send(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    address: &str,
+    amount: Option<u64>,
+    fee_rate: Option<f32>,
+    utxos: &[OutPoint],
+    utxo_exclude: &[OutPoint],
+    rbf: bool,
+    dry_run: bool,
+) -> Result<Value> {
+    let network = cfg.get_network();
+
+    let address = parse_address(address)?;
+
+    if !address.is_valid_for_network(network) {
+        return Err(SpacechainError::InvalidInput(format!(
+            "Address {} is not valid for {}.",
+            address, network
+        )));
+    }
+
+    let wallet = get_user_wallet(cfg, wallet_name)?;
+
+    let mining_utxos = keystore::load_mining_utxos(&utils::get_keystore_db_path(cfg)?, wallet_name)?;
+
+    let (psbt, details) = wallet_manager::create_send_transaction(
+        &wallet,
+        &address,
+        amount,
+        fee_rate,
+        utxos,
+        utxo_exclude,
+        &mining_utxos,
+        rbf,
+    )?;
+
+    if dry_run {
+        return Ok(
+            json!({"psbt": psbt.to_string(), "txid": details.txid, "fee": details.fee, "sent": details.sent, "received": details.received}),
+        );
+    }
+
+    let transaction = psbt.extract_tx();
+
+    utils::broadcast_tx(cfg, &transaction)?;
+
+    Ok(json!({"txid": transaction.txid(), "fee": details.fee}))
+}
+
+/// Lists the transactions of a wallet, annotating those that mined a
+/// spacechain block with the covenant step and the payload.
+pub fn list_transactions(cfg: &ConfigFile, wallet_name: &str) -> Result<Value> {
+    let wallet = get_user_wallet(cfg, wallet_name)?;
+
+    let mut covenant_steps: HashMap<Txid, usize> = HashMap::new();
+
+    if covenant::covenant_db_exists(cfg)? {
+        for (step, (_, tx)) in covenant::load_covenant_chain(cfg)?.iter().enumerate() {
+            covenant_steps.insert(tx.txid(), step);
+        }
+    }
+
+    let mut transactions = wallet.list_transactions(true)?;
+
+    // Confirmed transactions first, oldest to newest.
+    transactions.sort_by_key(|details| {
+        details
+            .confirmation_time
+            .as_ref()
+            .map(|confirmation_time| confirmation_time.height)
+            .unwrap_or(u32::MAX)
+    });
+
+    let mut txs: Vec<Value> = Vec::new();
+
+    for details in transactions {
+        // Transactions are listed with their raw transaction included.
+        let tx = details.transaction.as_ref().ok_or_else(|| {
+            SpacechainError::Wallet(bdk::Error::TransactionNotFound)
+        })?;
+
+        let covenant_step = tx
+            .input
+            .iter()
+            .find_map(|input| covenant_steps.get(&input.previous_output.txid));
+
+        let mut obj = json!({"txid": details.txid, "sent": details.sent, "received": details.received, "fee": details.fee,
+            "confirmation_height": details.confirmation_time.as_ref().map(|confirmation_time| confirmation_time.height),
+            "confirmation_time": details.confirmation_time.as_ref().map(|confirmation_time| confirmation_time.timestamp)});
+
+        if let Some(covenant_step) = covenant_step {
+            let payload = explorer::get_op_return_payload(tx);
+
+            obj["spacechain_step"] = json!(covenant_step);
+            obj["payload"] = json!(payload
+                .as_ref()
+                .map(|payload| String::from_utf8_lossy(payload).to_string()));
+            obj["payload_hex"] = json!(payload.as_ref().map(|payload| payload.to_hex()));
+        }
+
+        txs.push(obj);
+    }
+
+    Ok(json!(txs))
+}
+
+pub fn list_unspent(cfg: &ConfigFile, wallet_name: &str) -> Result<Value> {
+    let wallet = get_user_wallet(cfg, wallet_name)?;
+
+    let tip_height = wallet
+        .database()
+        .get_sync_time()?
+        .map(|sync_time| sync_time.block_time.height);
+
+    let mut utxos: Vec<Value> = Vec::new();
+
+    for utxo in wallet.list_unspent()? {
+        let confirmation_height = wallet
+            .get_tx(&utxo.outpoint.txid, false)?
+            .and_then(|details| details.confirmation_time)
+            .map(|confirmation_time| confirmation_time.height);
+
+        let confirmations = match (tip_height, confirmation_height) {
+            // The sync height lags behind transactions confirmed since,
+            // which still count one confirmation.
+            (Some(tip_height), Some(confirmation_height)) => {
+                tip_height.saturating_sub(confirmation_height) + 1
+            }
+            _ => 0,
+        };
+
+        utxos.push(
+            json!({"outpoint": utxo.outpoint.to_string(), "value": utxo.txout.value,
+            "confirmations": confirmations, "keychain": utxo.keychain}),
+        );
+    }
+
+    Ok(json!(utxos))
+}
+
+/// Merges the confirmed UTXOs below `threshold` sats, except reserved mining
+/// UTXOs, if the fee rate estimated for `target_blocks` is low enough.
+pub fn consolidate(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    threshold: u64,
+    max_fee_rate: f32,
+    target_blocks: usize,
+    dry_run: bool,
+) -> Result<ConsolidationOutcome> {
+    let fee_rate = utils::estimate_fee(cfg, target_blocks)?;
+
+    if fee_rate.as_sat_per_vb() > max_fee_rate {
+        return Ok(ConsolidationOutcome::FeeRateTooHigh {
+            fee_rate: fee_rate.as_sat_per_vb(),
+        });
+    }
+
+    let wallet = get_user_wallet(cfg, wallet_name)?;
+
+    let mining_utxos = keystore::load_mining_utxos(&utils::get_keystore_db_path(cfg)?, wallet_name)?;
+
+    let (confirmed_utxos, _) = wallet_manager::split_utxos_by_confirmation(&wallet)?;
+
+    let utxos: Vec<OutPoint> = confirmed_utxos
+        .iter()
+        .filter(|utxo| utxo.txout.value < threshold && !mining_utxos.contains(&utxo.outpoint))
+        .map(|utxo| utxo.outpoint)
+        .collect();
+
+    if utxos.len() < 2 {
+        return Ok(ConsolidationOutcome::NothingToConsolidate);
+    }
+
+    let (psbt, details) =
+        wallet_manager::create_consolidation_transaction(&wallet, &utxos, fee_rate)?;
+
+    if dry_run {
+        return Ok(ConsolidationOutcome::Consolidated(
+            json!({"psbt": psbt.to_string(), "txid": details.txid, "fee": details.fee, "inputs": utxos.len()}),
+        ));
+    }
+
+    let transaction = psbt.extract_tx();
+
+    utils::broadcast_tx(cfg, &transaction)?;
+
+    Ok(ConsolidationOutcome::Consolidated(
+        json!({"txid": transaction.txid(), "fee": details.fee, "inputs": utxos.len()}),
+    ))
+}
+
+/// Splits confirmed coins into `count` outputs of `amount` sats, reserved to
+/// fund future CPFP transactions. Coins reserved by an earlier call are
+/// left untouched.
+pub fn prepare_mining(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    count: usize,
+    amount: u64,
+    fee_rate: Option<f32>,
+) -> Result<Value> {
+    let wallet = get_user_wallet(cfg, wallet_name)?;
+
+    let keystore_db_path = utils::get_keystore_db_path(cfg)?;
+
+    let mining_utxos = keystore::load_mining_utxos(&keystore_db_path, wallet_name)?;
+
+    let (transaction, outpoints) = wallet_manager::create_fanout_transaction(
+        &wallet,
+        count,
+        amount,
+        fee_rate,
+        &mining_utxos,
+    )?;
+
+    utils::broadcast_tx(cfg, &transaction)?;
+
+    keystore::write_mining_utxos(&keystore_db_path, wallet_name, &outpoints)?;
+
+    let outpoints: Vec<String> = outpoints.iter().map(ToString::to_string).collect();
+
+    Ok(json!({"txid": transaction.txid(), "mining_utxos": outpoints}))
+}
+
+pub fn set_label(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    label_type: &str,
+    reference: &str,
+    label: &str,
+) -> Result<Value> {
+    keystore::write_label(
+        &utils::get_keystore_db_path(cfg)?,
+        wallet_name,
+        label_type,
+        reference,
+        label,
+    )?;
+
+    Ok(json!({"type": label_type, "ref": reference, "label": label}))
+}
+
+pub fn get_label(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    label_type: &str,
+    reference: &str,
+) -> Result<Value> {
+    let label = keystore::load_label(
+        &utils::get_keystore_db_path(cfg)?,
+        wallet_name,
+        label_type,
+        reference,
+    )?;
+
+    Ok(json!({"type": label_type, "ref": reference, "label": label}))
+}
+
+pub fn list_labels(cfg: &ConfigFile, wallet_name: &str) -> Result<Value> {
+    let labels: Vec<Value> = keystore::load_labels(&utils::get_keystore_db_path(cfg)?, wallet_name)?
+        .iter()
+        .map(|(label_type, reference, label)| {
+            json!({"type": label_type, "ref": reference, "label": label})
+        })
+        .collect();
+
+    Ok(json!(labels))
+}
+
+pub fn export_labels(cfg: &ConfigFile, wallet_name: &str, file: &str) -> Result<Value> {
+    let labels = keystore::export_labels(&utils::get_keystore_db_path(cfg)?, wallet_name, file)?;
+
+    Ok(json!({"file": file, "labels": labels}))
+}
+
+pub fn import_labels(cfg: &ConfigFile, wallet_name: &str, file: &str) -> Result<Value> {
+    let labels = keystore::import_labels(&utils::get_keystore_db_path(cfg)?, wallet_name, file)?;
+
+    Ok(json!({"file": file, "labels": labels}))
+}
+
+pub fn export_covenant(cfg: &ConfigFile, file: &str) -> Result<Value> {
+    let steps = covenant::export_covenant(cfg, file)?;
+
+    Ok(json!({"file": file, "steps": steps}))
+}
+
+pub fn import_covenant(cfg: &ConfigFile, file: &str) -> Result<Value> {
+    let steps = covenant::import_covenant(cfg, file)?;
+
+    Ok(json!({"file": file, "steps": steps}))
+}
+
+pub fn set_covenant_network(cfg: &ConfigFile, network: ChainNetwork) -> Result<Value> {
+    covenant::set_covenant_network(cfg, network.into())?;
+
+    Ok(json!({"network": network}))
+}
+
+/// Moves the wallets of versions without per-network directories into the
+/// directory of `network`, which must be the configured one. A legacy
+/// `convenant.db` in the current directory must not record another network.
+pub fn migrate_data_dir(cfg: &ConfigFile, network: ChainNetwork) -> Result<Value> {
+    let network: Network = network.into();
+
+    if network != cfg.get_network() {
+        return Err(SpacechainError::Config(format!(
+            "The configured network is {}. Use a config file for {} to move the wallets there.",
+            cfg.get_network(),
+            network
+        )));
+    }
+
+    if let Some(covenant_network) = covenant::get_legacy_covenant_network()? {
+        if covenant_network != network.to_string() {
+            return Err(SpacechainError::CovenantDb(format!(
+                "The convenant.db of the current directory was generated for {}, not {}.",
+                covenant_network, network
+            )));
+        }
+    }
+
+    let moved = utils::migrate_legacy_data_dir(cfg)?;
+
+    Ok(json!({"network": network.to_string(), "data_dir": utils::get_data_dir(cfg)?, "moved": moved}))
+}
+
+pub fn list_blocks(cfg: &ConfigFile, from: Option<usize>, to: Option<usize>) -> Result<Value> {
+    Ok(json!(explorer::list_blocks(cfg, from, to)?))
+}
+
+pub fn show_block(cfg: &ConfigFile, block: &str) -> Result<Option<Value>> {
+    explorer::show_block(cfg, block)
+}
+
+pub fn mining_report(
+    cfg: &ConfigFile,
+    wallet_name: &Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+) -> Result<Value> {
+    let path = utils::get_keystore_db_path(cfg)?;
+
+    accounting::mining_report(cfg, &path, wallet_name, since, until)
+}
+
+/// Adds the signatures of a wallet to `psbt`. Returns whether it is complete.
+pub fn sign_psbt(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    psbt: &mut PartiallySignedTransaction,
+) -> Result<bool> {
+    let (wallet, _, _) = load_user_wallet(cfg, wallet_name)?;
+
+    wallet_manager::sign_psbt(&wallet, psbt)
+}
+
+/// Finalizes and broadcasts a PSBT. A mining PSBT is broadcast along with
+/// its covenant transaction, like `mine` does.
+pub fn broadcast_psbt(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    mut psbt: PartiallySignedTransaction,
+) -> Result<BroadcastOutcome> {
+    let (wallet, _, _) = load_user_wallet(cfg, wallet_name)?;
+
+    if !wallet_manager::sign_psbt(&wallet, &mut psbt)? {
+        return Err(SpacechainError::Signing(
+            "The PSBT does not have enough signatures to be finalized.".to_string(),
+        ));
+    }
+
+    let fee_amount = wallet_manager::get_psbt_fee(&psbt)?;
+
+    let transaction = psbt.extract_tx();
+
+    // A mining PSBT spends an anchor output of a covenant transaction, which
+    // has to be broadcast first.
+    let mut covenant_step: Option<usize> = None;
+
+    if covenant::covenant_db_exists(cfg)? {
+        for input in transaction.input.iter() {
+            covenant_step = covenant::get_covenant_step(cfg, &input.previous_output.txid)?;
+            if covenant_step.is_some() {
+                break;
+            }
+        }
+    }
+
+    match covenant_step {
+        Some(covenant_step) => {
+            let (previous_covenant_txid, covenant_transaction) =
+                covenant::load_covenant_chain(cfg)?.swap_remove(covenant_step);
+
+            let outcome = broadcast_mining_transactions(
+                cfg,
+                wallet_name,
+                &previous_covenant_txid,
+                &covenant_transaction,
+                &transaction,
+                fee_amount,
+            )?;
+
+            Ok(BroadcastOutcome::Mining(outcome))
+        }
+        None => {
+            utils::broadcast_tx(cfg, &transaction)?;
+
+            Ok(BroadcastOutcome::Transaction {
+                txid: transaction.txid(),
+                fee: fee_amount,
+            })
+        }
+    }
+}
+
+/// Sends the notifications of the spacechain blocks confirmed or reorged
+/// since the last call, and returns them.
+pub fn check_events(cfg: &ConfigFile) -> Result<Value> {
+    let events = notifications::check_events(cfg, &utils::get_keystore_db_path(cfg)?)?;
+
+    Ok(json!(events))
+}
+
+/// Returns the next unspent covenant transaction, with the id of the
+/// previous one.
+pub fn get_covenant_tip(cfg: &ConfigFile) -> Result<Option<(Txid, Transaction)>> {
+    let covenant_wallet = covenant::load_convenant_wallet_from_db(cfg)?;
+    utils::sync_wallet(cfg, "covenant", &covenant_wallet, None)?;
+
+    covenant::get_covenant_tx_from_db(cfg, &covenant_wallet)
+}
+
+/// Mines a new spacechain block: broadcasts the next covenant transaction
+/// and a CPFP child carrying `data_message` and paying `fee_amount`.
+/// With `psbt_only`, or if the wallet needs other cosigners, the CPFP
+/// transaction is returned as a PSBT instead.
+pub fn mine(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    data_message: &str,
+    fee_amount: u64,
+    psbt_only: bool,
+) -> Result<MiningOutcome> {
+    match get_covenant_tip(cfg)? {
+        Some(covenant_tip) => mine_covenant_tip(
+            cfg,
+            wallet_name,
+            data_message,
+            fee_amount,
+            psbt_only,
+            &covenant_tip,
+        ),
+        None => Ok(MiningOutcome::NoCovenantTransaction),
+    }
+}
+
+/// Mines the covenant tip returned by `get_covenant_tip`, like `mine`.
+pub fn mine_covenant_tip(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    data_message: &str,
+    fee_amount: u64,
+    psbt_only: bool,
+    covenant_tip: &(Txid, Transaction),
+) -> Result<MiningOutcome> {
+    let (previous_covenant_txid, covenant_transaction) = covenant_tip;
+
+    let bump_script = covenant::load_bump_script(cfg)?;
+
+    let cpfp_wallet = get_user_wallet(cfg, wallet_name)?;
+
+    let mining_utxos = keystore::load_mining_utxos(&utils::get_keystore_db_path(cfg)?, wallet_name)?;
+
+    let (cpfp_psbt, finalized) = wallet_manager::create_cpfp_transaction(
+        &cpfp_wallet,
+        data_message,
+        covenant_transaction,
+        &bump_script,
+        fee_amount,
+        &mining_utxos,
+    )?;
+
+    if psbt_only || !finalized {
+        return Ok(MiningOutcome::Psbt {
+            covenant_txid: covenant_transaction.txid(),
+            psbt: cpfp_psbt,
+            complete: finalized,
+        });
+    }
+
+    let cpfp_transaction = cpfp_psbt.extract_tx();
+
+    broadcast_mining_transactions(
+        cfg,
+        wallet_name,
+        previous_covenant_txid,
+        covenant_transaction,
+        &cpfp_transaction,
+        fee_amount,
+    )
+}
+
+/// Broadcasts a covenant transaction and its CPFP child, recording the
+/// attempt and labelling the CPFP transaction with the spacechain block.
+pub fn broadcast_mining_transactions(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    previous_covenant_txid: &Txid,
+    covenant_transaction: &Transaction,
+    cpfp_transaction: &Transaction,
+    fee_amount: u64,
+) -> Result<MiningOutcome> {
+    let covenant_step = covenant::get_covenant_step(cfg, &covenant_transaction.txid())?
+        .ok_or_else(|| {
+            SpacechainError::CovenantDb(
+                "The covenant transaction is not part of the covenant chain.".to_string(),
+            )
+        })?;
+
+    let keystore_db_path = utils::get_keystore_db_path(cfg)?;
+
+    let record_attempt = |outcome: &str| {
+        accounting::record_attempt(
+            &keystore_db_path,
+            wallet_name,
+            covenant_step,
+            &covenant_transaction.txid(),
+            &cpfp_transaction.txid(),
+            fee_amount,
+            outcome,
+        )
+    };
+
+    match utils::broadcast_tx(cfg, covenant_transaction) {
+        Ok(_) => {}
+        Err(SpacechainError::Broadcast {
+            rejection: Some(BroadcastRejection::NonFinal),
+            ..
+        }) => {
+            record_attempt(accounting::OUTCOME_COVENANT_NOT_FINAL)?;
+            return Ok(MiningOutcome::CovenantNotFinal {
+                previous_covenant_txid: *previous_covenant_txid,
+            });
+        }
+        Err(err) => {
+            record_attempt(accounting::OUTCOME_COVENANT_BROADCAST_FAILED)?;
+            return Err(err);
+        }
+    }
+
+    // The CPFP transaction is retried, backing off, until the node sees the
+    // covenant transaction it spends.
+    let mut delay = CPFP_BROADCAST_INITIAL_DELAY;
+    let mut attempt = 1;
+    loop {
+        match utils::broadcast_tx(cfg, cpfp_transaction) {
+            Ok(_) => break,
+            Err(SpacechainError::Broadcast {
+                rejection: Some(BroadcastRejection::MissingInputs),
+                ..
+            }) if attempt < CPFP_BROADCAST_ATTEMPTS => {
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(SpacechainError::Broadcast {
+                rejection: Some(BroadcastRejection::MissingInputs),
+                message,
+            }) => {
+                record_attempt(accounting::OUTCOME_CPFP_BROADCAST_FAILED)?;
+                return Err(SpacechainError::Broadcast {
+                    rejection: Some(BroadcastRejection::MissingInputs),
+                    message: format!(
+                        "The node still does not see covenant transaction {} after {} attempts: {}",
+                        covenant_transaction.txid(),
+                        CPFP_BROADCAST_ATTEMPTS,
+                        message
+                    ),
+                });
+            }
+            Err(err) => {
+                record_attempt(accounting::OUTCOME_CPFP_BROADCAST_FAILED)?;
+                return Err(err);
+            }
+        }
+    }
+
+    record_attempt(accounting::OUTCOME_BROADCAST)?;
+
+    notifications::notify(
+        cfg,
+        notifications::EVENT_CPFP_BROADCAST,
+        json!({
+            "wallet_name": wallet_name,
+            "covenant_step": covenant_step,
+            "covenant_txid": covenant_transaction.txid(),
+            "cpfp_txid": cpfp_transaction.txid(),
+            "fee_amount": fee_amount,
+        }),
+    );
+
+    keystore::write_label(
+        &keystore_db_path,
+        wallet_name,
+        "tx",
+        &cpfp_transaction.txid().to_string(),
+        &format!("spacechain block {}", covenant_step),
+    )?;
+
+    Ok(MiningOutcome::Broadcast {
+        covenant_txid: covenant_transaction.txid(),
+        cpfp_txid: cpfp_transaction.txid(),
+    })
+}
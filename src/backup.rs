@@ -0,0 +1,283 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use age::armor::{ArmoredReader, ArmoredWriter, Format};
+use age::secrecy::SecretString;
+use bdk::{
+    bitcoin::{util::bip32::Fingerprint, Network},
+    database::{Database, SqliteDatabase},
+    wallet::AddressIndex,
+    KeychainKind, Wallet,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Result, SpacechainError};
+use crate::{config_file::ConfigFile, keystore, utils, wallet_manager};
+
+/// Version of the backup bundle format written by `write_backup`.
+const BACKUP_VERSION: u32 = 2;
+
+/// Environment variable read for the bundle passphrase before prompting.
+pub const BACKUP_PASSPHRASE_ENV: &str = "SPACECHAINS_BACKUP_PASSPHRASE";
+
+/// The external signer row of a wallet whose keys are held by a device.
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupExternalSigner {
+    command: String,
+    fingerprint: String,
+    account: u32,
+}
+
+/// Everything needed to recreate a wallet: the keystore row, the external
+/// signer if any, the last revealed derivation indexes of the BDK database
+/// and the labels.
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupBundle {
+    version: u32,
+    wallet_name: String,
+    network: String,
+    external_descriptor: String,
+    internal_descriptor: Option<String>,
+    birthdate: u64,
+    /// Added in version 2.
+    #[serde(default)]
+    external_signer: Option<BackupExternalSigner>,
+    last_external_index: Option<u32>,
+    last_internal_index: Option<u32>,
+    labels: Vec<keystore::Bip329Label>,
+}
+
+fn read_passphrase() -> Result<SecretString> {
+    let passphrase = match std::env::var(BACKUP_PASSPHRASE_ENV) {
+        Ok(passphrase) => passphrase,
+        Err(_) => rpassword::prompt_password("Backup passphrase: ")
+            .map_err(|err| SpacechainError::io("passphrase prompt", err))?,
+    };
+
+    Ok(SecretString::new(passphrase))
+}
+
+/// Writes the backup bundle of a wallet to `file_path`, encrypted with a
+/// passphrase if `encrypt` is set.
+pub fn write_backup(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    wallet: &Wallet<SqliteDatabase>,
+    file_path: &str,
+    encrypt: bool,
+) -> Result<()> {
+    let path = utils::get_keystore_db_path(cfg)?;
+
+    let (external_descriptor, internal_descriptor, birthdate) =
+        keystore::load_descriptors(&path, &wallet_name.to_string())?
+            .ok_or_else(|| SpacechainError::WalletNotFound(wallet_name.to_string()))?;
+
+    let bundle = BackupBundle {
+        version: BACKUP_VERSION,
+        wallet_name: wallet_name.to_string(),
+        network: cfg.get_network().to_string(),
+        external_descriptor,
+        internal_descriptor,
+        birthdate,
+        external_signer: keystore::load_external_signer(&path, wallet_name)?.map(
+            |(command, fingerprint, account)| BackupExternalSigner {
+                command,
+                fingerprint: fingerprint.to_string(),
+                account,
+            },
+        ),
+        last_external_index: wallet.database().get_last_index(KeychainKind::External)?,
+        last_internal_index: wallet.database().get_last_index(KeychainKind::Internal)?,
+        labels: keystore::load_labels(&path, wallet_name)?
+            .into_iter()
+            .map(|(label_type, reference, label)| keystore::Bip329Label {
+                label_type,
+                reference,
+                label,
+            })
+            .collect(),
+    };
+
+    // Serializing strings and integers cannot fail.
+    let json = serde_json::to_string_pretty(&bundle).unwrap();
+
+    let io_error = |err| SpacechainError::io(file_path, err);
+
+    let mut file = File::create(file_path).map_err(io_error)?;
+
+    if encrypt {
+        let encryptor = age::Encryptor::with_user_passphrase(read_passphrase()?);
+        let armored = ArmoredWriter::wrap_output(file, Format::AsciiArmor).map_err(io_error)?;
+        let mut writer = encryptor
+            .wrap_output(armored)
+            .map_err(|err| SpacechainError::InvalidInput(format!("{}: {}", file_path, err)))?;
+        writer.write_all(json.as_bytes()).map_err(io_error)?;
+        writer
+            .finish()
+            .and_then(|armored| armored.finish())
+            .map_err(io_error)?;
+    } else {
+        file.write_all(json.as_bytes()).map_err(io_error)?;
+    }
+
+    Ok(())
+}
+
+fn read_backup(file_path: &str) -> Result<BackupBundle> {
+    let invalid_backup = |err: &dyn std::fmt::Display| {
+        SpacechainError::InvalidInput(format!("{}: {}", file_path, err))
+    };
+
+    let mut contents: Vec<u8> = Vec::new();
+    File::open(file_path)
+        .and_then(|mut file| file.read_to_end(&mut contents))
+        .map_err(|err| SpacechainError::io(file_path, err))?;
+
+    if contents.first() == Some(&b'{') {
+        return serde_json::from_slice(&contents).map_err(|err| invalid_backup(&err));
+    }
+
+    let decryptor = match age::Decryptor::new(ArmoredReader::new(&contents[..]))
+        .map_err(|err| invalid_backup(&err))?
+    {
+        age::Decryptor::Passphrase(decryptor) => decryptor,
+        _ => return Err(invalid_backup(&"not encrypted with a passphrase")),
+    };
+
+    let mut reader = decryptor
+        .decrypt(&read_passphrase()?, None)
+        .map_err(|err| invalid_backup(&err))?;
+
+    let mut json: Vec<u8> = Vec::new();
+    reader
+        .read_to_end(&mut json)
+        .map_err(|err| SpacechainError::io(file_path, err))?;
+
+    serde_json::from_slice(&json).map_err(|err| invalid_backup(&err))
+}
+
+/// Recreates a wallet from a backup bundle: the keystore row, the external
+/// signer, the BDK database with its derivation indexes, and the labels.
+/// Returns the name of the restored wallet.
+pub fn restore_backup(
+    cfg: &ConfigFile,
+    file_path: &str,
+    wallet_name: &Option<String>,
+) -> Result<String> {
+    let bundle = read_backup(file_path)?;
+
+    if bundle.version > BACKUP_VERSION {
+        return Err(SpacechainError::InvalidInput(format!(
+            "{} has backup format version {}, but this software only supports up to version {}.",
+            file_path, bundle.version, BACKUP_VERSION
+        )));
+    }
+
+    let network = cfg.get_network();
+
+    let bundle_network = Network::from_str(&bundle.network).map_err(|_| {
+        SpacechainError::InvalidInput(format!(
+            "{} has unknown network {}.",
+            file_path, bundle.network
+        ))
+    })?;
+    if bundle_network != network {
+        return Err(SpacechainError::InvalidInput(format!(
+            "{} is a backup of a {} wallet, but the configured network is {}.",
+            file_path, bundle_network, network
+        )));
+    }
+
+    let external_signer = match &bundle.external_signer {
+        Some(signer) => {
+            let fingerprint = Fingerprint::from_str(&signer.fingerprint).map_err(|_| {
+                SpacechainError::InvalidInput(format!(
+                    "{} has invalid external signer fingerprint {}.",
+                    file_path, signer.fingerprint
+                ))
+            })?;
+            Some((&signer.command, fingerprint, signer.account))
+        }
+        None => None,
+    };
+
+    let wallet_name = wallet_name.clone().unwrap_or(bundle.wallet_name);
+
+    let path = utils::get_keystore_db_path(cfg)?;
+
+    keystore::write_wallet_data(
+        &path,
+        &wallet_name,
+        &bundle.external_descriptor,
+        &bundle.internal_descriptor,
+        bundle.birthdate,
+    )?;
+
+    if let Some((command, fingerprint, account)) = external_signer {
+        keystore::write_external_signer(&path, &wallet_name, command, &fingerprint, account)?;
+    }
+
+    let wallet = wallet_manager::load_wallet(
+        cfg,
+        &bundle.external_descriptor,
+        &bundle.internal_descriptor,
+    )?;
+
+    if let Some(index) = bundle.last_external_index {
+        wallet.get_address(AddressIndex::Reset(index))?;
+    }
+
+    if let Some(index) = bundle.last_internal_index {
+        wallet.get_internal_address(AddressIndex::Reset(index))?;
+    }
+
+    for label in bundle.labels.iter() {
+        keystore::write_label(
+            &path,
+            &wallet_name,
+            &label.label_type,
+            &label.reference,
+            &label.label,
+        )?;
+    }
+
+    Ok(wallet_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_file::{self, Backend, ChainNetwork};
+
+    #[test]
+    fn restores_the_external_signer() {
+        let mut cfg = config_file::default_config(ChainNetwork::Testnet, Backend::Electrum);
+        cfg.datadir = std::env::temp_dir().join(format!(
+            "spacechains-test-backup-signer-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&cfg.datadir);
+
+        let path = utils::get_keystore_db_path(&cfg).unwrap();
+        let external_descriptor = "wpkh(tprv8ZgxMBicQKsPeDgjzdC36fs6bMjGApWDNLR9erAXMs5skhMv36j9MV5ecvfavji5khqjWaWSFhN3YcCUUdiKH6isR4Pwy3U5y5egddBr16m/84'/1'/0'/0/*)".to_string();
+        let fingerprint = Fingerprint::from_str("8ad2a6b4").unwrap();
+
+        keystore::write_wallet_data(&path, "device", &external_descriptor, &None, 0).unwrap();
+        keystore::write_external_signer(&path, "device", "hwi", &fingerprint, 3).unwrap();
+
+        let wallet = wallet_manager::load_wallet(&cfg, &external_descriptor, &None).unwrap();
+        let file_path = cfg.datadir.join("backup.json");
+        let file_path = file_path.to_str().unwrap();
+        write_backup(&cfg, "device", &wallet, file_path, false).unwrap();
+
+        let restored = restore_backup(&cfg, file_path, &Some("restored".to_string())).unwrap();
+
+        assert_eq!(
+            keystore::load_external_signer(&path, &restored).unwrap(),
+            Some(("hwi".to_string(), fingerprint, 3))
+        );
+
+        std::fs::remove_dir_all(&cfg.datadir).unwrap();
+    }
+}
@@ -1,64 +1,849 @@
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use bdk::bitcoin::{self, Network};
 use serde::{Deserialize, Serialize};
 
+use crate::error::{Result, SpacechainError};
+
+/// Number of consecutive unused addresses after which sync stops looking
+/// for more, as recommended by BIP44.
+const DEFAULT_GAP_LIMIT: usize = 20;
+
+/// Environment variable relocating the data directory.
+pub const DATADIR_ENV: &str = "SPACECHAINS_DATADIR";
+
+const CONFIG_FILE_NAME: &str = "spacechains.conf";
+
+fn default_gap_limit() -> usize {
+    DEFAULT_GAP_LIMIT
+}
+
+/// Number of unconfirmed covenant steps left below which the
+/// `covenant_chain_exhaustion` notification fires.
+const DEFAULT_NOTIFY_REMAINING_STEPS: usize = 10;
+
+fn default_notify_remaining_steps() -> usize {
+    DEFAULT_NOTIFY_REMAINING_STEPS
+}
+
+/// Seconds before a broadcast or lookup through bitcoind RPC is abandoned.
+const DEFAULT_BITCOIND_TIMEOUT: u64 = 30;
+
+fn default_bitcoind_timeout() -> u64 {
+    DEFAULT_BITCOIND_TIMEOUT
+}
+
+/// Network of the wallets and of `convenant.db`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChainNetwork {
+    Signet,
+    #[default]
+    Testnet,
+}
+
+impl fmt::Display for ChainNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainNetwork::Signet => write!(f, "signet"),
+            ChainNetwork::Testnet => write!(f, "testnet"),
+        }
+    }
+}
+
+impl FromStr for ChainNetwork {
+    type Err = SpacechainError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "signet" => Ok(ChainNetwork::Signet),
+            "testnet" => Ok(ChainNetwork::Testnet),
+            _ => Err(SpacechainError::Config(format!(
+                "Unsupported network {}. Expected signet or testnet.",
+                s
+            ))),
+        }
+    }
+}
+
+impl From<ChainNetwork> for Network {
+    fn from(network: ChainNetwork) -> Self {
+        match network {
+            ChainNetwork::Signet => bitcoin::Network::Signet,
+            ChainNetwork::Testnet => bitcoin::Network::Testnet,
+        }
+    }
+}
+
+/// Backend used to reach the Bitcoin network.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    #[default]
+    Electrum,
+    BitcoinRpc,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Electrum => write!(f, "electrum"),
+            Backend::BitcoinRpc => write!(f, "bitcoin_rpc"),
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = SpacechainError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "electrum" => Ok(Backend::Electrum),
+            "bitcoin_rpc" => Ok(Backend::BitcoinRpc),
+            _ => Err(SpacechainError::Config(format!(
+                "Unsupported backend {}. Expected electrum or bitcoin_rpc.",
+                s
+            ))),
+        }
+    }
+}
+
 #[derive(Default, Debug, Serialize, Deserialize)]
 pub struct ConfigFile {
-    pub network: String,
-    pub blockchain: String,
+    pub network: ChainNetwork,
+    pub blockchain: Backend,
     pub electrum_url: String,
     pub bitcoind_url: String,
-    pub bitcoind_auth_file: String,
+    /// bitcoind cookie file, by default the one of the network in
+    /// `~/.bitcoin`. Unused if `bitcoind_rpc_user` is set.
+    #[serde(default)]
+    pub bitcoind_auth_file: Option<String>,
+    /// bitcoind `rpcauth` or `rpcuser` credentials.
+    #[serde(default)]
+    pub bitcoind_rpc_user: Option<String>,
+    #[serde(default)]
+    pub bitcoind_rpc_password: Option<String>,
+    /// Prepended to the names of the wallets created in bitcoind.
+    #[serde(default)]
+    pub bitcoind_wallet_prefix: Option<String>,
+    /// Seconds before a broadcast or lookup through bitcoind RPC is
+    /// abandoned. It does not apply to wallet syncs, which BDK runs with its
+    /// own client and 15 second timeout.
+    #[serde(default = "default_bitcoind_timeout")]
+    pub bitcoind_timeout: u64,
+    #[serde(default = "default_gap_limit")]
+    pub gap_limit: usize,
+    /// Shell command run on each event, with the JSON payload on stdin.
+    #[serde(default)]
+    pub notify_command: Option<String>,
+    /// URL the JSON payload of each event is POSTed to.
+    #[serde(default)]
+    pub notify_url: Option<String>,
+    #[serde(default = "default_notify_remaining_steps")]
+    pub notify_remaining_steps: usize,
+    /// bitcoind `zmqpubhashblock` endpoint, e.g. `tcp://127.0.0.1:28332`.
+    #[serde(default)]
+    pub zmq_hashblock_url: Option<String>,
+    /// bitcoind `zmqpubrawtx` endpoint, e.g. `tcp://127.0.0.1:28333`.
+    #[serde(default)]
+    pub zmq_rawtx_url: Option<String>,
+    /// Data directory the state of each network is kept in. It is not read
+    /// from the config file, see `load`.
+    #[serde(skip)]
+    pub datadir: PathBuf,
 }
 
 impl ConfigFile {
-    #[allow(dead_code)]
-    pub fn get_network(&self) -> Result<Network, std::string::String> {
-        if self.network == "signet" {
-            return Ok(bitcoin::Network::Signet);
-        } else if self.network == "testnet" {
-            return Ok(bitcoin::Network::Testnet);
+    pub fn get_network(&self) -> Network {
+        self.network.into()
+    }
+
+    /// Returns the bitcoind cookie file, `bitcoind_auth_file` or the
+    /// default one of the network.
+    pub fn get_bitcoind_auth_file(&self) -> PathBuf {
+        if let Some(auth_file) = &self.bitcoind_auth_file {
+            return PathBuf::from(auth_file);
+        }
+
+        let mut path = dirs::home_dir().unwrap_or_default();
+        path.push(".bitcoin");
+        path.push(match self.network {
+            ChainNetwork::Signet => "signet",
+            ChainNetwork::Testnet => "testnet3",
+        });
+        path.push(".cookie");
+        path
+    }
+
+    /// Returns the settings as JSON, with the bitcoind RPC password masked.
+    pub fn to_redacted_json(&self) -> serde_json::Value {
+        let mut settings = serde_json::json!(self);
+        if self.bitcoind_rpc_password.is_some() {
+            settings["bitcoind_rpc_password"] = serde_json::json!("********");
         }
-        Err("Only signet supported for now".to_string())
+        settings
+    }
+
+    /// Checks the settings that deserializing cannot, such as the URLs of
+    /// the configured backend.
+    pub fn validate(&self) -> Result<()> {
+        match self.blockchain {
+            Backend::Electrum => {
+                check_host_port("electrum_url", &self.electrum_url, &["tcp://", "ssl://"])?
+            }
+            Backend::BitcoinRpc => {
+                check_host_port("bitcoind_url", &self.bitcoind_url, &["http://"])?;
+                if self.bitcoind_auth_file.as_deref() == Some("") {
+                    return Err(invalid_setting("bitcoind_auth_file", "", "it is empty"));
+                }
+                if self.bitcoind_rpc_user.is_some() != self.bitcoind_rpc_password.is_some() {
+                    return Err(SpacechainError::Config(
+                        "bitcoind_rpc_user and bitcoind_rpc_password must be set together."
+                            .to_string(),
+                    ));
+                }
+                if self.bitcoind_timeout == 0 {
+                    return Err(invalid_setting(
+                        "bitcoind_timeout",
+                        "0",
+                        "it must be at least 1",
+                    ));
+                }
+            }
+        }
+
+        if self.gap_limit == 0 {
+            return Err(invalid_setting("gap_limit", "0", "it must be at least 1"));
+        }
+
+        if let Some(url) = &self.notify_url {
+            if !url.starts_with("http://") {
+                return Err(invalid_setting(
+                    "notify_url",
+                    url,
+                    "expected an http:// URL",
+                ));
+            }
+        }
+
+        for (key, url) in [
+            ("zmq_hashblock_url", &self.zmq_hashblock_url),
+            ("zmq_rawtx_url", &self.zmq_rawtx_url),
+        ] {
+            if let Some(url) = url {
+                if !url.starts_with("ipc://") {
+                    check_host_port(key, url, &["tcp://"])?;
+                }
+            }
+        }
+
+        Ok(())
     }
 }
 
-pub fn create_or_get_default() -> (ConfigFile, String) {
-    let home_dir = dirs::home_dir();
+fn invalid_setting(key: &str, value: &str, reason: &str) -> SpacechainError {
+    SpacechainError::Config(format!("Invalid {} '{}': {}.", key, value, reason))
+}
 
-    let mut path = home_dir.clone().unwrap();
-    path.push(".spacechains");
+/// Checks that `url` is `host:port`, optionally prefixed with one of
+/// `schemes`.
+fn check_host_port(key: &str, url: &str, schemes: &[&str]) -> Result<()> {
+    let address = schemes
+        .iter()
+        .find_map(|scheme| url.strip_prefix(scheme))
+        .unwrap_or(url);
+
+    if address.contains("://") {
+        return Err(invalid_setting(
+            key,
+            url,
+            &format!("expected a {} URL", schemes.join(" or ")),
+        ));
+    }
+
+    let (host, port) = address
+        .trim_end_matches('/')
+        .rsplit_once(':')
+        .ok_or_else(|| invalid_setting(key, url, "expected host:port"))?;
+
+    if host.is_empty() {
+        return Err(invalid_setting(key, url, "the host is missing"));
+    }
+    if port.parse::<u16>().is_err() {
+        return Err(invalid_setting(key, url, "the port is not a number"));
+    }
+
+    Ok(())
+}
 
-    std::fs::create_dir_all(path.clone()).unwrap();
+/// Returns `datadir` if set, else `$SPACECHAINS_DATADIR` if set, else
+/// `~/.spacechains`.
+pub fn get_base_data_dir(datadir: Option<&Path>) -> Result<PathBuf> {
+    if let Some(datadir) = datadir {
+        return Ok(datadir.to_path_buf());
+    }
 
-    path.push("spacechains.conf");
+    if let Some(datadir) = std::env::var_os(DATADIR_ENV) {
+        return Ok(PathBuf::from(datadir));
+    }
 
-    let binding = path.clone();
-    let path_str = binding.as_os_str().to_str().unwrap();
+    let mut path = dirs::home_dir().ok_or_else(|| {
+        SpacechainError::Config(format!(
+            "Could not find the home directory. Use --datadir or {}.",
+            DATADIR_ENV
+        ))
+    })?;
+    path.push(".spacechains");
 
-    let mut bc_path = home_dir.unwrap();
-    bc_path.push(".bitcoin");
-    bc_path.push("signet");
-    bc_path.push(".cookie");
+    Ok(path)
+}
 
-    let bc_path_str = bc_path.as_os_str().to_str().unwrap();
+/// Returns the config file path, `config_path` or `spacechains.conf` in
+/// `datadir`.
+fn get_config_path(datadir: &Path, config_path: Option<&Path>) -> PathBuf {
+    match config_path {
+        Some(config_path) => config_path.to_path_buf(),
+        None => datadir.join(CONFIG_FILE_NAME),
+    }
+}
+
+/// Prefixes the message of a config error with the config file path.
+fn in_file(path_str: &str, err: SpacechainError) -> SpacechainError {
+    match err {
+        SpacechainError::Config(msg) => SpacechainError::Config(format!("{}: {}", path_str, msg)),
+        err => err,
+    }
+}
+
+/// Loads and validates the config file, `config_path` or
+/// `spacechains.conf` in the data directory. Returns the config and its
+/// path.
+pub fn load(datadir: Option<&Path>, config_path: Option<&Path>) -> Result<(ConfigFile, String)> {
+    let datadir = get_base_data_dir(datadir)?;
+    let path = get_config_path(&datadir, config_path);
+    let path_str = path.to_string_lossy().to_string();
 
     if !path.exists() {
-        let cfg = ConfigFile {
-            network: String::from_str("testnet").unwrap(), // signet
-            blockchain: String::from_str("electrum").unwrap(),
-            electrum_url: String::from_str("ssl://electrum.blockstream.info:60002").unwrap(), // tcp://127.0.0.1:50001
-            bitcoind_url: "127.0.0.1:38332".to_string(),
-            bitcoind_auth_file: bc_path_str.to_string(),
-        };
+        return Err(SpacechainError::Config(format!(
+            "No config file at {}. Run `spacechains init` to create one.",
+            path_str
+        )));
+    }
+
+    let content =
+        std::fs::read_to_string(&path).map_err(|err| SpacechainError::io(&path_str, err))?;
+
+    let mut cfg: ConfigFile = toml::from_str(&content)
+        .map_err(|err| SpacechainError::Config(format!("{}: {}", path_str, err)))?;
+
+    cfg.validate().map_err(|err| in_file(&path_str, err))?;
 
-        confy::store_path(path, &cfg).unwrap();
+    cfg.datadir = datadir;
 
-        (cfg, String::from_str(path_str).unwrap())
+    Ok((cfg, path_str))
+}
+
+/// Loads the config file from the default data directory.
+pub fn load_default() -> Result<(ConfigFile, String)> {
+    load(None, None)
+}
+
+/// Returns the default config of `network` and `blockchain`, the backend.
+pub fn default_config(network: ChainNetwork, blockchain: Backend) -> ConfigFile {
+    let (electrum_url, bitcoind_url) = match network {
+        ChainNetwork::Signet => ("tcp://127.0.0.1:50001", "127.0.0.1:38332"),
+        ChainNetwork::Testnet => ("ssl://electrum.blockstream.info:60002", "127.0.0.1:18332"),
+    };
+
+    ConfigFile {
+        network,
+        blockchain,
+        electrum_url: electrum_url.to_string(),
+        bitcoind_url: bitcoind_url.to_string(),
+        bitcoind_auth_file: None,
+        bitcoind_rpc_user: None,
+        bitcoind_rpc_password: None,
+        bitcoind_wallet_prefix: None,
+        bitcoind_timeout: DEFAULT_BITCOIND_TIMEOUT,
+        gap_limit: DEFAULT_GAP_LIMIT,
+        notify_command: None,
+        notify_url: None,
+        notify_remaining_steps: DEFAULT_NOTIFY_REMAINING_STEPS,
+        zmq_hashblock_url: None,
+        zmq_rawtx_url: None,
+        datadir: PathBuf::new(),
+    }
+}
+
+/// Returns the value of the setting `key`.
+pub fn get_value(cfg: &ConfigFile, key: &str) -> Result<serde_json::Value> {
+    let settings = cfg.to_redacted_json();
+
+    settings
+        .get(key)
+        .cloned()
+        .ok_or_else(|| unknown_key(&settings, key))
+}
+
+fn unknown_key(settings: &serde_json::Value, key: &str) -> SpacechainError {
+    let keys: Vec<&str> = settings
+        .as_object()
+        .map(|settings| settings.keys().map(String::as_str).collect())
+        .unwrap_or_default();
+
+    SpacechainError::InvalidInput(format!(
+        "Unknown setting {}. Expected one of {}.",
+        key,
+        keys.join(", ")
+    ))
+}
+
+/// Returns `cfg` with the setting `key` set to `value`, validated. An empty
+/// `value` unsets an optional setting.
+pub fn set_value(cfg: &ConfigFile, key: &str, value: &str) -> Result<ConfigFile> {
+    let mut settings =
+        serde_json::to_value(cfg).map_err(|err| SpacechainError::Config(err.to_string()))?;
+
+    let current = settings
+        .get(key)
+        .ok_or_else(|| unknown_key(&settings, key))?;
+
+    let new_value = if current.is_number() {
+        serde_json::Value::from(
+            value
+                .parse::<u64>()
+                .map_err(|_| invalid_setting(key, value, "expected a non-negative integer"))?,
+        )
+    } else if value.is_empty() {
+        serde_json::Value::Null
     } else {
-        let cfg: ConfigFile = confy::load_path(path).unwrap();
+        serde_json::Value::String(value.to_string())
+    };
+
+    settings[key] = new_value;
+
+    let mut new_cfg: ConfigFile = serde_json::from_value(settings)
+        .map_err(|err| invalid_setting(key, value, &err.to_string()))?;
+    new_cfg.datadir = cfg.datadir.clone();
+
+    new_cfg.validate()?;
+
+    Ok(new_cfg)
+}
+
+/// Quotes `value` as a TOML basic string. JSON string escapes are valid in
+/// TOML.
+fn toml_string(value: &str) -> String {
+    serde_json::Value::String(value.to_string()).to_string()
+}
+
+/// Writes an optional setting, commented out with `example` when unset.
+fn toml_optional(key: &str, value: &Option<String>, example: &str) -> String {
+    match value {
+        Some(value) => format!("{} = {}", key, toml_string(value)),
+        None => format!("# {} = {}", key, toml_string(example)),
+    }
+}
+
+/// Renders `cfg` as a config file, with a comment on each setting.
+pub fn to_commented_toml(cfg: &ConfigFile) -> String {
+    format!(
+        r#"# spacechains configuration file, written by `spacechains init`.
+# `spacechains config set` rewrites only the line of the setting it changes.
+
+# Network of the wallets and of convenant.db: signet or testnet.
+network = {network}
+
+# Backend used to reach the network: electrum or bitcoin_rpc.
+blockchain = {blockchain}
+
+# Electrum server, used by the electrum backend.
+electrum_url = {electrum_url}
+
+# bitcoind RPC address, used by the bitcoin_rpc backend.
+bitcoind_url = {bitcoind_url}
+
+# bitcoind cookie file, by default the one of the network in ~/.bitcoin.
+{bitcoind_auth_file}
+
+# bitcoind rpcauth or rpcuser credentials, used instead of the cookie file.
+{bitcoind_rpc_user}
+{bitcoind_rpc_password}
+
+# Prepended to the names of the wallets created in bitcoind.
+{bitcoind_wallet_prefix}
+
+# Seconds before a broadcast or lookup through bitcoind RPC is abandoned.
+# It does not apply to wallet syncs, which BDK runs with its own client and
+# 15 second timeout.
+bitcoind_timeout = {bitcoind_timeout}
+
+# Consecutive unused addresses after which sync stops looking for more.
+gap_limit = {gap_limit}
+
+# Shell command run on each event, with the JSON payload on stdin.
+{notify_command}
+
+# URL the JSON payload of each event is POSTed to.
+{notify_url}
+
+# Unconfirmed covenant steps left below which covenant_chain_exhaustion fires.
+notify_remaining_steps = {notify_remaining_steps}
+
+# bitcoind zmqpubhashblock and zmqpubrawtx endpoints, used by `watch`.
+{zmq_hashblock_url}
+{zmq_rawtx_url}
+"#,
+        network = toml_string(&cfg.network.to_string()),
+        blockchain = toml_string(&cfg.blockchain.to_string()),
+        electrum_url = toml_string(&cfg.electrum_url),
+        bitcoind_url = toml_string(&cfg.bitcoind_url),
+        bitcoind_auth_file = toml_optional(
+            "bitcoind_auth_file",
+            &cfg.bitcoind_auth_file,
+            &cfg.get_bitcoind_auth_file().to_string_lossy()
+        ),
+        bitcoind_rpc_user =
+            toml_optional("bitcoind_rpc_user", &cfg.bitcoind_rpc_user, "spacechains"),
+        bitcoind_rpc_password = toml_optional(
+            "bitcoind_rpc_password",
+            &cfg.bitcoind_rpc_password,
+            "password"
+        ),
+        bitcoind_wallet_prefix = toml_optional(
+            "bitcoind_wallet_prefix",
+            &cfg.bitcoind_wallet_prefix,
+            "spacechains-"
+        ),
+        bitcoind_timeout = cfg.bitcoind_timeout,
+        gap_limit = cfg.gap_limit,
+        notify_command = toml_optional(
+            "notify_command",
+            &cfg.notify_command,
+            "/usr/local/bin/on-spacechains-event"
+        ),
+        notify_url = toml_optional(
+            "notify_url",
+            &cfg.notify_url,
+            "http://127.0.0.1:8080/events"
+        ),
+        notify_remaining_steps = cfg.notify_remaining_steps,
+        zmq_hashblock_url = toml_optional(
+            "zmq_hashblock_url",
+            &cfg.zmq_hashblock_url,
+            "tcp://127.0.0.1:28332"
+        ),
+        zmq_rawtx_url = toml_optional("zmq_rawtx_url", &cfg.zmq_rawtx_url, "tcp://127.0.0.1:28333"),
+    )
+}
+
+/// Writes `cfg` to the config file at `path`, replacing its content. A new
+/// file is readable by the current user only, as it may hold the bitcoind
+/// RPC password.
+pub fn save(cfg: &ConfigFile, path: &Path) -> Result<()> {
+    let path_str = path.to_string_lossy();
+
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)
+            .map_err(|err| SpacechainError::io(&parent.to_string_lossy(), err))?;
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options
+        .open(path)
+        .map_err(|err| SpacechainError::io(&path_str, err))?;
+    file.write_all(to_commented_toml(cfg).as_bytes())
+        .map_err(|err| SpacechainError::io(&path_str, err))
+}
+
+/// Returns the setting of a `key = value` line of the config file, also
+/// when the line is commented out.
+fn line_key(line: &str) -> Option<(&str, bool)> {
+    let line = line.trim_start();
+    let (line, commented) = match line.strip_prefix('#') {
+        Some(rest) => (rest.trim_start(), true),
+        None => (line, false),
+    };
+    let (key, _) = line.split_once('=')?;
+
+    Some((key.trim(), commented))
+}
+
+/// Writes the setting `key` of `cfg` to the config file at `path`, leaving
+/// its other lines and comments untouched. The line of the setting is
+/// replaced, or the commented-out example uncommented, or a line appended.
+/// An unset optional setting has its line removed.
+pub fn save_value(cfg: &ConfigFile, key: &str, path: &Path) -> Result<()> {
+    let path_str = path.to_string_lossy();
+
+    let content =
+        std::fs::read_to_string(path).map_err(|err| SpacechainError::io(&path_str, err))?;
+
+    let settings =
+        serde_json::to_value(cfg).map_err(|err| SpacechainError::Config(err.to_string()))?;
+    let new_line = match settings.get(key) {
+        Some(serde_json::Value::Null) => None,
+        Some(serde_json::Value::String(value)) => Some(format!("{} = {}", key, toml_string(value))),
+        Some(value) => Some(format!("{} = {}", key, value)),
+        None => return Err(unknown_key(&settings, key)),
+    };
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut example = None;
+    let mut written = false;
+    for line in content.lines() {
+        match line_key(line) {
+            Some((line_key, false)) if line_key == key => {
+                if let (Some(new_line), false) = (&new_line, written) {
+                    lines.push(new_line.clone());
+                    written = true;
+                }
+            }
+            Some((line_key, true)) if line_key == key && example.is_none() => {
+                example = Some(lines.len());
+                lines.push(line.to_string());
+            }
+            _ => lines.push(line.to_string()),
+        }
+    }
+
+    if let (Some(new_line), false) = (new_line, written) {
+        match example {
+            Some(index) => lines[index] = new_line,
+            None => lines.push(new_line),
+        }
+    }
+
+    let mut new_content = lines.join("\n");
+    new_content.push('\n');
+
+    std::fs::write(path, new_content).map_err(|err| SpacechainError::io(&path_str, err))
+}
+
+/// Prints `question` and reads an answer from stdin, `default` if empty.
+fn prompt(question: &str, default: &str) -> Result<String> {
+    print!("{} [{}]: ", question, default);
+    std::io::stdout()
+        .flush()
+        .map_err(|err| SpacechainError::io("stdout", err))?;
+
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .map_err(|err| SpacechainError::io("stdin", err))?;
+
+    match answer.trim() {
+        "" => Ok(default.to_string()),
+        answer => Ok(answer.to_string()),
+    }
+}
+
+/// Prompts until the answer parses as a `T`.
+fn prompt_parse<T: FromStr<Err = SpacechainError> + fmt::Display>(
+    question: &str,
+    default: T,
+) -> Result<T> {
+    loop {
+        match prompt(question, &default.to_string())?.parse() {
+            Ok(value) => return Ok(value),
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+}
+
+/// Asks for each setting of `cfg`, keeping the current value on an empty
+/// answer. The backend defaults depend on the network answered.
+pub fn prompt_config(cfg: ConfigFile) -> Result<ConfigFile> {
+    let network = prompt_parse("Network (signet, testnet)", cfg.network)?;
+    let blockchain = prompt_parse("Backend (electrum, bitcoin_rpc)", cfg.blockchain)?;
+
+    let mut cfg = if network == cfg.network {
+        ConfigFile { blockchain, ..cfg }
+    } else {
+        default_config(network, blockchain)
+    };
+
+    match cfg.blockchain {
+        Backend::Electrum => {
+            cfg.electrum_url = prompt("Electrum server", &cfg.electrum_url)?;
+        }
+        Backend::BitcoinRpc => {
+            cfg.bitcoind_url = prompt("bitcoind RPC address", &cfg.bitcoind_url)?;
+            let user = prompt(
+                "bitcoind RPC user, empty for the cookie file",
+                cfg.bitcoind_rpc_user.as_deref().unwrap_or_default(),
+            )?;
+            if user.is_empty() {
+                let auth_file = prompt(
+                    "bitcoind cookie file",
+                    &cfg.get_bitcoind_auth_file().to_string_lossy(),
+                )?;
+                if auth_file != cfg.get_bitcoind_auth_file().to_string_lossy() {
+                    cfg.bitcoind_auth_file = Some(auth_file);
+                }
+            } else {
+                cfg.bitcoind_rpc_password = Some(
+                    rpassword::prompt_password("bitcoind RPC password: ")
+                        .map_err(|err| SpacechainError::io("password prompt", err))?,
+                );
+                cfg.bitcoind_rpc_user = Some(user);
+            }
+        }
+    }
+
+    Ok(cfg)
+}
+
+/// Writes `cfg` to the config file, `config_path` or `spacechains.conf` in
+/// the data directory, and creates the data directory. An existing config
+/// file is only replaced with `force`. Returns the config file path.
+pub fn init(
+    datadir: Option<&Path>,
+    config_path: Option<&Path>,
+    cfg: &ConfigFile,
+    force: bool,
+) -> Result<String> {
+    cfg.validate()?;
+
+    let datadir = get_base_data_dir(datadir)?;
+    let path = get_config_path(&datadir, config_path);
+    let path_str = path.to_string_lossy().to_string();
+
+    if path.exists() && !force {
+        return Err(SpacechainError::Config(format!(
+            "{} already exists. Use --force to replace it.",
+            path_str
+        )));
+    }
+
+    std::fs::create_dir_all(&datadir)
+        .map_err(|err| SpacechainError::io(&datadir.to_string_lossy(), err))?;
+
+    save(cfg, &path)?;
+
+    Ok(path_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_is_valid() {
+        default_config(ChainNetwork::Testnet, Backend::Electrum)
+            .validate()
+            .unwrap();
+        default_config(ChainNetwork::Signet, Backend::BitcoinRpc)
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn validate_rejects_invalid_settings() {
+        let mut cfg = default_config(ChainNetwork::Testnet, Backend::Electrum);
+        cfg.electrum_url = "http://localhost:50001".to_string();
+        assert!(matches!(cfg.validate(), Err(SpacechainError::Config(_))));
+
+        let mut cfg = default_config(ChainNetwork::Testnet, Backend::BitcoinRpc);
+        cfg.bitcoind_rpc_user = Some("user".to_string());
+        assert!(matches!(cfg.validate(), Err(SpacechainError::Config(_))));
+
+        let mut cfg = default_config(ChainNetwork::Testnet, Backend::Electrum);
+        cfg.gap_limit = 0;
+        assert!(matches!(cfg.validate(), Err(SpacechainError::Config(_))));
+    }
+
+    #[test]
+    fn set_value_parses_and_validates() {
+        let cfg = default_config(ChainNetwork::Testnet, Backend::Electrum);
+
+        let cfg = set_value(&cfg, "gap_limit", "50").unwrap();
+        assert_eq!(cfg.gap_limit, 50);
+
+        let cfg = set_value(&cfg, "notify_command", "cat").unwrap();
+        assert_eq!(cfg.notify_command.as_deref(), Some("cat"));
+
+        let cfg = set_value(&cfg, "notify_command", "").unwrap();
+        assert_eq!(cfg.notify_command, None);
+
+        let cfg = set_value(&cfg, "network", "signet").unwrap();
+        assert_eq!(cfg.get_network(), Network::Signet);
+    }
+
+    #[test]
+    fn set_value_rejects_invalid_values() {
+        let cfg = default_config(ChainNetwork::Testnet, Backend::Electrum);
+
+        assert!(matches!(
+            set_value(&cfg, "no_such_key", "1"),
+            Err(SpacechainError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            set_value(&cfg, "gap_limit", "many"),
+            Err(SpacechainError::Config(_))
+        ));
+        assert!(matches!(
+            set_value(&cfg, "gap_limit", "0"),
+            Err(SpacechainError::Config(_))
+        ));
+        assert!(matches!(
+            set_value(&cfg, "network", "mainnet"),
+            Err(SpacechainError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn get_value_masks_the_rpc_password() {
+        let mut cfg = default_config(ChainNetwork::Testnet, Backend::BitcoinRpc);
+        cfg.bitcoind_rpc_user = Some("user".to_string());
+        cfg.bitcoind_rpc_password = Some("secret".to_string());
+
+        assert_eq!(get_value(&cfg, "bitcoind_rpc_user").unwrap(), "user");
+        assert_eq!(
+            get_value(&cfg, "bitcoind_rpc_password").unwrap(),
+            "********"
+        );
+    }
+
+    #[test]
+    fn save_value_keeps_the_other_lines() {
+        let path = std::env::temp_dir().join(format!(
+            "spacechains-test-save-value-{}.conf",
+            std::process::id()
+        ));
+        let cfg = default_config(ChainNetwork::Testnet, Backend::Electrum);
+        let content = format!("# Edited by hand.\n{}", to_commented_toml(&cfg));
+        std::fs::write(&path, &content).unwrap();
+
+        let cfg = set_value(&cfg, "gap_limit", "50").unwrap();
+        save_value(&cfg, "gap_limit", &path).unwrap();
+        let cfg = set_value(&cfg, "notify_url", "http://127.0.0.1:8080/events").unwrap();
+        save_value(&cfg, "notify_url", &path).unwrap();
+
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(saved.starts_with("# Edited by hand.\n"));
+        assert!(saved.contains("\ngap_limit = 50\n"));
+        assert!(saved.contains("\nnotify_url = \"http://127.0.0.1:8080/events\"\n"));
+        assert_eq!(saved.lines().count(), content.lines().count());
+        let loaded: ConfigFile = toml::from_str(&saved).unwrap();
+        assert_eq!(loaded.gap_limit, 50);
+        assert_eq!(loaded.notify_url, cfg.notify_url);
+
+        let cfg = set_value(&cfg, "notify_url", "").unwrap();
+        save_value(&cfg, "notify_url", &path).unwrap();
+        let loaded: ConfigFile = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(loaded.notify_url, None);
 
-        (cfg, String::from_str(path_str).unwrap())
+        std::fs::remove_file(&path).unwrap();
     }
 }
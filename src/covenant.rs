@@ -1,123 +1,911 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
 use bdk::{
     bitcoin::{
         consensus::{deserialize, serialize},
-        secp256k1::Secp256k1,
-        Transaction, Txid,
+        hashes::hex::{FromHex, ToHex},
+        Network, OutPoint, Script, Transaction, Txid,
     },
     database::{any::SqliteDbConfiguration, ConfigurableDatabase, SqliteDatabase},
-    wallet::wallet_name_from_descriptor,
-    KeychainKind, Wallet,
+    descriptor::{Descriptor, DescriptorPublicKey},
+    Wallet,
 };
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use serde::{Deserialize, Serialize};
 
 use crate::config_file::ConfigFile;
+use crate::error::{Result, SpacechainError};
+use crate::{utils, wallet_manager};
+
+const COVENANT_DB_FILE: &str = "convenant.db";
+
+/// Current version of the `convenant.db` schema.
+/// Databases with a lower version are migrated forward when loaded.
+pub const COVENANT_DB_SCHEMA_VERSION: u32 = 2;
+
+/// Forward migrations. The migration at index `i` upgrades a database
+/// from schema version `i` to version `i + 1`.
+const COVENANT_DB_MIGRATIONS: [fn(&Connection) -> Result<()>; 2] =
+    [migrate_v0_to_v1, migrate_v1_to_v2];
+
+const METADATA_SCHEMA_VERSION: &str = "schema_version";
+const METADATA_NETWORK: &str = "network";
+const METADATA_GENESIS_OUTPOINT: &str = "genesis_outpoint";
+const METADATA_CHAIN_LENGTH: &str = "chain_length";
+const METADATA_BUMP_SCRIPT: &str = "bump_script";
+const METADATA_CREATOR: &str = "creator";
+
+/// Creator recorded for databases that do not say which tool generated
+/// them.
+const UNKNOWN_CREATOR: &str = "unknown";
+
+/// Version of the covenant export format written by `export_covenant`.
+const COVENANT_EXPORT_VERSION: u32 = 1;
+
+/// A line of a covenant export file.
+///
+/// The file is JSON lines. The first line is a `header` with the format
+/// version, network, public descriptor and the remaining metadata entries.
+/// It is followed by one `step` line per pre-signed transaction, ordered
+/// from the genesis outpoint to the tip, with the transaction serialized
+/// as consensus hex.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum CovenantExportLine {
+    Header {
+        version: u32,
+        network: String,
+        descriptor: String,
+        metadata: BTreeMap<String, String>,
+    },
+    Step {
+        step: usize,
+        previous_txid: String,
+        tx: String,
+    },
+}
 
-fn load_convenant_wallet(cfg: &ConfigFile, public_descriptor: &String) -> Wallet<SqliteDatabase> {
-    let home_dir = dirs::home_dir();
+fn db_error(err: rusqlite::Error) -> SpacechainError {
+    SpacechainError::CovenantDb(format!("{}: {}", COVENANT_DB_FILE, err))
+}
 
-    let mut path = home_dir.unwrap();
+fn invalid_covenant_db(msg: &str) -> SpacechainError {
+    SpacechainError::CovenantDb(format!("{}: {}", COVENANT_DB_FILE, msg))
+}
 
-    let network = cfg.get_network().unwrap();
+fn load_convenant_wallet(
+    cfg: &ConfigFile,
+    public_descriptor: &str,
+) -> Result<Wallet<SqliteDatabase>> {
+    let network = cfg.get_network();
 
-    let wallet_name =
-        wallet_name_from_descriptor(public_descriptor.as_str(), None, network, &Secp256k1::new())
-            .unwrap();
+    let path = utils::get_bdk_wallet_path(cfg, &public_descriptor.to_string(), &None)?;
 
-    path.push(".spacechains");
-    path.push(wallet_name);
+    let sqlite_db_configuration = SqliteDbConfiguration {
+        path: path.to_string_lossy().to_string(),
+    };
 
-    std::fs::create_dir_all(path.clone()).unwrap();
+    let sqlite_database = bdk::database::SqliteDatabase::from_config(&sqlite_db_configuration)?;
 
-    path.push("database");
+    Ok(Wallet::new(
+        public_descriptor,
+        None,
+        network,
+        sqlite_database,
+    )?)
+}
 
-    let sqlite_db_configuration = SqliteDbConfiguration {
-        path: path.into_os_string().into_string().unwrap(),
-    };
+/// Returns the path of `convenant.db` in the directory of the configured
+/// network.
+pub fn get_covenant_db_path(cfg: &ConfigFile) -> Result<PathBuf> {
+    Ok(utils::get_data_dir(cfg)?.join(COVENANT_DB_FILE))
+}
+
+/// Error for a missing `convenant.db` at `path`. A `convenant.db` in the
+/// current directory, where versions without a data directory read it, is
+/// no longer used, so the error says where to copy it.
+fn covenant_db_not_found(path: &Path) -> SpacechainError {
+    let mut msg = format!("{} not found.", path.to_string_lossy());
+
+    if Path::new(COVENANT_DB_FILE).exists() {
+        msg.push_str(&format!(
+            " The {} of the current directory is no longer read. Copy it there.",
+            COVENANT_DB_FILE
+        ));
+    }
+
+    SpacechainError::CovenantDb(msg)
+}
+
+/// Opens the `convenant.db` at `path` and migrates it to the current
+/// schema version in one transaction, so that a failed migration leaves
+/// it unchanged.
+fn open_and_migrate(path: &Path) -> Result<Connection> {
+    if !path.exists() {
+        return Err(covenant_db_not_found(path));
+    }
+
+    let mut conn = Connection::open(path).map_err(db_error)?;
+
+    let tx = conn.transaction().map_err(db_error)?;
+    migrate_covenant_db(&tx)?;
+    tx.commit().map_err(db_error)?;
+
+    Ok(conn)
+}
+
+pub fn covenant_db_exists(cfg: &ConfigFile) -> Result<bool> {
+    Ok(get_covenant_db_path(cfg)?.exists())
+}
+
+/// Opens `convenant.db`, migrates it to the current schema version and
+/// checks that it was generated for the configured network.
+pub fn open_covenant_db(cfg: &ConfigFile) -> Result<Connection> {
+    let conn = open_and_migrate(&get_covenant_db_path(cfg)?)?;
+
+    check_covenant_db_network(cfg, &conn)?;
 
-    let sqlite_database =
-        bdk::database::SqliteDatabase::from_config(&sqlite_db_configuration).unwrap();
+    Ok(conn)
+}
+
+fn get_schema_version(conn: &Connection) -> Result<u32> {
+    let has_metadata: bool = conn
+        .query_row(
+            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'convenant_metadata'",
+            [],
+            |row| row.get(0),
+        )
+        .map_err(db_error)?;
+
+    if !has_metadata {
+        return Ok(0);
+    }
 
-    Wallet::new(public_descriptor.as_str(), None, network, sqlite_database).unwrap()
+    match get_metadata(conn, METADATA_SCHEMA_VERSION)? {
+        Some(version) => version
+            .parse::<u32>()
+            .map_err(|_| invalid_covenant_db(&format!("invalid schema version {}", version))),
+        None => Ok(0),
+    }
 }
 
-pub fn load_convenant_wallet_from_db(cfg: &ConfigFile) -> Wallet<SqliteDatabase> {
-    let conn = Connection::open("convenant.db").unwrap();
+/// Runs the pending migrations of `conn`, which callers open as a
+/// transaction.
+fn migrate_covenant_db(conn: &Connection) -> Result<()> {
+    let version = get_schema_version(conn)?;
+
+    if version > COVENANT_DB_SCHEMA_VERSION {
+        return Err(SpacechainError::CovenantDb(format!(
+            "{} has schema version {}, but this software only supports up to version {}. Please upgrade spacechains.",
+            COVENANT_DB_FILE, version, COVENANT_DB_SCHEMA_VERSION
+        )));
+    }
+
+    for (from_version, migration) in COVENANT_DB_MIGRATIONS
+        .iter()
+        .enumerate()
+        .skip(version as usize)
+    {
+        migration(conn)?;
+        set_metadata(
+            conn,
+            METADATA_SCHEMA_VERSION,
+            &(from_version + 1).to_string(),
+        )?;
+    }
 
+    Ok(())
+}
+
+/// Adds the `convenant_metadata` table and fills in what can be derived
+/// from the existing pre-signed transactions.
+/// The network cannot be derived, so it is left unset, and the tool that
+/// generated the transactions is recorded as unknown.
+fn migrate_v0_to_v1(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS convenant_descriptor (public_descriptor TEXT);
+        CREATE TABLE IF NOT EXISTS convenant_txs (previous_tx_id BLOB UNIQUE, tx_hex BLOB);
+        CREATE TABLE IF NOT EXISTS convenant_metadata (key TEXT UNIQUE NOT NULL, value TEXT NOT NULL);",
+    )
+    .map_err(db_error)?;
+
+    let txs = load_covenant_txs(conn)?;
+
+    set_metadata(conn, METADATA_CHAIN_LENGTH, &txs.len().to_string())?;
+
+    if let Some(outpoint) = find_genesis_outpoint(&txs) {
+        set_metadata(conn, METADATA_GENESIS_OUTPOINT, &outpoint.to_string())?;
+    }
+
+    if get_metadata(conn, METADATA_CREATOR)?.is_none() {
+        set_metadata(conn, METADATA_CREATOR, UNKNOWN_CREATOR)?;
+    }
+
+    Ok(())
+}
+
+/// Records the bump witness script in the metadata. Databases created
+/// before this version all use the fixed `OP_0 OP_CSV OP_1ADD` script.
+fn migrate_v1_to_v2(conn: &Connection) -> Result<()> {
+    if get_metadata(conn, METADATA_BUMP_SCRIPT)?.is_none() {
+        set_metadata(
+            conn,
+            METADATA_BUMP_SCRIPT,
+            &utils::build_bump_script().to_hex(),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn check_covenant_db_network(cfg: &ConfigFile, conn: &Connection) -> Result<()> {
+    let network = cfg.get_network();
+
+    match get_metadata(conn, METADATA_NETWORK)? {
+        Some(db_network) => {
+            let db_network = Network::from_str(&db_network)
+                .map_err(|_| invalid_covenant_db(&format!("unknown network {}", db_network)))?;
+            if db_network != network {
+                return Err(SpacechainError::CovenantDb(format!(
+                    "{} was generated for {}, but the configured network is {}.",
+                    COVENANT_DB_FILE, db_network, network
+                )));
+            }
+        }
+        None => {
+            return Err(SpacechainError::CovenantDb(format!(
+                "{} does not record its network, which cannot be derived from its transactions. If it was generated for {}, record it once with `spacechains set-covenant-network {}`. The {} shipped with spacechains is a testnet covenant.",
+                COVENANT_DB_FILE, network, network, COVENANT_DB_FILE
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Records the network of a `convenant.db` that does not record one, as
+/// databases created before the metadata table. `network` must be the
+/// configured one, which the database is used with. A recorded network is
+/// never changed.
+pub fn set_covenant_network(cfg: &ConfigFile, network: Network) -> Result<()> {
+    if network != cfg.get_network() {
+        return Err(SpacechainError::Config(format!(
+            "The configured network is {}. Use a config file for {} to record it.",
+            cfg.get_network(),
+            network
+        )));
+    }
+
+    let conn = open_and_migrate(&get_covenant_db_path(cfg)?)?;
+
+    match get_metadata(&conn, METADATA_NETWORK)? {
+        Some(db_network) if db_network == network.to_string() => Ok(()),
+        Some(db_network) => Err(SpacechainError::CovenantDb(format!(
+            "{} already records the network {}.",
+            COVENANT_DB_FILE, db_network
+        ))),
+        None => set_metadata(&conn, METADATA_NETWORK, &network.to_string()),
+    }
+}
+
+/// Returns the network recorded in the `convenant.db` of the current
+/// directory, where versions without a data directory kept it. The file is
+/// only read, never migrated.
+pub fn get_legacy_covenant_network() -> Result<Option<String>> {
+    let path = Path::new(COVENANT_DB_FILE);
+
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let conn =
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(db_error)?;
+
+    let has_metadata: bool = conn
+        .query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'convenant_metadata')",
+            [],
+            |row| row.get(0),
+        )
+        .map_err(db_error)?;
+
+    if !has_metadata {
+        return Ok(None);
+    }
+
+    get_metadata(&conn, METADATA_NETWORK)
+}
+
+pub fn get_metadata(conn: &Connection, key: &str) -> Result<Option<String>> {
+    conn.query_row(
+        "SELECT value FROM convenant_metadata WHERE key = ?1",
+        [key],
+        |row| row.get(0),
+    )
+    .optional()
+    .map_err(db_error)
+}
+
+pub fn set_metadata(conn: &Connection, key: &str, value: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO convenant_metadata (key, value) VALUES (?1, ?2) ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        params![key, value],
+    )
+    .map_err(db_error)?;
+
+    Ok(())
+}
+
+fn load_covenant_descriptor(conn: &Connection) -> Result<String> {
     let mut stmt = conn
         .prepare("SELECT public_descriptor FROM convenant_descriptor")
-        .unwrap();
+        .map_err(db_error)?;
+
+    let descriptors: Vec<String> = stmt
+        .query_map([], |row| row.get(0))
+        .map_err(db_error)?
+        .collect::<rusqlite::Result<_>>()
+        .map_err(db_error)?;
+
+    match descriptors.as_slice() {
+        [descriptor] => Ok(descriptor.clone()),
+        _ => Err(invalid_covenant_db(&format!(
+            "expected one covenant descriptor, found {}",
+            descriptors.len()
+        ))),
+    }
+}
 
-    let convenant_iter = stmt
-        .query_map([], |row| {
-            let public_desc: String = row.get(0).unwrap();
-            Ok(public_desc)
-        })
-        .unwrap();
+/// Decodes a `convenant_txs` row.
+fn decode_covenant_tx(previous_tx_id_bytes: &[u8], tx_bytes: &[u8]) -> Result<(Txid, Transaction)> {
+    let txid: Txid = deserialize(previous_tx_id_bytes)
+        .map_err(|err| invalid_covenant_db(&format!("invalid previous transaction id: {}", err)))?;
 
-    let mut descriptors: Vec<String> = Vec::new();
+    let tx: Transaction = deserialize(tx_bytes)
+        .map_err(|err| invalid_covenant_db(&format!("invalid transaction: {}", err)))?;
 
-    for row in convenant_iter {
-        let result = row.unwrap();
-        descriptors.push(result);
-    }
+    Ok((txid, tx))
+}
 
-    assert!(descriptors.len() == 1);
+fn load_covenant_txs(conn: &Connection) -> Result<Vec<(Txid, Transaction)>> {
+    let mut stmt = conn
+        .prepare("SELECT previous_tx_id, tx_hex FROM convenant_txs")
+        .map_err(db_error)?;
+
+    let rows: Vec<(Vec<u8>, Vec<u8>)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(db_error)?
+        .collect::<rusqlite::Result<_>>()
+        .map_err(db_error)?;
+
+    rows.iter()
+        .map(|(previous_tx_id_bytes, tx_bytes)| decode_covenant_tx(previous_tx_id_bytes, tx_bytes))
+        .collect()
+}
 
-    load_convenant_wallet(cfg, descriptors.get(0).unwrap())
+/// The genesis outpoint is the covenant input of the only transaction
+/// whose previous transaction is not itself part of the chain.
+fn find_genesis_outpoint(txs: &[(Txid, Transaction)]) -> Option<OutPoint> {
+    let (previous_txid, tx) = txs
+        .iter()
+        .find(|(previous_txid, _)| !txs.iter().any(|(_, tx)| tx.txid() == *previous_txid))?;
+
+    tx.input
+        .iter()
+        .map(|input| input.previous_output)
+        .find(|outpoint| outpoint.txid == *previous_txid)
 }
 
-pub fn get_covenant_tx_from_db(
-    wallet: &Wallet<SqliteDatabase>,
-) -> Option<(Txid, Transaction, usize)> {
-    let conn = Connection::open("convenant.db").unwrap();
+/// Orders the pre-signed transactions from the genesis outpoint to the tip.
+/// Returns `None` if they do not form a single unbroken chain.
+fn order_covenant_chain(txs: Vec<(Txid, Transaction)>) -> Option<Vec<(Txid, Transaction)>> {
+    if txs.is_empty() {
+        return Some(txs);
+    }
 
-    for utxo in wallet.list_unspent().unwrap().iter() {
-        let prev_txid_bytes = serialize(&utxo.outpoint.txid);
+    let genesis_outpoint = find_genesis_outpoint(&txs)?;
+
+    let mut by_previous_txid: BTreeMap<Txid, Transaction> = txs.into_iter().collect();
+
+    let mut chain: Vec<(Txid, Transaction)> = Vec::new();
+    let mut previous_txid = genesis_outpoint.txid;
+
+    while let Some(tx) = by_previous_txid.remove(&previous_txid) {
+        let txid = tx.txid();
+        chain.push((previous_txid, tx));
+        previous_txid = txid;
+    }
 
-        let mut stmt = conn
-            .prepare("SELECT previous_tx_id, tx_hex FROM convenant_txs WHERE previous_tx_id=(?1)")
-            .unwrap();
+    if !by_previous_txid.is_empty() {
+        return None;
+    }
+
+    Some(chain)
+}
 
-        let convenant_iter = stmt
-            .query_map(params![prev_txid_bytes], |row| {
-                let previous_tx_id_bytes: Vec<u8> = row.get(0).unwrap();
-                let txid: Txid = deserialize(&previous_tx_id_bytes).unwrap();
+/// Checks that an ordered chain is consistent with the covenant descriptor:
+/// each step spends the previous one and, except for the tip, pays back to
+/// the covenant script.
+fn verify_covenant_chain(descriptor: &str, chain: &[(Txid, Transaction)]) -> Result<()> {
+    let invalid_chain = SpacechainError::CovenantDb;
+
+    let descriptor = Descriptor::<DescriptorPublicKey>::from_str(descriptor)
+        .map_err(|err| invalid_chain(format!("Invalid covenant descriptor: {}", err)))?;
+
+    let covenant_script = descriptor.at_derivation_index(0).script_pubkey();
+
+    for (step, (previous_txid, tx)) in chain.iter().enumerate() {
+        if !tx
+            .input
+            .iter()
+            .any(|input| input.previous_output.txid == *previous_txid)
+        {
+            return Err(invalid_chain(format!(
+                "Step {} does not spend its previous transaction {}.",
+                step, previous_txid
+            )));
+        }
 
-                let tx_bytes: Vec<u8> = row.get(1).unwrap();
-                let tx: Transaction = deserialize(&tx_bytes).unwrap();
+        if step > 0 {
+            let previous_tx = &chain[step - 1].1;
+            let spends_covenant_output = tx.input.iter().any(|input| {
+                input.previous_output.txid == *previous_txid
+                    && previous_tx
+                        .output
+                        .get(input.previous_output.vout as usize)
+                        .map(|out| out.script_pubkey == covenant_script)
+                        .unwrap_or(false)
+            });
+
+            if !spends_covenant_output {
+                return Err(invalid_chain(format!(
+                    "Step {} does not spend the covenant output of step {}.",
+                    step,
+                    step - 1
+                )));
+            }
+        }
 
-                Ok((txid, tx))
-            })
-            .unwrap();
+        if step + 1 < chain.len()
+            && !tx
+                .output
+                .iter()
+                .any(|out| out.script_pubkey == covenant_script)
+        {
+            return Err(invalid_chain(format!(
+                "Step {} has no output paying to the covenant descriptor.",
+                step
+            )));
+        }
+    }
 
-        let mut previous_tx_ids: Vec<Txid> = Vec::new();
-        let mut txs: Vec<Transaction> = Vec::new();
+    Ok(())
+}
 
-        for row in convenant_iter {
-            let result = row.unwrap();
-            let previous_txid = result.0;
-            previous_tx_ids.push(previous_txid);
-            let tx = result.1;
-            txs.push(tx);
+/// Checks that each step has an anchor output the miner can spend, a P2WSH
+/// output of `bump_script` or a pay-to-anchor output.
+fn verify_covenant_anchors(bump_script: &Script, chain: &[(Txid, Transaction)]) -> Result<()> {
+    for (step, (_, tx)) in chain.iter().enumerate() {
+        if wallet_manager::find_anchor_outputs(tx, bump_script).is_empty() {
+            return Err(SpacechainError::CovenantDb(format!(
+                "Step {} has no output paying to the bump script {} or to a pay-to-anchor script.",
+                step,
+                bump_script.to_hex()
+            )));
         }
+    }
+
+    Ok(())
+}
+
+pub fn export_covenant(cfg: &ConfigFile, file_path: &str) -> Result<usize> {
+    let conn = open_covenant_db(cfg)?;
+
+    let descriptor = load_covenant_descriptor(&conn)?;
+
+    let chain = load_covenant_chain(cfg)?;
+
+    let mut stmt = conn
+        .prepare("SELECT key, value FROM convenant_metadata")
+        .map_err(db_error)?;
+
+    let metadata: BTreeMap<String, String> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(db_error)?
+        .collect::<rusqlite::Result<BTreeMap<String, String>>>()
+        .map_err(db_error)?
+        .into_iter()
+        .filter(|(key, _)| key != METADATA_SCHEMA_VERSION && key != METADATA_NETWORK)
+        .collect();
+
+    let mut file = File::create(file_path).map_err(|err| SpacechainError::io(file_path, err))?;
+
+    let header = CovenantExportLine::Header {
+        version: COVENANT_EXPORT_VERSION,
+        network: cfg.get_network().to_string(),
+        descriptor,
+        metadata,
+    };
+
+    let lines = std::iter::once(header).chain(chain.iter().enumerate().map(
+        |(step, (previous_txid, tx))| CovenantExportLine::Step {
+            step,
+            previous_txid: previous_txid.to_string(),
+            tx: serialize(tx).to_hex(),
+        },
+    ));
+
+    for line in lines {
+        // Serializing strings and maps of strings cannot fail.
+        writeln!(file, "{}", serde_json::to_string(&line).unwrap())
+            .map_err(|err| SpacechainError::io(file_path, err))?;
+    }
+
+    Ok(chain.len())
+}
+
+/// Reads the lines of a covenant export file.
+fn read_covenant_export(file_path: &str) -> Result<Vec<CovenantExportLine>> {
+    let file = File::open(file_path).map_err(|err| SpacechainError::io(file_path, err))?;
+
+    let mut lines: Vec<CovenantExportLine> = Vec::new();
 
-        if txs.is_empty() {
+    for (line_number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|err| SpacechainError::io(file_path, err))?;
+
+        if line.trim().is_empty() {
             continue;
         }
 
-        assert!(txs.len() == 1);
-        assert!(previous_tx_ids.len() == 1);
+        let line = serde_json::from_str(&line).map_err(|err| {
+            SpacechainError::InvalidInput(format!(
+                "{}, line {}: {}",
+                file_path,
+                line_number + 1,
+                err
+            ))
+        })?;
+
+        lines.push(line);
+    }
+
+    Ok(lines)
+}
+
+pub fn import_covenant(cfg: &ConfigFile, file_path: &str) -> Result<usize> {
+    let network = cfg.get_network();
+
+    let invalid_export = SpacechainError::InvalidInput;
+
+    let mut lines = read_covenant_export(file_path)?.into_iter();
+
+    let (descriptor, metadata) = match lines.next() {
+        Some(CovenantExportLine::Header {
+            version,
+            network: file_network,
+            descriptor,
+            metadata,
+        }) => {
+            if version > COVENANT_EXPORT_VERSION {
+                return Err(invalid_export(format!(
+                    "{} has export format version {}, but this software only supports up to version {}.",
+                    file_path, version, COVENANT_EXPORT_VERSION
+                )));
+            }
+
+            let file_network = Network::from_str(&file_network).map_err(|_| {
+                invalid_export(format!("{} has unknown network {}.", file_path, file_network))
+            })?;
+            if file_network != network {
+                return Err(invalid_export(format!(
+                    "{} was exported for {}, but the configured network is {}.",
+                    file_path, file_network, network
+                )));
+            }
+
+            (descriptor, metadata)
+        }
+        _ => {
+            return Err(invalid_export(format!(
+                "{} does not start with a covenant header.",
+                file_path
+            )))
+        }
+    };
 
-        let satisfaction_weight = wallet
-            .get_descriptor_for_keychain(KeychainKind::External)
-            .max_satisfaction_weight()
-            .unwrap();
+    let mut chain: Vec<(Txid, Transaction)> = Vec::new();
+
+    for line in lines {
+        match line {
+            CovenantExportLine::Step {
+                step,
+                previous_txid,
+                tx,
+            } => {
+                if step != chain.len() {
+                    return Err(invalid_export(format!(
+                        "Expected step {}, found step {}.",
+                        chain.len(),
+                        step
+                    )));
+                }
+
+                let previous_txid = Txid::from_str(&previous_txid).map_err(|err| {
+                    invalid_export(format!("Step {}: invalid previous_txid: {}", step, err))
+                })?;
+                let tx: Transaction = Vec::<u8>::from_hex(&tx)
+                    .ok()
+                    .and_then(|tx_bytes| deserialize(&tx_bytes).ok())
+                    .ok_or_else(|| invalid_export(format!("Step {}: invalid transaction.", step)))?;
+
+                if let Some((_, previous_tx)) = chain.last() {
+                    if previous_tx.txid() != previous_txid {
+                        return Err(invalid_export(format!(
+                            "Step {} does not follow step {}: expected previous transaction {}, found {}.",
+                            step,
+                            step - 1,
+                            previous_tx.txid(),
+                            previous_txid
+                        )));
+                    }
+                }
+
+                chain.push((previous_txid, tx));
+            }
+            CovenantExportLine::Header { .. } => {
+                return Err(invalid_export(format!(
+                    "{} contains more than one covenant header.",
+                    file_path
+                )))
+            }
+        }
+    }
 
-        let tx = txs.get(0).unwrap();
-        let previous_tx_id = previous_tx_ids.get(0).unwrap();
+    verify_covenant_chain(&descriptor, &chain)?;
 
-        return Some((previous_tx_id.clone(), tx.clone(), satisfaction_weight));
+    let bump_script = match metadata.get(METADATA_BUMP_SCRIPT) {
+        Some(bump_script) => Script::from_hex(bump_script).map_err(|_| {
+            invalid_export(format!("{} has an invalid bump script.", file_path))
+        })?,
+        None => utils::build_bump_script(),
+    };
+    verify_covenant_anchors(&bump_script, &chain)?;
+
+    // Always import into the data directory, never into a `convenant.db`
+    // found in the current directory.
+    let path = utils::get_data_dir(cfg)?.join(COVENANT_DB_FILE);
+
+    let mut conn = Connection::open(path).map_err(db_error)?;
+
+    // Nothing is written unless the whole chain is imported.
+    let conn = conn.transaction().map_err(db_error)?;
+
+    migrate_covenant_db(&conn)?;
+
+    let existing_txs: usize = conn
+        .query_row("SELECT COUNT(*) FROM convenant_txs", [], |row| row.get(0))
+        .map_err(db_error)?;
+
+    if existing_txs > 0 {
+        return Err(SpacechainError::CovenantDb(format!(
+            "{} already contains a covenant chain. Please move it away before importing.",
+            COVENANT_DB_FILE
+        )));
+    }
+
+    conn.execute("DELETE FROM convenant_descriptor", [])
+        .map_err(db_error)?;
+
+    conn.execute(
+        "INSERT INTO convenant_descriptor (public_descriptor) VALUES (?1)",
+        params![descriptor],
+    )
+    .map_err(db_error)?;
+
+    for (previous_txid, tx) in chain.iter() {
+        conn.execute(
+            "INSERT INTO convenant_txs (previous_tx_id, tx_hex) VALUES (?1, ?2)",
+            params![serialize(previous_txid), serialize(tx)],
+        )
+        .map_err(db_error)?;
+    }
+
+    for (key, value) in metadata.iter() {
+        if key != METADATA_SCHEMA_VERSION && key != METADATA_NETWORK {
+            set_metadata(&conn, key, value)?;
+        }
     }
 
-    None
+    if !metadata.contains_key(METADATA_CREATOR) {
+        set_metadata(&conn, METADATA_CREATOR, UNKNOWN_CREATOR)?;
+    }
+
+    set_metadata(&conn, METADATA_NETWORK, &network.to_string())?;
+    set_metadata(&conn, METADATA_CHAIN_LENGTH, &chain.len().to_string())?;
+
+    if let Some(outpoint) = find_genesis_outpoint(&chain) {
+        set_metadata(&conn, METADATA_GENESIS_OUTPOINT, &outpoint.to_string())?;
+    }
+
+    conn.commit().map_err(db_error)?;
+
+    Ok(chain.len())
+}
+
+/// Returns the pre-signed transactions ordered from the genesis outpoint
+/// to the tip, each with the id of the transaction it spends.
+pub fn load_covenant_chain(cfg: &ConfigFile) -> Result<Vec<(Txid, Transaction)>> {
+    let conn = open_covenant_db(cfg)?;
+
+    order_covenant_chain(load_covenant_txs(&conn)?)
+        .ok_or_else(|| invalid_covenant_db("does not contain a single covenant chain"))
+}
+
+/// Returns the position of a pre-signed transaction in the covenant chain.
+pub fn get_covenant_step(cfg: &ConfigFile, txid: &Txid) -> Result<Option<usize>> {
+    Ok(load_covenant_chain(cfg)?
+        .iter()
+        .position(|(_, tx)| tx.txid() == *txid))
+}
+
+/// Returns the witness script of the P2WSH bump outputs of the covenant
+/// transactions, as recorded in the metadata.
+pub fn load_bump_script(cfg: &ConfigFile) -> Result<Script> {
+    let conn = open_covenant_db(cfg)?;
+
+    let bump_script = get_metadata(&conn, METADATA_BUMP_SCRIPT)?
+        .ok_or_else(|| invalid_covenant_db("no bump script in the metadata"))?;
+
+    Script::from_hex(&bump_script).map_err(|_| invalid_covenant_db("invalid bump script"))
+}
+
+pub fn load_convenant_wallet_from_db(cfg: &ConfigFile) -> Result<Wallet<SqliteDatabase>> {
+    let conn = open_covenant_db(cfg)?;
+
+    let descriptor = load_covenant_descriptor(&conn)?;
+
+    load_convenant_wallet(cfg, &descriptor)
+}
+
+pub fn get_covenant_tx_from_db(
+    cfg: &ConfigFile,
+    wallet: &Wallet<SqliteDatabase>,
+) -> Result<Option<(Txid, Transaction)>> {
+    let conn = open_covenant_db(cfg)?;
+
+    let mut stmt = conn
+        .prepare("SELECT previous_tx_id, tx_hex FROM convenant_txs WHERE previous_tx_id=(?1)")
+        .map_err(db_error)?;
+
+    for utxo in wallet.list_unspent()?.iter() {
+        let prev_txid_bytes = serialize(&utxo.outpoint.txid);
+
+        // `previous_tx_id` is unique, so there is at most one row.
+        let row: Option<(Vec<u8>, Vec<u8>)> = stmt
+            .query_row(params![prev_txid_bytes], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()
+            .map_err(db_error)?;
+
+        if let Some((previous_tx_id_bytes, tx_bytes)) = row {
+            return decode_covenant_tx(&previous_tx_id_bytes, &tx_bytes).map(Some);
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_file::{self, Backend, ChainNetwork};
+
+    fn test_config(name: &str, network: ChainNetwork) -> ConfigFile {
+        let mut cfg = config_file::default_config(network, Backend::Electrum);
+        cfg.datadir = std::env::temp_dir().join(format!(
+            "spacechains-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&cfg.datadir);
+        cfg
+    }
+
+    /// Copies the `convenant.db` shipped with the repository, which is a
+    /// testnet covenant, into the data directory of `cfg`.
+    fn copy_shipped_covenant_db(cfg: &ConfigFile) {
+        let shipped = Path::new(env!("CARGO_MANIFEST_DIR")).join(COVENANT_DB_FILE);
+        std::fs::copy(shipped, utils::get_data_dir(cfg).unwrap().join(COVENANT_DB_FILE)).unwrap();
+        set_covenant_network(cfg, Network::Testnet).unwrap();
+    }
+
+    #[test]
+    fn migrates_the_shipped_covenant_db() {
+        let cfg = test_config("covenant-migrate", ChainNetwork::Testnet);
+        let path = get_covenant_db_path(&cfg).unwrap();
+        std::fs::copy(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join(COVENANT_DB_FILE),
+            &path,
+        )
+        .unwrap();
+
+        // The network is not recorded, so the database is refused, but it
+        // is migrated.
+        assert!(matches!(
+            open_covenant_db(&cfg),
+            Err(SpacechainError::CovenantDb(_))
+        ));
+
+        let conn = Connection::open(&path).unwrap();
+        assert_eq!(get_schema_version(&conn).unwrap(), COVENANT_DB_SCHEMA_VERSION);
+        assert_eq!(
+            get_metadata(&conn, METADATA_CREATOR).unwrap().as_deref(),
+            Some(UNKNOWN_CREATOR)
+        );
+        assert_eq!(
+            get_metadata(&conn, METADATA_BUMP_SCRIPT).unwrap(),
+            Some(utils::build_bump_script().to_hex())
+        );
+        assert_eq!(get_metadata(&conn, METADATA_NETWORK).unwrap(), None);
+
+        set_covenant_network(&cfg, Network::Testnet).unwrap();
+        open_covenant_db(&cfg).unwrap();
+
+        std::fs::remove_dir_all(&cfg.datadir).unwrap();
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let source = test_config("covenant-source", ChainNetwork::Testnet);
+        let target = test_config("covenant-target", ChainNetwork::Testnet);
+        copy_shipped_covenant_db(&source);
+
+        let export_path = source.datadir.join("covenant.jsonl");
+        let export_path = export_path.to_str().unwrap();
+        let steps = export_covenant(&source, export_path).unwrap();
+        assert!(steps > 0);
+
+        assert_eq!(import_covenant(&target, export_path).unwrap(), steps);
+        assert_eq!(
+            load_covenant_chain(&target).unwrap(),
+            load_covenant_chain(&source).unwrap()
+        );
+
+        let reexport_path = target.datadir.join("covenant.jsonl");
+        let reexport_path = reexport_path.to_str().unwrap();
+        export_covenant(&target, reexport_path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(export_path).unwrap(),
+            std::fs::read_to_string(reexport_path).unwrap()
+        );
+
+        std::fs::remove_dir_all(&source.datadir).unwrap();
+        std::fs::remove_dir_all(&target.datadir).unwrap();
+    }
+
+    #[test]
+    fn import_rejects_another_network() {
+        let source = test_config("covenant-testnet", ChainNetwork::Testnet);
+        let target = test_config("covenant-signet", ChainNetwork::Signet);
+        copy_shipped_covenant_db(&source);
+
+        let export_path = source.datadir.join("covenant.jsonl");
+        let export_path = export_path.to_str().unwrap();
+        export_covenant(&source, export_path).unwrap();
+
+        assert!(matches!(
+            import_covenant(&target, export_path),
+            Err(SpacechainError::InvalidInput(_))
+        ));
+        assert!(!utils::get_data_dir(&target)
+            .unwrap()
+            .join(COVENANT_DB_FILE)
+            .exists());
+
+        std::fs::remove_dir_all(&source.datadir).unwrap();
+        let _ = std::fs::remove_dir_all(&target.datadir);
+    }
 }
@@ -0,0 +1,126 @@
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, SpacechainError>;
+
+/// The reasons for rejecting a transaction that mining reacts to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BroadcastRejection {
+    /// An input is unknown to the node or already spent, e.g. because the
+    /// parent transaction has not reached its mempool yet.
+    MissingInputs,
+    /// The relative timelock of an input has not expired yet.
+    NonFinal,
+}
+
+/// Errors returned by spacechains operations. Each kind maps to a distinct
+/// process exit code, so that scripts can branch on the failure.
+#[derive(Debug)]
+pub enum SpacechainError {
+    /// The config file cannot be read or written, or has invalid values.
+    Config(String),
+    /// The keystore database failed, or a name is already taken.
+    Keystore(String),
+    /// There is no wallet with this name in the keystore.
+    WalletNotFound(String),
+    /// `convenant.db` is missing, invalid or for another network, or a
+    /// covenant chain is inconsistent.
+    CovenantDb(String),
+    /// The Electrum server or the Bitcoin Core node failed.
+    Backend(String),
+    /// A BDK wallet operation failed.
+    Wallet(bdk::Error),
+    /// The wallet cannot pay for the transaction.
+    InsufficientFunds { needed: u64, available: u64 },
+    /// A transaction or message could not be signed.
+    Signing(String),
+    /// A transaction was rejected when broadcast. `rejection` is set when
+    /// the reason is one mining reacts to.
+    Broadcast {
+        rejection: Option<BroadcastRejection>,
+        message: String,
+    },
+    /// An argument or input file is invalid.
+    InvalidInput(String),
+    /// A notification hook failed. Hooks never interrupt mining, so this
+    /// is only reported.
+    Notification(String),
+    /// A file could not be read or written.
+    Io { path: String, source: std::io::Error },
+}
+
+impl SpacechainError {
+    pub(crate) fn io(path: &str, source: std::io::Error) -> Self {
+        SpacechainError::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    /// Exit code of the CLI for this error. Codes start at 10, as 1 is used
+    /// by panics and 2 by invalid command line arguments.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            SpacechainError::Config(_) => 10,
+            SpacechainError::Keystore(_) => 11,
+            SpacechainError::WalletNotFound(_) => 12,
+            SpacechainError::CovenantDb(_) => 13,
+            SpacechainError::Backend(_) => 14,
+            SpacechainError::Wallet(_) => 15,
+            SpacechainError::InsufficientFunds { .. } => 16,
+            SpacechainError::Signing(_) => 17,
+            SpacechainError::Broadcast { .. } => 18,
+            SpacechainError::InvalidInput(_) => 19,
+            SpacechainError::Io { .. } => 20,
+            SpacechainError::Notification(_) => 21,
+        }
+    }
+}
+
+impl fmt::Display for SpacechainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpacechainError::Config(msg) => write!(f, "Config error: {}", msg),
+            SpacechainError::Keystore(msg) => write!(f, "Keystore error: {}", msg),
+            SpacechainError::WalletNotFound(wallet_name) => {
+                write!(f, "Wallet {} not found !", wallet_name)
+            }
+            SpacechainError::CovenantDb(msg) => write!(f, "Covenant error: {}", msg),
+            SpacechainError::Backend(msg) => write!(f, "Backend error: {}", msg),
+            SpacechainError::Wallet(err) => write!(f, "Wallet error: {}", err),
+            SpacechainError::InsufficientFunds { needed, available } => write!(
+                f,
+                "Insufficient funds ! {} sats needed, {} sats available.",
+                needed, available
+            ),
+            SpacechainError::Signing(msg) => write!(f, "Signing error: {}", msg),
+            SpacechainError::Broadcast { message, .. } => {
+                write!(f, "Broadcast error: {}", message)
+            }
+            SpacechainError::InvalidInput(msg) => write!(f, "{}", msg),
+            SpacechainError::Notification(msg) => write!(f, "Notification error: {}", msg),
+            SpacechainError::Io { path, source } => write!(f, "{}: {}", path, source),
+        }
+    }
+}
+
+impl std::error::Error for SpacechainError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SpacechainError::Wallet(err) => Some(err),
+            SpacechainError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<bdk::Error> for SpacechainError {
+    fn from(err: bdk::Error) -> Self {
+        match err {
+            bdk::Error::InsufficientFunds { needed, available } => {
+                SpacechainError::InsufficientFunds { needed, available }
+            }
+            bdk::Error::Signer(err) => SpacechainError::Signing(err.to_string()),
+            err => SpacechainError::Wallet(err),
+        }
+    }
+}
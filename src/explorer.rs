@@ -0,0 +1,237 @@
+use std::collections::BTreeSet;
+
+use bdk::{
+    bitcoin::{
+        blockdata::script::Instruction, consensus::serialize, hashes::hex::ToHex, OutPoint,
+        Transaction, Txid,
+    },
+    BlockTime,
+};
+use serde_json::{json, Value};
+
+use crate::error::{Result, SpacechainError};
+use crate::{config_file::ConfigFile, covenant, utils, wallet_manager};
+
+/// A confirmed step of the covenant chain, i.e. a spacechain block.
+/// The CPFP child is `None` if it could not be found in the confirmation block.
+struct SpacechainBlock {
+    height: usize,
+    previous_txid: Txid,
+    covenant_tx: Transaction,
+    confirmation_time: BlockTime,
+    cpfp: Option<(Transaction, u64)>,
+}
+
+/// Ids and confirmation height of a spacechain block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfirmedBlock {
+    pub height: usize,
+    pub covenant_txid: Txid,
+    pub cpfp_txid: Option<Txid>,
+    pub confirmation_height: u32,
+}
+
+/// Returns the data pushed by the first `OP_RETURN` output of `tx`.
+pub fn get_op_return_payload(tx: &Transaction) -> Option<Vec<u8>> {
+    let out = tx
+        .output
+        .iter()
+        .find(|out| out.script_pubkey.is_op_return())?;
+
+    let payload: Vec<u8> = out
+        .script_pubkey
+        .instructions()
+        .filter_map(|instruction| match instruction {
+            Ok(Instruction::PushBytes(bytes)) => Some(bytes.to_vec()),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+
+    Some(payload)
+}
+
+/// Walks the covenant chain and returns the steps that have been confirmed,
+/// in order, skipping the heights for which `include` returns false.
+/// The CPFP child of a step is only looked up if `lookup_cpfp` returns true
+/// for its height and confirmation height.
+fn get_spacechain_blocks(
+    cfg: &ConfigFile,
+    include: impl Fn(usize) -> bool,
+    lookup_cpfp: impl Fn(usize, u32) -> bool,
+) -> Result<Vec<SpacechainBlock>> {
+    let covenant_wallet = covenant::load_convenant_wallet_from_db(cfg)?;
+    utils::sync_wallet(cfg, "covenant", &covenant_wallet, None)?;
+
+    let bump_script = covenant::load_bump_script(cfg)?;
+
+    let mut blocks: Vec<SpacechainBlock> = Vec::new();
+
+    for (height, (previous_txid, covenant_tx)) in
+        covenant::load_covenant_chain(cfg)?.into_iter().enumerate()
+    {
+        let txid = covenant_tx.txid();
+
+        let confirmation_time = match covenant_wallet.get_tx(&txid, false)? {
+            Some(details) => details.confirmation_time,
+            None => None,
+        };
+
+        let confirmation_time = match confirmation_time {
+            Some(confirmation_time) => confirmation_time,
+            None => break,
+        };
+
+        if !include(height) {
+            continue;
+        }
+
+        let mut cpfp: Option<(Transaction, u64)> = None;
+
+        let anchors = if lookup_cpfp(height, confirmation_time.height) {
+            wallet_manager::find_anchor_outputs(&covenant_tx, &bump_script)
+        } else {
+            Vec::new()
+        };
+
+        for (vout, _, _) in anchors {
+            let outpoint = OutPoint { txid, vout };
+            cpfp = utils::find_spending_tx(cfg, &outpoint, confirmation_time.height)?;
+            if cpfp.is_some() {
+                break;
+            }
+        }
+
+        blocks.push(SpacechainBlock {
+            height,
+            previous_txid,
+            covenant_tx,
+            confirmation_time,
+            cpfp,
+        });
+    }
+
+    Ok(blocks)
+}
+
+fn block_summary(block: &SpacechainBlock) -> Value {
+    let payload = block
+        .cpfp
+        .as_ref()
+        .and_then(|(cpfp_tx, _)| get_op_return_payload(cpfp_tx));
+
+    json!({
+        "height": block.height,
+        "covenant_txid": block.covenant_tx.txid(),
+        "cpfp_txid": block.cpfp.as_ref().map(|(cpfp_tx, _)| cpfp_tx.txid()),
+        "confirmation_height": block.confirmation_time.height,
+        "fee": block.cpfp.as_ref().map(|(_, fee)| *fee),
+        "payload": payload.as_ref().map(|payload| String::from_utf8_lossy(payload).to_string()),
+        "payload_hex": payload.as_ref().map(|payload| payload.to_hex()),
+    })
+}
+
+/// Returns the CPFP transaction id of each confirmed block among `heights`.
+pub fn get_block_cpfp_txids(
+    cfg: &ConfigFile,
+    heights: &BTreeSet<usize>,
+) -> Result<Vec<(usize, Option<Txid>)>> {
+    Ok(get_spacechain_blocks(cfg, |height| heights.contains(&height), |_, _| true)?
+        .iter()
+        .map(|block| {
+            let cpfp_txid = block.cpfp.as_ref().map(|(cpfp_tx, _)| cpfp_tx.txid());
+            (block.height, cpfp_txid)
+        })
+        .collect())
+}
+
+/// Returns the confirmed blocks, with their CPFP transaction id.
+/// `known_cpfp` returns the CPFP transaction id already known for a step
+/// confirmed at a given height, which is then not looked up again.
+pub fn get_confirmed_blocks(
+    cfg: &ConfigFile,
+    known_cpfp: impl Fn(usize, u32) -> Option<Txid>,
+) -> Result<Vec<ConfirmedBlock>> {
+    Ok(get_spacechain_blocks(
+        cfg,
+        |_| true,
+        |height, confirmation_height| known_cpfp(height, confirmation_height).is_none(),
+    )?
+    .iter()
+    .map(|block| ConfirmedBlock {
+        height: block.height,
+        covenant_txid: block.covenant_tx.txid(),
+        cpfp_txid: block
+            .cpfp
+            .as_ref()
+            .map(|(cpfp_tx, _)| cpfp_tx.txid())
+            .or_else(|| known_cpfp(block.height, block.confirmation_time.height)),
+        confirmation_height: block.confirmation_time.height,
+    })
+    .collect())
+}
+
+/// Lists the spacechain blocks with heights between `from` and `to`, inclusive.
+pub fn list_blocks(
+    cfg: &ConfigFile,
+    from: Option<usize>,
+    to: Option<usize>,
+) -> Result<Vec<Value>> {
+    let from = from.unwrap_or(0);
+    let to = to.unwrap_or(usize::MAX);
+
+    Ok(
+        get_spacechain_blocks(cfg, |height| height >= from && height <= to, |_, _| true)?
+            .iter()
+            .map(block_summary)
+            .collect(),
+    )
+}
+
+/// Shows a spacechain block given its height, the id of its covenant
+/// transaction or the id of its CPFP transaction.
+pub fn show_block(cfg: &ConfigFile, block_id: &str) -> Result<Option<Value>> {
+    let blocks = match block_id.parse::<usize>() {
+        Ok(block_height) => {
+            get_spacechain_blocks(cfg, |height| height == block_height, |_, _| true)?
+        }
+        Err(_) => {
+            let block_txid: Txid = block_id.parse().map_err(|_| {
+                SpacechainError::InvalidInput(format!(
+                    "Invalid block {}. Expected a height or a transaction id.",
+                    block_id
+                ))
+            })?;
+            get_spacechain_blocks(cfg, |_| true, |_, _| true)?
+                .into_iter()
+                .filter(|block| {
+                    block.covenant_tx.txid() == block_txid
+                        || block
+                            .cpfp
+                            .as_ref()
+                            .map(|(cpfp_tx, _)| cpfp_tx.txid() == block_txid)
+                            .unwrap_or(false)
+                })
+                .collect()
+        }
+    };
+
+    let block = match blocks.first() {
+        Some(block) => block,
+        None => return Ok(None),
+    };
+
+    let mut obj = block_summary(block);
+
+    obj["previous_covenant_txid"] = json!(block.previous_txid);
+    obj["confirmation_block_hash"] =
+        json!(utils::get_block_hash(cfg, block.confirmation_time.height)?);
+    obj["confirmation_timestamp"] = json!(block.confirmation_time.timestamp);
+    obj["covenant_tx_hex"] = json!(serialize(&block.covenant_tx).to_hex());
+    obj["cpfp_tx_hex"] = json!(block
+        .cpfp
+        .as_ref()
+        .map(|(cpfp_tx, _)| serialize(cpfp_tx).to_hex()));
+
+    Ok(Some(obj))
+}
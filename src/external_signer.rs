@@ -0,0 +1,237 @@
+use std::cell::RefCell;
+use std::process::Command;
+use std::str::FromStr;
+
+use bdk::{
+    bitcoin::{
+        psbt::PartiallySignedTransaction,
+        secp256k1::{All, Secp256k1},
+        util::bip32::Fingerprint,
+        Network,
+    },
+    database::BatchDatabase,
+    signer::{SignerCommon, SignerError, SignerId, TransactionSigner},
+    SignOptions, Wallet,
+};
+use serde_json::Value;
+
+use crate::error::{Result, SpacechainError};
+
+/// Returns the chain name used by HWI and Bitcoin Core for `network`.
+fn chain_name(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "main",
+        Network::Testnet => "test",
+        Network::Signet => "signet",
+        Network::Regtest => "regtest",
+    }
+}
+
+/// Runs an HWI-compatible signer command and returns its JSON output.
+fn run_signer_command(
+    command: &str,
+    fingerprint: Option<&Fingerprint>,
+    network: Network,
+    args: &[&str],
+) -> Result<Value> {
+    let mut cmd = Command::new(command);
+
+    if let Some(fingerprint) = fingerprint {
+        cmd.arg("--fingerprint").arg(fingerprint.to_string());
+    }
+
+    let output = cmd
+        .arg("--chain")
+        .arg(chain_name(network))
+        .args(args)
+        .output()
+        .map_err(|err| {
+            SpacechainError::Signing(format!(
+                "Could not run the external signer {}: {}",
+                command, err
+            ))
+        })?;
+
+    let result: Value = serde_json::from_slice(&output.stdout).map_err(|_| {
+        SpacechainError::Signing(format!(
+            "The external signer {} returned an invalid response: {}{}",
+            command,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ))
+    })?;
+
+    if let Some(error) = result.get("error") {
+        return Err(SpacechainError::Signing(format!(
+            "The external signer {} failed: {}",
+            command, error
+        )));
+    }
+
+    Ok(result)
+}
+
+/// Lists the fingerprints of the devices the signer command can see.
+pub fn enumerate(command: &str, network: Network) -> Result<Vec<Fingerprint>> {
+    let result = run_signer_command(command, None, network, &["enumerate"])?;
+
+    let invalid_response = || {
+        SpacechainError::Signing(format!(
+            "The external signer {} did not return a list of devices.",
+            command
+        ))
+    };
+
+    result
+        .as_array()
+        .ok_or_else(invalid_response)?
+        .iter()
+        .filter_map(|device| device["fingerprint"].as_str())
+        .map(|fingerprint| Fingerprint::from_str(fingerprint).map_err(|_| invalid_response()))
+        .collect()
+}
+
+/// Returns the public external and internal `wpkh` descriptors of an account
+/// of the device.
+pub fn get_descriptors(
+    command: &str,
+    fingerprint: &Fingerprint,
+    network: Network,
+    account: u32,
+) -> Result<(String, String)> {
+    let account = account.to_string();
+
+    let result = run_signer_command(
+        command,
+        Some(fingerprint),
+        network,
+        &["getdescriptors", "--account", &account],
+    )?;
+
+    let find_wpkh = |key: &str| -> Result<String> {
+        result[key]
+            .as_array()
+            .and_then(|descriptors| {
+                descriptors
+                    .iter()
+                    .filter_map(|descriptor| descriptor.as_str())
+                    .find(|descriptor| descriptor.starts_with("wpkh("))
+            })
+            .map(ToString::to_string)
+            .ok_or_else(|| {
+                SpacechainError::Signing(format!(
+                    "The external signer {} did not return a {} wpkh descriptor.",
+                    command, key
+                ))
+            })
+    };
+
+    Ok((find_wpkh("receive")?, find_wpkh("internal")?))
+}
+
+thread_local! {
+    /// The reason the last external signing on this thread failed. bdk's
+    /// `SignerError` cannot carry a message, so it is kept here until `sign`
+    /// returns.
+    static SIGNING_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn take_signing_error() -> Option<String> {
+    SIGNING_ERROR.with(|error| error.borrow_mut().take())
+}
+
+/// Signs `psbt` with the signers of `wallet` and returns whether all inputs
+/// are finalized. If an external signer fails, the error carries its reason.
+pub fn sign<D: BatchDatabase>(
+    wallet: &Wallet<D>,
+    psbt: &mut PartiallySignedTransaction,
+    sign_options: SignOptions,
+) -> Result<bool> {
+    take_signing_error();
+
+    let result = wallet.sign(psbt, sign_options);
+    let reason = take_signing_error();
+
+    result.map_err(|err| match (err, reason) {
+        (bdk::Error::Signer(_), Some(reason)) => SpacechainError::Signing(reason),
+        (err, _) => err.into(),
+    })
+}
+
+fn signing_error(msg: String) -> SignerError {
+    SIGNING_ERROR.with(|error| *error.borrow_mut() = Some(msg));
+    SignerError::UserCanceled
+}
+
+/// A signer that delegates signing to an HWI-compatible command, so that the
+/// keys of the wallet never touch the disk.
+#[derive(Debug)]
+pub struct ExternalSigner {
+    command: String,
+    fingerprint: Fingerprint,
+    network: Network,
+    account: u32,
+}
+
+impl ExternalSigner {
+    pub fn new(command: &str, fingerprint: Fingerprint, network: Network, account: u32) -> Self {
+        ExternalSigner {
+            command: command.to_string(),
+            fingerprint,
+            network,
+            account,
+        }
+    }
+}
+
+impl SignerCommon for ExternalSigner {
+    fn id(&self, _secp: &Secp256k1<All>) -> SignerId {
+        SignerId::Fingerprint(self.fingerprint)
+    }
+}
+
+impl TransactionSigner for ExternalSigner {
+    fn sign_transaction(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        _sign_options: &SignOptions,
+        _secp: &Secp256k1<All>,
+    ) -> std::result::Result<(), SignerError> {
+        let account = self.account.to_string();
+
+        let result = run_signer_command(
+            &self.command,
+            Some(&self.fingerprint),
+            self.network,
+            &["signtx", "--account", &account, &psbt.to_string()],
+        )
+        .map_err(|err| match err {
+            SpacechainError::Signing(msg) => signing_error(msg),
+            err => signing_error(err.to_string()),
+        })?;
+
+        let signed_psbt = result["psbt"]
+            .as_str()
+            .and_then(|signed_psbt| PartiallySignedTransaction::from_str(signed_psbt).ok())
+            .ok_or_else(|| {
+                signing_error(format!(
+                    "The external signer {} did not return a valid PSBT.",
+                    self.command
+                ))
+            })?;
+
+        if signed_psbt.unsigned_tx != psbt.unsigned_tx {
+            return Err(signing_error(format!(
+                "The external signer {} returned a different transaction.",
+                self.command
+            )));
+        }
+
+        psbt.combine(signed_psbt).map_err(|err| {
+            signing_error(format!(
+                "Could not combine the PSBT of the external signer {}: {}",
+                self.command, err
+            ))
+        })
+    }
+}
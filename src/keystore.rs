@@ -1,162 +1,771 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use bdk::bitcoin::secp256k1::Secp256k1;
 use bdk::bitcoin::util::bip32::DerivationPath;
-use bdk::bitcoin::util::bip32::ExtendedPrivKey;
-use bdk::bitcoin::Network;
+use bdk::bitcoin::util::bip32::{ExtendedPrivKey, ExtendedPubKey, Fingerprint};
+use bdk::bitcoin::{Network, OutPoint};
 use bdk::descriptor::IntoWalletDescriptor;
 use bdk::keys::{
     bip39::{Language, Mnemonic, WordCount},
     GeneratableKey, GeneratedKey,
 };
-use bdk::miniscript::Tap;
-use rusqlite::{params, Connection};
+use bdk::miniscript::{Segwitv0, Tap};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
 
-use crate::utils;
+use crate::error::{Result, SpacechainError};
 
-pub fn create_new_wallet_desc(wallet_name: &str, network: Network) {
+fn db_error(err: rusqlite::Error) -> SpacechainError {
+    SpacechainError::Keystore(err.to_string())
+}
+
+fn key_error(err: impl std::fmt::Display) -> SpacechainError {
+    SpacechainError::Keystore(format!("Key generation error: {}", err))
+}
+
+/// Current time as a unix timestamp, used as the birthdate of new wallets.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn create_new_wallet_desc(
+    database_file: &PathBuf,
+    wallet_name: &str,
+    network: Network,
+) -> Result<()> {
     let mnemonic: GeneratedKey<Mnemonic, Tap> =
         Mnemonic::generate((WordCount::Words12, Language::English))
-            .map_err(|_| bdk::Error::Generic("Mnemonic generation error".to_string()))
-            .unwrap();
+            .map_err(|_| key_error("Mnemonic generation error"))?;
 
     let seed = mnemonic.to_seed("");
 
-    let xprv = ExtendedPrivKey::new_master(network, &seed).unwrap();
+    let xprv = ExtendedPrivKey::new_master(network, &seed).map_err(key_error)?;
 
-    let external_path = DerivationPath::from_str("m/84h/0h/0h/0").unwrap();
-    let internal_path = DerivationPath::from_str("m/84h/0h/0h/1").unwrap();
+    let external_path = DerivationPath::from_str("m/84h/0h/0h/0").map_err(key_error)?;
+    let internal_path = DerivationPath::from_str("m/84h/0h/0h/1").map_err(key_error)?;
 
     let secp = Secp256k1::new();
 
-    let (external_descriptor, ext_keymap) = bdk::descriptor!(wpkh((xprv.clone(), external_path)))
-        .unwrap()
+    let (external_descriptor, ext_keymap) = bdk::descriptor!(wpkh((xprv, external_path)))
+        .map_err(key_error)?
         .into_wallet_descriptor(&secp, network)
-        .unwrap();
+        .map_err(key_error)?;
 
-    let (internal_descriptor, int_keymap) = bdk::descriptor!(wpkh((xprv.clone(), internal_path)))
-        .unwrap()
+    let (internal_descriptor, int_keymap) = bdk::descriptor!(wpkh((xprv, internal_path)))
+        .map_err(key_error)?
         .into_wallet_descriptor(&secp, network)
-        .unwrap();
+        .map_err(key_error)?;
 
     let external_descriptor_str = external_descriptor.to_string_with_secret(&ext_keymap);
 
     let internal_descriptor_str = internal_descriptor.to_string_with_secret(&int_keymap);
 
-    let birthdate = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-
-    let path = utils::get_keystore_db_path();
-
     write_wallet_data(
-        &path,
+        database_file,
         wallet_name,
         &external_descriptor_str,
         &Some(internal_descriptor_str),
-        birthdate,
-    );
+        now(),
+    )
 }
 
 pub fn import_wallet_desc(
+    database_file: &PathBuf,
     wallet_name: &str,
     external_descriptor: &String,
     internal_descriptor: &Option<String>,
-) {
-    let path = utils::get_keystore_db_path();
-
-    let birthdate = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-
+    birthdate: u64,
+) -> Result<()> {
     write_wallet_data(
-        &path,
+        database_file,
         wallet_name,
         external_descriptor,
         internal_descriptor,
         birthdate,
-    );
+    )
 }
 
-fn write_wallet_data(
+pub fn write_wallet_data(
     database_file: &PathBuf,
     wallet_name: &str,
     external_descriptor: &String,
     internal_descriptor: &Option<String>,
     birthdate: u64,
-) {
-    let conn = Connection::open(database_file).unwrap();
+) -> Result<()> {
+    let conn = open_wallet_data_table(database_file)?;
+
+    let internal_descriptor_data: Option<Vec<u8>> = internal_descriptor
+        .as_ref()
+        .map(|desc| desc.as_bytes().to_vec());
+
+    match conn.execute(
+        "INSERT INTO wallet_data (wallet_name, external_descriptor, internal_descriptor, birthdate) VALUES (?1, ?2, ?3, ?4)",
+        params![wallet_name, external_descriptor.as_bytes(), internal_descriptor_data, birthdate],
+    ) {
+        Ok(_) => Ok(()),
+        Err(rusqlite::Error::SqliteFailure(rc, _))
+            if rc.code == rusqlite::ErrorCode::ConstraintViolation =>
+        {
+            Err(SpacechainError::Keystore(format!(
+                "There is already a wallet with the name {}. Please choose another name.",
+                wallet_name
+            )))
+        }
+        Err(err) => Err(db_error(err)),
+    }
+}
+
+fn open_wallet_data_table(database_file: &PathBuf) -> Result<Connection> {
+    let conn = Connection::open(database_file).map_err(db_error)?;
 
     conn.execute(
         "CREATE TABLE IF NOT EXISTS wallet_data (wallet_name TEXT UNIQUE NOT NULL, external_descriptor BLOB NOT NULL, internal_descriptor BLOB, birthdate INT NOT NULL);",
         [],
     )
-    .unwrap();
+    .map_err(db_error)?;
 
-    let mut internal_descriptor_data: Option<Vec<u8>> = None;
-    if internal_descriptor.is_some() {
-        let desc: Vec<u8> = (*internal_descriptor.clone().unwrap().as_bytes()).to_vec();
-        internal_descriptor_data = Some(desc);
-    }
+    Ok(conn)
+}
+
+/// Derivation path of the keys generated for multisig wallets, without the
+/// `m/` prefix. Test networks use coin type 1, as BIP 48 requires.
+fn signer_key_path(network: Network) -> String {
+    let coin_type = match network {
+        Network::Bitcoin => 0,
+        _ => 1,
+    };
+
+    format!("48h/{}h/0h/2h", coin_type)
+}
+
+/// Unspendable internal key of taproot multisig descriptors, from BIP341.
+const NUMS_INTERNAL_KEY: &str = "50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0";
+
+fn open_signer_keys_table(database_file: &PathBuf) -> Result<Connection> {
+    let conn = Connection::open(database_file).map_err(db_error)?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS signer_keys (key_name TEXT UNIQUE NOT NULL, private_key TEXT NOT NULL, public_key TEXT NOT NULL);",
+        [],
+    )
+    .map_err(db_error)?;
+
+    Ok(conn)
+}
+
+/// Generates a key to take part in multisig wallets and stores it under
+/// `key_name`. Returns its public key expression, to share with the cosigners.
+/// Like wallet descriptors, the private key is stored unencrypted in the
+/// keystore.
+pub fn create_signer_key(
+    database_file: &PathBuf,
+    key_name: &str,
+    network: Network,
+) -> Result<String> {
+    let mnemonic: GeneratedKey<Mnemonic, Segwitv0> =
+        Mnemonic::generate((WordCount::Words12, Language::English))
+            .map_err(|_| key_error("Mnemonic generation error"))?;
+
+    let seed = mnemonic.to_seed("");
+
+    let secp = Secp256k1::new();
+
+    let master_xprv = ExtendedPrivKey::new_master(network, &seed).map_err(key_error)?;
+
+    let key_path = signer_key_path(network);
+
+    let path = DerivationPath::from_str(&format!("m/{}", key_path)).map_err(key_error)?;
+
+    let account_xprv = master_xprv.derive_priv(&secp, &path).map_err(key_error)?;
+    let account_xpub = ExtendedPubKey::from_priv(&secp, &account_xprv);
+
+    let origin = format!("[{}/{}]", master_xprv.fingerprint(&secp), key_path);
+
+    let private_key = format!("{}{}", origin, account_xprv);
+    let public_key = format!("{}{}", origin, account_xpub);
+
+    let conn = open_signer_keys_table(database_file)?;
 
     match conn.execute(
-        "INSERT INTO wallet_data (wallet_name, external_descriptor, internal_descriptor, birthdate) VALUES (?1, ?2, ?3, ?4)",
-        params![wallet_name, external_descriptor.as_bytes(), internal_descriptor_data, birthdate],
+        "INSERT INTO signer_keys (key_name, private_key, public_key) VALUES (?1, ?2, ?3)",
+        params![key_name, private_key, public_key],
     ) {
-        Ok(_) => {},
-        Err(rusqlite::Error::SqliteFailure(rc, _ )) => {
-            if rc.code == rusqlite::ErrorCode::ConstraintViolation {
-                assert_eq!(rc.extended_code, 2067);
-                panic!("There is already a wallet with the name {}. Please choose another name.", wallet_name);
-            }
-
-        },
-        Err(err) => panic!("{}", err)
+        Ok(_) => Ok(public_key),
+        Err(rusqlite::Error::SqliteFailure(rc, _))
+            if rc.code == rusqlite::ErrorCode::ConstraintViolation =>
+        {
+            Err(SpacechainError::Keystore(format!(
+                "There is already a signer key with the name {}. Please choose another name.",
+                key_name
+            )))
+        }
+        Err(err) => Err(db_error(err)),
     }
 }
 
-pub fn load_descriptors(
+/// Returns the private and public key expressions of a signer key.
+pub fn load_signer_key(
     database_file: &PathBuf,
-    wallet_name: &String,
-) -> Option<(String, Option<String>, u64)> {
-    let conn = Connection::open(database_file).unwrap();
+    key_name: &str,
+) -> Result<Option<(String, String)>> {
+    let conn = open_signer_keys_table(database_file)?;
+
+    conn.query_row(
+        "SELECT private_key, public_key FROM signer_keys WHERE key_name = ?1",
+        [key_name],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+    .map_err(db_error)
+}
+
+/// Builds the descriptor of one keychain of a multisig wallet. Keys are given
+/// as (public, descriptor) expression pairs and sorted by their public form,
+/// so that every cosigner derives the same taproot script.
+fn build_multisig_descriptor(
+    threshold: usize,
+    keys: &[(String, String)],
+    keychain: u32,
+    taproot: bool,
+    network: Network,
+) -> Result<String> {
+    let mut keys = keys.to_vec();
+    keys.sort();
+
+    let keys: Vec<String> = keys
+        .iter()
+        .map(|(_, key)| format!("{}/{}/*", key, keychain))
+        .collect();
+
+    let descriptor = if taproot {
+        format!(
+            "tr({},multi_a({},{}))",
+            NUMS_INTERNAL_KEY,
+            threshold,
+            keys.join(",")
+        )
+    } else {
+        format!("wsh(sortedmulti({},{}))", threshold, keys.join(","))
+    };
+
+    let secp = Secp256k1::new();
+
+    let (descriptor, keymap) = descriptor
+        .as_str()
+        .into_wallet_descriptor(&secp, network)
+        .map_err(|err| {
+            SpacechainError::InvalidInput(format!("Invalid multisig descriptor: {}", err))
+        })?;
+
+    Ok(descriptor.to_string_with_secret(&keymap))
+}
+
+/// Creates a `threshold`-of-n multisig wallet from the cosigner public keys
+/// and, if given, a local signer key.
+pub fn create_multisig_wallet_desc(
+    database_file: &PathBuf,
+    wallet_name: &str,
+    threshold: usize,
+    signer_key: &Option<String>,
+    cosigners: &[String],
+    taproot: bool,
+    network: Network,
+) -> Result<()> {
+    let mut keys: Vec<(String, String)> = cosigners
+        .iter()
+        .map(|cosigner| (cosigner.clone(), cosigner.clone()))
+        .collect();
+
+    if let Some(key_name) = signer_key {
+        let (private_key, public_key) = load_signer_key(database_file, key_name)?.ok_or_else(|| {
+            SpacechainError::Keystore(format!("Signer key {} not found !", key_name))
+        })?;
+
+        keys.push((public_key, private_key));
+    }
+
+    if threshold == 0 || threshold > keys.len() {
+        return Err(SpacechainError::InvalidInput(format!(
+            "Invalid threshold {}. Expected a value between 1 and {}.",
+            threshold,
+            keys.len()
+        )));
+    }
 
-    let mut stmt = conn.prepare("SELECT external_descriptor, internal_descriptor, birthdate FROM wallet_data WHERE wallet_name = ?1").unwrap();
+    let external_descriptor = build_multisig_descriptor(threshold, &keys, 0, taproot, network)?;
+    let internal_descriptor = build_multisig_descriptor(threshold, &keys, 1, taproot, network)?;
 
-    let convenant_iter = stmt
+    write_wallet_data(
+        database_file,
+        wallet_name,
+        &external_descriptor,
+        &Some(internal_descriptor),
+        now(),
+    )
+}
+
+fn open_external_signers_table(database_file: &PathBuf) -> Result<Connection> {
+    let conn = Connection::open(database_file).map_err(db_error)?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS external_signers (wallet_name TEXT UNIQUE NOT NULL, command TEXT NOT NULL, fingerprint TEXT NOT NULL, account INTEGER NOT NULL DEFAULT 0);",
+        [],
+    )
+    .map_err(db_error)?;
+
+    Ok(conn)
+}
+
+/// Records that the keys of a wallet are held by an external signer, under
+/// the given account of the device.
+pub fn write_external_signer(
+    database_file: &PathBuf,
+    wallet_name: &str,
+    command: &str,
+    fingerprint: &Fingerprint,
+    account: u32,
+) -> Result<()> {
+    let conn = open_external_signers_table(database_file)?;
+
+    conn.execute(
+        "INSERT INTO external_signers (wallet_name, command, fingerprint, account) VALUES (?1, ?2, ?3, ?4) ON CONFLICT(wallet_name) DO UPDATE SET command = excluded.command, fingerprint = excluded.fingerprint, account = excluded.account",
+        params![wallet_name, command, fingerprint.to_string(), account],
+    )
+    .map_err(db_error)?;
+
+    Ok(())
+}
+
+/// Returns the command, device fingerprint and account of the external signer
+/// of a wallet.
+pub fn load_external_signer(
+    database_file: &PathBuf,
+    wallet_name: &str,
+) -> Result<Option<(String, Fingerprint, u32)>> {
+    let conn = open_external_signers_table(database_file)?;
+
+    let external_signer: Option<(String, String, u32)> = conn
+        .query_row(
+            "SELECT command, fingerprint, account FROM external_signers WHERE wallet_name = ?1",
+            [wallet_name],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()
+        .map_err(db_error)?;
+
+    match external_signer {
+        Some((command, fingerprint, account)) => {
+            let fingerprint = Fingerprint::from_str(&fingerprint).map_err(|err| {
+                SpacechainError::Keystore(format!(
+                    "Invalid external signer fingerprint {}: {}",
+                    fingerprint, err
+                ))
+            })?;
+            Ok(Some((command, fingerprint, account)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn open_mining_utxos_table(database_file: &PathBuf) -> Result<Connection> {
+    let conn = Connection::open(database_file).map_err(db_error)?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS mining_utxos (wallet_name TEXT NOT NULL, outpoint TEXT UNIQUE NOT NULL);",
+        [],
+    )
+    .map_err(db_error)?;
+
+    Ok(conn)
+}
+
+/// Reserves outputs created by `prepare-mining` to fund CPFP transactions.
+pub fn write_mining_utxos(
+    database_file: &PathBuf,
+    wallet_name: &str,
+    outpoints: &[OutPoint],
+) -> Result<()> {
+    let conn = open_mining_utxos_table(database_file)?;
+
+    for outpoint in outpoints {
+        conn.execute(
+            "INSERT INTO mining_utxos (wallet_name, outpoint) VALUES (?1, ?2)",
+            params![wallet_name, outpoint.to_string()],
+        )
+        .map_err(db_error)?;
+    }
+
+    Ok(())
+}
+
+pub fn load_mining_utxos(database_file: &PathBuf, wallet_name: &str) -> Result<Vec<OutPoint>> {
+    let conn = open_mining_utxos_table(database_file)?;
+
+    let mut stmt = conn
+        .prepare("SELECT outpoint FROM mining_utxos WHERE wallet_name = ?1")
+        .map_err(db_error)?;
+
+    let outpoints: Vec<String> = stmt
+        .query_map([wallet_name], |row| row.get(0))
+        .map_err(db_error)?
+        .collect::<rusqlite::Result<_>>()
+        .map_err(db_error)?;
+
+    outpoints
+        .iter()
+        .map(|outpoint| {
+            OutPoint::from_str(outpoint).map_err(|err| {
+                SpacechainError::Keystore(format!("Invalid mining UTXO {}: {}", outpoint, err))
+            })
+        })
+        .collect()
+}
+
+fn open_sync_checkpoints_table(database_file: &PathBuf) -> Result<Connection> {
+    let conn = Connection::open(database_file).map_err(db_error)?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS sync_checkpoints (wallet_name TEXT UNIQUE NOT NULL, height INT NOT NULL, timestamp INT NOT NULL);",
+        [],
+    )
+    .map_err(db_error)?;
+
+    Ok(conn)
+}
+
+pub fn write_sync_checkpoint(
+    database_file: &PathBuf,
+    wallet_name: &str,
+    height: u32,
+    timestamp: u64,
+) -> Result<()> {
+    let conn = open_sync_checkpoints_table(database_file)?;
+
+    conn.execute(
+        "INSERT INTO sync_checkpoints (wallet_name, height, timestamp) VALUES (?1, ?2, ?3) ON CONFLICT(wallet_name) DO UPDATE SET height = excluded.height, timestamp = excluded.timestamp",
+        params![wallet_name, height, timestamp],
+    )
+    .map_err(db_error)?;
+
+    Ok(())
+}
+
+/// Returns the height and time of the block the wallet was last synced to.
+pub fn load_sync_checkpoint(
+    database_file: &PathBuf,
+    wallet_name: &str,
+) -> Result<Option<(u32, u64)>> {
+    let conn = open_sync_checkpoints_table(database_file)?;
+
+    conn.query_row(
+        "SELECT height, timestamp FROM sync_checkpoints WHERE wallet_name = ?1",
+        [wallet_name],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+    .map_err(db_error)
+}
+
+/// Label types defined by BIP329.
+pub const LABEL_TYPES: [&str; 6] = ["tx", "addr", "pubkey", "input", "output", "xpub"];
+
+/// A line of a BIP329 label export. Fields other than `type`, `ref` and
+/// `label` are ignored on import.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bip329Label {
+    #[serde(rename = "type")]
+    pub label_type: String,
+    #[serde(rename = "ref")]
+    pub reference: String,
+    #[serde(default)]
+    pub label: String,
+}
+
+fn open_labels_table(database_file: &PathBuf) -> Result<Connection> {
+    let conn = Connection::open(database_file).map_err(db_error)?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS labels (wallet_name TEXT NOT NULL, type TEXT NOT NULL, ref TEXT NOT NULL, label TEXT NOT NULL, UNIQUE(wallet_name, type, ref));",
+        [],
+    )
+    .map_err(db_error)?;
+
+    Ok(conn)
+}
+
+fn check_label_type(label_type: &str) -> Result<()> {
+    if !LABEL_TYPES.contains(&label_type) {
+        return Err(SpacechainError::InvalidInput(format!(
+            "Invalid label type {}. Expected one of: {}.",
+            label_type,
+            LABEL_TYPES.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+fn insert_label(
+    conn: &Connection,
+    wallet_name: &str,
+    label_type: &str,
+    reference: &str,
+    label: &str,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO labels (wallet_name, type, ref, label) VALUES (?1, ?2, ?3, ?4) ON CONFLICT(wallet_name, type, ref) DO UPDATE SET label = excluded.label",
+        params![wallet_name, label_type, reference, label],
+    )
+    .map_err(db_error)?;
+
+    Ok(())
+}
+
+pub fn write_label(
+    database_file: &PathBuf,
+    wallet_name: &str,
+    label_type: &str,
+    reference: &str,
+    label: &str,
+) -> Result<()> {
+    check_label_type(label_type)?;
+
+    let conn = open_labels_table(database_file)?;
+
+    insert_label(&conn, wallet_name, label_type, reference, label)
+}
+
+pub fn load_label(
+    database_file: &PathBuf,
+    wallet_name: &str,
+    label_type: &str,
+    reference: &str,
+) -> Result<Option<String>> {
+    let conn = open_labels_table(database_file)?;
+
+    conn.query_row(
+        "SELECT label FROM labels WHERE wallet_name = ?1 AND type = ?2 AND ref = ?3",
+        params![wallet_name, label_type, reference],
+        |row| row.get(0),
+    )
+    .optional()
+    .map_err(db_error)
+}
+
+/// Returns the `(type, ref, label)` entries of a wallet.
+pub fn load_labels(
+    database_file: &PathBuf,
+    wallet_name: &str,
+) -> Result<Vec<(String, String, String)>> {
+    let conn = open_labels_table(database_file)?;
+
+    let mut stmt = conn
+        .prepare("SELECT type, ref, label FROM labels WHERE wallet_name = ?1 ORDER BY type, ref")
+        .map_err(db_error)?;
+
+    let labels = stmt
         .query_map([wallet_name], |row| {
-            let external_descriptor: Vec<u8> = row.get(0).unwrap();
-            let internal_descriptor: Option<Vec<u8>> = row.get(1).unwrap();
-            let birthdate: u64 = row.get(2).unwrap();
-            Ok((external_descriptor, internal_descriptor, birthdate))
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
         })
-        .unwrap();
+        .map_err(db_error)?
+        .collect::<rusqlite::Result<_>>()
+        .map_err(db_error)?;
 
-    let mut wallet_data: Vec<(String, Option<String>, u64)> = Vec::new();
+    Ok(labels)
+}
 
-    for row in convenant_iter {
-        let result = row.unwrap();
+/// Writes the labels of a wallet as BIP329 JSON lines.
+pub fn export_labels(database_file: &PathBuf, wallet_name: &str, file_path: &str) -> Result<usize> {
+    let labels = load_labels(database_file, wallet_name)?;
 
-        let external_descriptor = String::from_utf8(result.0).unwrap();
+    let mut file = File::create(file_path).map_err(|err| SpacechainError::io(file_path, err))?;
 
-        let mut internal_descriptor: Option<String> = None;
-        if result.1.is_some() {
-            internal_descriptor = Some(String::from_utf8(result.1.unwrap()).unwrap());
+    for (label_type, reference, label) in labels.iter() {
+        let line = Bip329Label {
+            label_type: label_type.clone(),
+            reference: reference.clone(),
+            label: label.clone(),
+        };
+
+        // Serializing a struct of strings cannot fail.
+        writeln!(file, "{}", serde_json::to_string(&line).unwrap())
+            .map_err(|err| SpacechainError::io(file_path, err))?;
+    }
+
+    Ok(labels.len())
+}
+
+/// Parses BIP329 JSON lines, checking the type of each label.
+pub fn parse_bip329_labels(reader: impl BufRead, file_path: &str) -> Result<Vec<Bip329Label>> {
+    let mut labels: Vec<Bip329Label> = Vec::new();
+
+    for (line_number, line) in reader.lines().enumerate() {
+        let line = line.map_err(|err| SpacechainError::io(file_path, err))?;
+
+        if line.trim().is_empty() {
+            continue;
         }
 
-        let birthdate = result.2;
+        let invalid_line = |msg: String| {
+            SpacechainError::InvalidInput(format!("{}, line {}: {}", file_path, line_number + 1, msg))
+        };
 
-        wallet_data.push((external_descriptor, internal_descriptor, birthdate));
+        let label: Bip329Label =
+            serde_json::from_str(&line).map_err(|err| invalid_line(err.to_string()))?;
+
+        check_label_type(&label.label_type).map_err(|err| invalid_line(err.to_string()))?;
+
+        labels.push(label);
     }
 
-    if wallet_data.len() == 1 {
-        Some(wallet_data.get(0).unwrap().clone())
-    } else {
-        assert_eq!(wallet_data.len(), 0);
-        None
+    Ok(labels)
+}
+
+/// Reads BIP329 JSON lines into the labels of a wallet, replacing existing
+/// labels with the same type and reference. Nothing is imported unless the
+/// whole file is valid.
+pub fn import_labels(database_file: &PathBuf, wallet_name: &str, file_path: &str) -> Result<usize> {
+    let file = File::open(file_path).map_err(|err| SpacechainError::io(file_path, err))?;
+
+    let labels = parse_bip329_labels(BufReader::new(file), file_path)?;
+
+    let mut conn = open_labels_table(database_file)?;
+    let tx = conn.transaction().map_err(db_error)?;
+
+    for label in labels.iter() {
+        insert_label(
+            &tx,
+            wallet_name,
+            &label.label_type,
+            &label.reference,
+            &label.label,
+        )?;
+    }
+
+    tx.commit().map_err(db_error)?;
+
+    Ok(labels.len())
+}
+
+pub fn load_descriptors(
+    database_file: &PathBuf,
+    wallet_name: &String,
+) -> Result<Option<(String, Option<String>, u64)>> {
+    let conn = open_wallet_data_table(database_file)?;
+
+    let wallet_data: Option<(Vec<u8>, Option<Vec<u8>>, u64)> = conn
+        .query_row(
+            "SELECT external_descriptor, internal_descriptor, birthdate FROM wallet_data WHERE wallet_name = ?1",
+            [wallet_name],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()
+        .map_err(db_error)?;
+
+    let (external_descriptor, internal_descriptor, birthdate) = match wallet_data {
+        Some(wallet_data) => wallet_data,
+        None => return Ok(None),
+    };
+
+    let invalid_descriptor = |_| {
+        SpacechainError::Keystore(format!(
+            "The descriptors of wallet {} are not valid UTF-8.",
+            wallet_name
+        ))
+    };
+
+    let external_descriptor = String::from_utf8(external_descriptor).map_err(invalid_descriptor)?;
+
+    let internal_descriptor = internal_descriptor
+        .map(String::from_utf8)
+        .transpose()
+        .map_err(invalid_descriptor)?;
+
+    Ok(Some((external_descriptor, internal_descriptor, birthdate)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_keystore(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "spacechains-test-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn label_export_import_round_trip() {
+        let keystore = test_keystore("labels");
+        let export_path = keystore.with_extension("jsonl");
+        let export_path = export_path.to_str().unwrap();
+
+        let txid = "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd";
+        write_label(&keystore, "w1", "tx", txid, "Mining fee").unwrap();
+        write_label(&keystore, "w1", "addr", "tb1qexample", "With \"quotes\"\n").unwrap();
+        write_label(&keystore, "w2", "tx", txid, "Other wallet").unwrap();
+
+        assert_eq!(export_labels(&keystore, "w1", export_path).unwrap(), 2);
+        assert_eq!(import_labels(&keystore, "w3", export_path).unwrap(), 2);
+
+        assert_eq!(
+            load_labels(&keystore, "w3").unwrap(),
+            load_labels(&keystore, "w1").unwrap()
+        );
+
+        std::fs::remove_file(&keystore).unwrap();
+        std::fs::remove_file(export_path).unwrap();
+    }
+
+    #[test]
+    fn parse_bip329_labels_ignores_extra_fields() {
+        let lines = "{\"type\":\"output\",\"ref\":\"txid:0\",\"label\":\"a\",\"spendable\":false}\n\n{\"type\":\"xpub\",\"ref\":\"xpub1\"}\n";
+
+        let labels = parse_bip329_labels(lines.as_bytes(), "labels.jsonl").unwrap();
+
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels[0].label_type, "output");
+        assert_eq!(labels[0].label, "a");
+        assert_eq!(labels[1].label, "");
+    }
+
+    #[test]
+    fn import_labels_is_all_or_nothing() {
+        let keystore = test_keystore("labels-invalid");
+        let import_path = keystore.with_extension("jsonl");
+        let import_path = import_path.to_str().unwrap();
+
+        std::fs::write(
+            import_path,
+            "{\"type\":\"tx\",\"ref\":\"a\",\"label\":\"valid\"}\n{\"type\":\"block\",\"ref\":\"b\",\"label\":\"invalid type\"}\n",
+        )
+        .unwrap();
+
+        assert!(matches!(
+            import_labels(&keystore, "w1", import_path),
+            Err(SpacechainError::InvalidInput(_))
+        ));
+        assert!(load_labels(&keystore, "w1").unwrap().is_empty());
+
+        let _ = std::fs::remove_file(&keystore);
+        std::fs::remove_file(import_path).unwrap();
+    }
+
+    #[test]
+    fn signer_key_path_uses_the_coin_type_of_the_network() {
+        assert_eq!(signer_key_path(Network::Bitcoin), "48h/0h/0h/2h");
+        assert_eq!(signer_key_path(Network::Testnet), "48h/1h/0h/2h");
+        assert_eq!(signer_key_path(Network::Signet), "48h/1h/0h/2h");
     }
 }
@@ -0,0 +1,21 @@
+//! Spacechains mining: wallets, the covenant chain, CPFP mining and the
+//! spacechain block explorer. The `spacechains` binary is a thin command
+//! line wrapper around the `api` module.
+
+pub mod accounting;
+pub mod api;
+pub mod backup;
+pub mod config_file;
+pub mod covenant;
+pub mod error;
+pub mod explorer;
+pub mod external_signer;
+pub mod keystore;
+pub mod message;
+pub mod notifications;
+pub mod server;
+pub mod utils;
+pub mod wallet_manager;
+pub mod zmq_subscriber;
+
+pub use error::{Result, SpacechainError};
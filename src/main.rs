@@ -1,235 +1,961 @@
-use bdk::{
-    bitcoin::{secp256k1::Secp256k1, Network},
-    database::SqliteDatabase,
-    wallet::{export::FullyNodedExport, wallet_name_from_descriptor, AddressIndex},
-    Wallet,
-};
-use clap::{command, Parser, Subcommand};
-use config_file::ConfigFile;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use bdk::bitcoin::{OutPoint, Txid};
+use clap::{Parser, Subcommand};
 use serde_json::{json, Value};
 
-mod config_file;
-mod covenant;
-mod keystore;
-mod utils;
-mod wallet_manager;
+use spacechains::api::{self, BroadcastOutcome, ConsolidationOutcome, MiningOutcome};
+use spacechains::config_file::{self, Backend, ChainNetwork, ConfigFile};
+use spacechains::notifications::RivalCpfpWatcher;
+use spacechains::zmq_subscriber::{ZmqEvent, ZmqSubscriber};
+use spacechains::{server, utils, Result, SpacechainError};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 struct Cli {
+    /// Data directory, by default $SPACECHAINS_DATADIR or ~/.spacechains.
+    /// Each network has its own subdirectory
+    #[arg(long, global = true)]
+    datadir: Option<PathBuf>,
+    /// Config file, by default spacechains.conf in the data directory
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Subcommand)]
+enum LabelCommands {
+    /// Set the label of a transaction, address, UTXO or key
+    Set {
+        wallet_name: String,
+        /// BIP329 type: tx, addr, pubkey, input, output or xpub
+        label_type: String,
+        /// The labeled item, e.g. a txid, an address or txid:vout
+        reference: String,
+        label: String,
+    },
+    /// Get the label of a transaction, address, UTXO or key
+    Get {
+        wallet_name: String,
+        label_type: String,
+        reference: String,
+    },
+    /// List all labels of a wallet
+    List { wallet_name: String },
+    /// Export the labels of a wallet as BIP329 JSON lines
+    Export { wallet_name: String, file: String },
+    /// Import BIP329 JSON lines into the labels of a wallet
+    Import { wallet_name: String, file: String },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Print the value of a setting, with bitcoind_rpc_password masked
+    Get { key: String },
+    /// Change a setting in the config file, keeping its other lines and
+    /// comments. An empty value unsets an optional setting
+    Set { key: String, value: String },
+    /// Validate the config file and connect to the configured backend
+    Check,
+}
+
 #[derive(Subcommand)]
 enum Commands {
+    /// Write a commented default config file for a network and backend
+    Init {
+        /// signet or testnet
+        #[arg(long, default_value = "testnet")]
+        network: ChainNetwork,
+        /// electrum or bitcoin_rpc
+        #[arg(long, default_value = "electrum")]
+        blockchain: Backend,
+        /// Electrum server, by default the public one of the network
+        #[arg(long)]
+        electrum_url: Option<String>,
+        /// bitcoind RPC address, by default the local one of the network
+        #[arg(long)]
+        bitcoind_url: Option<String>,
+        /// bitcoind cookie file, by default the one of the network in ~/.bitcoin
+        #[arg(long)]
+        bitcoind_auth_file: Option<String>,
+        /// bitcoind RPC user, to authenticate with a password instead of the cookie file
+        #[arg(long, requires = "bitcoind_rpc_password")]
+        bitcoind_rpc_user: Option<String>,
+        /// bitcoind RPC password
+        #[arg(long, requires = "bitcoind_rpc_user")]
+        bitcoind_rpc_password: Option<String>,
+        /// Prefix of the wallet names in bitcoind
+        #[arg(long)]
+        bitcoind_wallet_prefix: Option<String>,
+        /// Do not prompt, use the options and the defaults as they are
+        #[arg(long)]
+        non_interactive: bool,
+        /// Replace an existing config file
+        #[arg(long)]
+        force: bool,
+    },
     /// Create a new wallet, with a new random BIP 84 extended key
     CreateWallet { wallet_name: String },
+    /// Create a key to take part in multisig wallets and show its public key
+    CreateSignerKey { key_name: String },
+    /// Create a multisig wallet from cosigner extended public keys
+    CreateMultisigWallet {
+        wallet_name: String,
+        /// Number of signatures required to spend
+        #[arg(long)]
+        threshold: usize,
+        /// Extended public key of a cosigner, with its key origin if known
+        #[arg(long = "cosigner", required = true)]
+        cosigners: Vec<String>,
+        /// Local signer key, created with create-signer-key, to include in the wallet
+        #[arg(long)]
+        signer_key: Option<String>,
+        /// Use a taproot multi_a script instead of wsh(sortedmulti)
+        #[arg(long)]
+        taproot: bool,
+    },
+    /// Import a watch-only wallet whose keys are held by an external signer
+    ImportExternalSigner {
+        wallet_name: String,
+        /// HWI-compatible signer command
+        command: String,
+        /// Fingerprint of the device to use, if the signer sees several
+        #[arg(long)]
+        fingerprint: Option<String>,
+        /// BIP 84 account of the device
+        #[arg(long, default_value_t = 0)]
+        account: u32,
+        /// Date (YYYY-MM-DD), unix timestamp or block height from which to scan
+        /// for transactions. Defaults to the genesis block.
+        #[arg(long)]
+        birthdate: Option<String>,
+    },
     /// Import a wallet, given external and internal descriptors
     ImportWallet {
         wallet_name: String,
         external_descriptor: String,
         internal_descriptor: Option<String>,
+        /// Date (YYYY-MM-DD), unix timestamp or block height from which to scan
+        /// for transactions. Defaults to the genesis block.
+        #[arg(long)]
+        birthdate: Option<String>,
+    },
+    /// Rescan the blockchain for a wallet's transactions
+    Rescan {
+        wallet_name: String,
+        /// Scan from this block height instead of the wallet birthdate
+        #[arg(long)]
+        from_height: Option<u32>,
     },
     /// Get a wallet balance
     GetBalance { wallet_name: String },
     /// Get a new address
     GetNewAddress { wallet_name: String },
+    /// Get an address, optionally reusing the last unused one
+    GetAddress {
+        wallet_name: String,
+        /// Return the last revealed address that has not received funds, if any
+        #[arg(long)]
+        unused: bool,
+    },
+    /// List the revealed addresses of a wallet
+    ListAddresses { wallet_name: String },
+    /// Check whether an address belongs to a wallet
+    VerifyAddress {
+        wallet_name: String,
+        address: String,
+    },
+    /// Sign a message with the key of a wallet address (BIP322 simple)
+    SignMessage {
+        wallet_name: String,
+        /// Address to sign with, or the id of a CPFP transaction to sign with the address that funded it
+        address: String,
+        message: String,
+    },
+    /// Verify a BIP322 simple message signature
+    VerifyMessage {
+        address: String,
+        message: String,
+        signature: String,
+    },
     /// Back up your wallet
-    Backup { wallet_name: String },
+    Backup {
+        wallet_name: String,
+        /// Write a full backup bundle to this file instead of printing the descriptor
+        #[arg(long)]
+        file: Option<String>,
+        /// Encrypt the backup bundle with a passphrase
+        #[arg(long, requires = "file")]
+        encrypt: bool,
+    },
+    /// Restore a wallet from a backup bundle
+    Restore {
+        file: String,
+        /// Restore under this name instead of the one in the bundle
+        #[arg(long)]
+        wallet_name: Option<String>,
+    },
     /// Show configuration file
     ConfigFile,
+    /// Read, change or check settings of the config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
     /// Mine a new spacechain block
     Mine {
         wallet_name: String,
         data_message: String,
         fee_amount: u64,
+        /// Write the CPFP transaction to this PSBT file for co-signing instead of broadcasting it
+        #[arg(long)]
+        psbt: Option<String>,
+    },
+    /// Add the signatures of a wallet to a PSBT file
+    SignPsbt { wallet_name: String, file: String },
+    /// Finalize and broadcast a PSBT file, along with its covenant transaction for mining PSBTs
+    BroadcastPsbt { wallet_name: String, file: String },
+    /// Send funds from a wallet to an address
+    Send {
+        wallet_name: String,
+        address: String,
+        /// Amount in satoshis
+        #[arg(required_unless_present = "send_all")]
+        amount: Option<u64>,
+        /// Fee rate in sat/vB
+        #[arg(long)]
+        fee_rate: Option<f32>,
+        /// Send the whole balance of the selected coins, except reserved
+        /// mining UTXOs
+        #[arg(long, conflicts_with = "amount")]
+        send_all: bool,
+        /// Spend only these UTXOs (txid:vout), reserved or not, can be
+        /// repeated
+        #[arg(long = "utxo")]
+        utxos: Vec<OutPoint>,
+        /// Never spend these UTXOs (txid:vout), can be repeated
+        #[arg(long)]
+        utxo_exclude: Vec<OutPoint>,
+        /// Signal replace-by-fee
+        #[arg(long)]
+        rbf: bool,
+        /// Print the signed PSBT instead of broadcasting
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// List the transactions of a wallet
+    ListTransactions { wallet_name: String },
+    /// List the unspent outputs of a wallet
+    ListUnspent { wallet_name: String },
+    /// Merge small UTXOs into one when fees are low
+    Consolidate {
+        wallet_name: String,
+        /// Only merge UTXOs below this value, in satoshis
+        #[arg(long)]
+        threshold: u64,
+        /// Only consolidate if the estimated fee rate is below this, in sat/vB
+        #[arg(long)]
+        max_fee_rate: f32,
+        /// Confirmation target, in blocks, for the fee rate estimate
+        #[arg(long, default_value_t = 6)]
+        target_blocks: usize,
+        /// Print the signed PSBT instead of broadcasting
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Split confirmed coins into UTXOs reserved to fund mining
+    PrepareMining {
+        wallet_name: String,
+        /// Number of UTXOs to create
+        #[arg(long)]
+        count: usize,
+        /// Value of each UTXO, in satoshis
+        #[arg(long)]
+        amount: u64,
+        /// Fee rate in sat/vB
+        #[arg(long)]
+        fee_rate: Option<f32>,
+    },
+    /// Manage wallet labels
+    Label {
+        #[command(subcommand)]
+        command: LabelCommands,
+    },
+    /// Export the covenant chain to a JSON lines file
+    ExportCovenant { file: String },
+    /// Import a covenant chain from a JSON lines file into convenant.db
+    ImportCovenant { file: String },
+    /// Record the network of a convenant.db that does not record one
+    SetCovenantNetwork { network: ChainNetwork },
+    /// Move the wallets of versions without per-network directories into
+    /// the directory of the configured network
+    MigrateDataDir { network: ChainNetwork },
+    /// List the confirmed spacechain blocks
+    ListBlocks {
+        #[arg(long)]
+        from: Option<usize>,
+        #[arg(long)]
+        to: Option<usize>,
+    },
+    /// Show a spacechain block, given its height or a transaction id
+    ShowBlock { block: String },
+    /// Summarize past mining attempts: wins, losses and fees
+    MiningReport {
+        /// Only include attempts made by this wallet
+        #[arg(long)]
+        wallet: Option<String>,
+        /// Start of the time window, as a unix timestamp
+        #[arg(long)]
+        since: Option<u64>,
+        /// End of the time window, as a unix timestamp
+        #[arg(long)]
+        until: Option<u64>,
+    },
+    /// Watch the covenant chain and send notifications of confirmed blocks,
+    /// replaced CPFP transactions, reorgs and chain exhaustion
+    Watch {
+        /// Seconds between two checks. With ZMQ, each new block also
+        /// triggers a check
+        #[arg(long, default_value_t = 60)]
+        interval: u64,
+        /// Check once and exit
+        #[arg(long, conflicts_with = "mine")]
+        once: bool,
+        /// Mine the next covenant step with this wallet, retrying at each
+        /// check until it is broadcast
+        #[arg(long, requires_all = ["data_message", "fee_amount"])]
+        mine: Option<String>,
+        /// Data message of the blocks mined with --mine
+        #[arg(long)]
+        data_message: Option<String>,
+        /// Fee amount of the blocks mined with --mine
+        #[arg(long)]
+        fee_amount: Option<u64>,
+    },
+    /// Serve a JSON-RPC API over HTTP, authenticated with a cookie file.
+    /// Like bitcoind, every error, invalid parameters included, is answered
+    /// with HTTP status 500 and a JSON-RPC error object
+    Serve {
+        /// Address and port to listen on
+        #[arg(long, default_value = "127.0.0.1:38380")]
+        bind: String,
+        /// Cookie file to write, by default .cookie in the network directory
+        #[arg(long)]
+        cookie_file: Option<String>,
     },
 }
 
-fn main() {
-    let cli = Cli::parse();
-
-    let (cfg, cfg_path) = config_file::create_or_get_default();
-
-    let network = cfg.get_network().unwrap();
+fn print_json(obj: &Value) {
+    println!("{}", serde_json::to_string_pretty(obj).unwrap());
+}
 
-    match &cli.command {
-        Commands::CreateWallet { wallet_name } => {
-            create_wallet(wallet_name, network);
-        }
-        Commands::ImportWallet {
-            wallet_name,
-            external_descriptor,
-            internal_descriptor,
-        } => {
-            import_wallet(wallet_name, external_descriptor, internal_descriptor);
-        }
-        Commands::GetBalance { wallet_name } => {
-            get_balance(&cfg, wallet_name);
-        }
-        Commands::GetNewAddress { wallet_name } => {
-            get_new_address(&cfg, wallet_name);
-        }
-        Commands::Backup { wallet_name } => {
-            backup2(&cfg, wallet_name);
-        }
-        Commands::ConfigFile => {
-            config_file(&cfg, &cfg_path);
-        }
-        Commands::Mine {
-            wallet_name,
-            data_message,
-            fee_amount,
-        } => {
-            mine(&cfg, wallet_name, data_message, *fee_amount);
-        }
+fn print_mining_outcome(outcome: &MiningOutcome) {
+    match outcome {
+        MiningOutcome::NoCovenantTransaction => println!("No covenant transaction found."),
+        MiningOutcome::CovenantNotFinal { previous_covenant_txid } => println!("The previous covenant transaction {} has not yet been confirmed, causing non-BIP68-final validation error. Please wait for at least one confirmation.", previous_covenant_txid),
+        MiningOutcome::Psbt { .. } => print_json(&api::mining_outcome_to_json(outcome)),
+        MiningOutcome::Broadcast {
+            covenant_txid,
+            cpfp_txid,
+        } => print_json(
+            &json!({"covenant_transaction_id": covenant_txid, "cpfp_transaction_id": cpfp_txid}),
+        ),
     }
 }
 
-fn get_user_wallet(cfg: &ConfigFile, wallet_name: &String) -> Wallet<SqliteDatabase> {
-    let path = utils::get_keystore_db_path();
+/// Waits up to `timeout` for a new block, and checks the transactions
+/// announced meanwhile for rival CPFP transactions.
+fn wait_for_block(
+    cfg: &ConfigFile,
+    subscriber: &ZmqSubscriber,
+    rival_watcher: &mut Option<RivalCpfpWatcher>,
+    timeout: Duration,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout;
 
-    let wallet_data = keystore::load_descriptors(&path, wallet_name);
+    let keystore_db_path = utils::get_keystore_db_path(cfg)?;
 
-    if wallet_data == None {
-        panic!("Wallet {wallet_name} not found !")
+    loop {
+        let tx = match subscriber.recv(deadline.saturating_duration_since(Instant::now()))? {
+            Some(ZmqEvent::Transaction(tx)) => tx,
+            Some(ZmqEvent::Block(_)) | None => return Ok(()),
+        };
+
+        if let Some(rival_watcher) = rival_watcher.as_mut() {
+            match rival_watcher.check(cfg, &keystore_db_path, &tx) {
+                Ok(Some(event)) => println!("{}", event),
+                Ok(None) => {}
+                Err(err) => eprintln!("{}", err),
+            }
+        }
     }
+}
 
-    let wallet_data = wallet_data.unwrap();
-
-    let external_descriptor = wallet_data.0;
-    let internal_descriptor = wallet_data.1;
-    let birthdate = wallet_data.2;
+/// Mines `tip_txid` with `mine`, unless it is `done_tip`, the last tip that
+/// was mined or given up on after an error. A covenant step that is not
+/// BIP68-final yet, or a backend error, leaves the tip pending, so that it
+/// is mined again at the next check. Returns `None` if `mine` was not called.
+fn mine_pending_tip(
+    done_tip: &mut Option<Txid>,
+    tip_txid: Txid,
+    mine: impl FnOnce() -> Result<MiningOutcome>,
+) -> Option<Result<MiningOutcome>> {
+    if *done_tip == Some(tip_txid) {
+        return None;
+    }
 
-    let network = cfg.get_network().unwrap();
+    let result = mine();
 
-    let wallet_name = wallet_name_from_descriptor(
-        &external_descriptor,
-        internal_descriptor.as_ref(),
-        network,
-        &Secp256k1::new(),
-    )
-    .unwrap();
+    if !matches!(
+        result,
+        Ok(MiningOutcome::CovenantNotFinal { .. }) | Err(SpacechainError::Backend(_))
+    ) {
+        *done_tip = Some(tip_txid);
+    }
 
-    let wallet = wallet_manager::load_wallet(&external_descriptor, &internal_descriptor, network);
+    Some(result)
+}
 
-    utils::sync_wallet(&cfg, wallet_name.as_str(), &wallet, Some(birthdate));
+/// Mines the covenant tip unless it is `done_tip`, which is updated.
+/// Only the covenant wallet is synced, so that mining does not wait for
+/// the events to be found.
+fn mine_covenant_tip(
+    cfg: &ConfigFile,
+    done_tip: &mut Option<Txid>,
+    (wallet_name, data_message, fee_amount): (&str, &str, u64),
+) {
+    let covenant_tip = match api::get_covenant_tip(cfg) {
+        Ok(Some(covenant_tip)) => covenant_tip,
+        Ok(None) => return,
+        Err(err) => {
+            eprintln!("{}", err);
+            return;
+        }
+    };
 
-    wallet
+    let result = mine_pending_tip(done_tip, covenant_tip.1.txid(), || {
+        api::mine_covenant_tip(
+            cfg,
+            wallet_name,
+            data_message,
+            fee_amount,
+            false,
+            &covenant_tip,
+        )
+    });
+
+    match result {
+        Some(Ok(outcome)) => println!("{}", api::mining_outcome_to_json(&outcome)),
+        Some(Err(err)) => eprintln!("{}", err),
+        None => {}
+    }
 }
 
-fn config_file(cfg: &ConfigFile, path: &String) {
-    println!("Config file located in {}", path);
-
-    let obj = json!(cfg);
+/// Sends the notifications of new spacechain blocks, at each new block
+/// with ZMQ, or every `interval` otherwise. With `mining`, mines the next
+/// covenant step at each check until it is broadcast, before looking for
+/// the events.
+fn watch(
+    cfg: &ConfigFile,
+    interval: Duration,
+    once: bool,
+    mining: Option<(&str, &str, u64)>,
+) -> Result<()> {
+    let subscriber = ZmqSubscriber::connect(cfg)?;
+
+    let mut rival_watcher = match (&subscriber, &cfg.zmq_rawtx_url) {
+        (Some(_), Some(_)) => Some(RivalCpfpWatcher::new(cfg)?),
+        _ => None,
+    };
+
+    // The last covenant tip mined or given up on.
+    let mut done_tip: Option<Txid> = None;
 
-    println!("{}", serde_json::to_string_pretty(&obj).unwrap());
-}
+    loop {
+        if let Some(mining) = mining {
+            mine_covenant_tip(cfg, &mut done_tip, mining);
+        }
 
-fn backup2(cfg: &ConfigFile, wallet_name: &String) {
-    let wallet = get_user_wallet(cfg, wallet_name);
+        // A failed check, e.g. a backend hiccup, is retried at the next
+        // block or interval.
+        let events = match api::check_events(cfg) {
+            Ok(events) => events,
+            Err(err) if once => return Err(err),
+            Err(err) => {
+                eprintln!("{}", err);
+                json!([])
+            }
+        };
 
-    let export = FullyNodedExport::export_wallet(&wallet, wallet_name, true)
-        .map_err(ToString::to_string)
-        .map_err(bdk::Error::Generic)
-        .unwrap();
+        if once {
+            print_json(&events);
+            return Ok(());
+        }
 
-    let value: Value = serde_json::from_str(export.to_string().as_str()).unwrap();
+        for event in events.as_array().into_iter().flatten() {
+            println!("{}", event);
+        }
 
-    println!("{}", serde_json::to_string_pretty(&value).unwrap());
+        match &subscriber {
+            Some(subscriber) => wait_for_block(cfg, subscriber, &mut rival_watcher, interval)?,
+            None => std::thread::sleep(interval),
+        }
+    }
 }
 
-fn get_new_address(cfg: &ConfigFile, wallet_name: &String) {
-    let wallet = get_user_wallet(cfg, wallet_name);
-
-    let addr = wallet.get_address(AddressIndex::New).unwrap();
+fn init(cli: &Cli) -> Result<()> {
+    let Commands::Init {
+        network,
+        blockchain,
+        electrum_url,
+        bitcoind_url,
+        bitcoind_auth_file,
+        bitcoind_rpc_user,
+        bitcoind_rpc_password,
+        bitcoind_wallet_prefix,
+        non_interactive,
+        force,
+    } = &cli.command
+    else {
+        unreachable!()
+    };
+
+    let mut cfg = config_file::default_config(*network, *blockchain);
+    if let Some(electrum_url) = electrum_url {
+        cfg.electrum_url = electrum_url.clone();
+    }
+    if let Some(bitcoind_url) = bitcoind_url {
+        cfg.bitcoind_url = bitcoind_url.clone();
+    }
+    cfg.bitcoind_auth_file = bitcoind_auth_file.clone();
+    cfg.bitcoind_rpc_user = bitcoind_rpc_user.clone();
+    cfg.bitcoind_rpc_password = bitcoind_rpc_password.clone();
+    cfg.bitcoind_wallet_prefix = bitcoind_wallet_prefix.clone();
 
-    let obj = json!({"address": addr.address.to_string(), "index": addr.index});
+    if !non_interactive {
+        cfg = config_file::prompt_config(cfg)?;
+    }
 
-    println!("{}", serde_json::to_string_pretty(&obj).unwrap());
-}
+    let cfg_path = config_file::init(
+        cli.datadir.as_deref(),
+        cli.config.as_deref(),
+        &cfg,
+        *force,
+    )?;
 
-fn create_wallet(wallet_name: &String, network: Network) {
-    keystore::create_new_wallet_desc(wallet_name, network);
-    println!("Wallet created successfully !");
-}
+    println!("Config file written to {}", cfg_path);
 
-fn import_wallet(
-    wallet_name: &String,
-    external_descriptor: &String,
-    internal_descriptor: &Option<String>,
-) {
-    keystore::import_wallet_desc(wallet_name, external_descriptor, internal_descriptor);
-    println!("Wallet imported successfully !");
+    Ok(())
 }
 
-fn get_balance(cfg: &ConfigFile, wallet_name: &String) {
-    let wallet = get_user_wallet(cfg, wallet_name);
-
-    let balance = wallet.get_balance().unwrap();
+fn main() {
+    let cli = Cli::parse();
 
-    let obj = json!({"immature": balance.immature, "trusted_pending": balance.trusted_pending,
-        "untrusted_pending": balance.untrusted_pending, "confirmed": balance.confirmed});
+    let result = match &cli.command {
+        Commands::Init { .. } => init(&cli),
+        _ => config_file::load(cli.datadir.as_deref(), cli.config.as_deref())
+            .and_then(|(cfg, cfg_path)| run(&cli, &cfg, &cfg_path)),
+    };
 
-    println!("{}", serde_json::to_string_pretty(&obj).unwrap());
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        std::process::exit(err.exit_code());
+    }
 }
 
-fn mine(cfg: &ConfigFile, wallet_name: &String, data_message: &str, fee_amount: u64) {
-    let covenant_wallet = covenant::load_convenant_wallet_from_db(&cfg);
-    utils::sync_wallet(&cfg, "covenant", &covenant_wallet, None);
-
-    let covenant_result = covenant::get_covenant_tx_from_db(&covenant_wallet);
-
-    if covenant_result == None {
-        println!("No covenant transaction found.");
-        return;
+fn run(cli: &Cli, cfg: &ConfigFile, cfg_path: &str) -> Result<()> {
+    if utils::has_legacy_data_dir(cfg) && !matches!(cli.command, Commands::MigrateDataDir { .. }) {
+        eprintln!(
+            "{} holds wallets of a version without per-network directories. Move them with `spacechains migrate-data-dir <network>`.",
+            cfg.datadir.to_string_lossy()
+        );
     }
 
-    let (previous_covenant_txid, covenant_transaction, satisfaction_weight) =
-        covenant_result.unwrap();
+    match &cli.command {
+        Commands::CreateWallet { wallet_name } => {
+            api::create_wallet(cfg, wallet_name)?;
+            println!("Wallet created successfully !");
+        }
+        Commands::CreateSignerKey { key_name } => {
+            print_json(&api::create_signer_key(cfg, key_name)?);
+        }
+        Commands::CreateMultisigWallet {
+            wallet_name,
+            threshold,
+            cosigners,
+            signer_key,
+            taproot,
+        } => {
+            api::create_multisig_wallet(
+                cfg,
+                wallet_name,
+                *threshold,
+                cosigners,
+                signer_key,
+                *taproot,
+            )?;
+            println!("Wallet created successfully !");
+        }
+        Commands::ImportExternalSigner {
+            wallet_name,
+            command,
+            fingerprint,
+            account,
+            birthdate,
+        } => {
+            let birthdate = birthdate
+                .as_ref()
+                .map(|birthdate| utils::parse_birthdate(cfg, birthdate))
+                .transpose()?
+                .unwrap_or(0);
+            print_json(&api::import_external_signer(
+                cfg,
+                wallet_name,
+                command,
+                fingerprint,
+                *account,
+                birthdate,
+            )?);
+        }
+        Commands::ImportWallet {
+            wallet_name,
+            external_descriptor,
+            internal_descriptor,
+            birthdate,
+        } => {
+            let birthdate = birthdate
+                .as_ref()
+                .map(|birthdate| utils::parse_birthdate(cfg, birthdate))
+                .transpose()?
+                .unwrap_or(0);
+            api::import_wallet(
+                cfg,
+                wallet_name,
+                external_descriptor,
+                internal_descriptor,
+                birthdate,
+            )?;
+            println!("Wallet imported successfully !");
+        }
+        Commands::Rescan {
+            wallet_name,
+            from_height,
+        } => {
+            print_json(&api::rescan(cfg, wallet_name, *from_height)?);
+        }
+        Commands::GetBalance { wallet_name } => {
+            print_json(&api::get_balance(cfg, wallet_name)?);
+        }
+        Commands::GetNewAddress { wallet_name } => {
+            print_json(&api::get_new_address(cfg, wallet_name)?);
+        }
+        Commands::GetAddress {
+            wallet_name,
+            unused,
+        } => {
+            print_json(&api::get_address(cfg, wallet_name, *unused)?);
+        }
+        Commands::ListAddresses { wallet_name } => {
+            print_json(&api::list_addresses(cfg, wallet_name)?);
+        }
+        Commands::VerifyAddress {
+            wallet_name,
+            address,
+        } => {
+            print_json(&api::verify_address(cfg, wallet_name, address)?);
+        }
+        Commands::SignMessage {
+            wallet_name,
+            address,
+            message,
+        } => {
+            print_json(&api::sign_message(cfg, wallet_name, address, message)?);
+        }
+        Commands::VerifyMessage {
+            address,
+            message,
+            signature,
+        } => {
+            print_json(&api::verify_message(address, message, signature)?);
+        }
+        Commands::Backup {
+            wallet_name,
+            file,
+            encrypt,
+        } => match file {
+            Some(file) => print_json(&api::backup_to_file(cfg, wallet_name, file, *encrypt)?),
+            None => print_json(&api::export_wallet(cfg, wallet_name)?),
+        },
+        Commands::Restore { file, wallet_name } => {
+            let wallet_name = api::restore(cfg, file, wallet_name)?;
+            println!("Wallet {} restored successfully !", wallet_name);
+        }
+        Commands::Init { .. } => unreachable!(),
+        Commands::ConfigFile => {
+            println!("Config file located in {}", cfg_path);
+            println!("Data directory {}", cfg.datadir.to_string_lossy());
+            print_json(&cfg.to_redacted_json());
+        }
+        Commands::Config { command } => match command {
+            ConfigCommands::Get { key } => match config_file::get_value(cfg, key)? {
+                Value::String(value) => println!("{}", value),
+                value => println!("{}", value),
+            },
+            ConfigCommands::Set { key, value } => {
+                let cfg = config_file::set_value(cfg, key, value)?;
+                config_file::save_value(&cfg, key, Path::new(cfg_path))?;
+                println!("{} set in {}", key, cfg_path);
+            }
+            ConfigCommands::Check => {
+                // The config was validated when loaded.
+                print_json(&utils::check_backend(cfg)?);
+            }
+        },
+        Commands::Mine {
+            wallet_name,
+            data_message,
+            fee_amount,
+            psbt,
+        } => {
+            let outcome = api::mine(cfg, wallet_name, data_message, *fee_amount, psbt.is_some())?;
+
+            match (&outcome, psbt) {
+                (
+                    MiningOutcome::Psbt {
+                        covenant_txid,
+                        psbt,
+                        complete,
+                    },
+                    Some(psbt_file),
+                ) => {
+                    utils::write_psbt(psbt_file, psbt)?;
+                    print_json(
+                        &json!({"covenant_transaction_id": covenant_txid, "cpfp_transaction_id": psbt.unsigned_tx.txid(),
+                            "psbt_file": psbt_file, "complete": complete}),
+                    );
+                }
+                (MiningOutcome::Psbt { .. }, None) => {
+                    return Err(SpacechainError::Signing(format!("The CPFP transaction needs the signatures of other cosigners of wallet {}. Use --psbt to write it to a file and co-sign it with sign-psbt.", wallet_name)));
+                }
+                _ => print_mining_outcome(&outcome),
+            }
+        }
+        Commands::SignPsbt { wallet_name, file } => {
+            let mut psbt = utils::read_psbt(file)?;
 
-    let cpfp_wallet = get_user_wallet(cfg, wallet_name);
+            let complete = api::sign_psbt(cfg, wallet_name, &mut psbt)?;
 
-    let cpfp_transaction = wallet_manager::create_cpfp_transaction(
-        &cpfp_wallet,
-        data_message,
-        &covenant_transaction,
-        satisfaction_weight,
-        fee_amount,
-    );
+            utils::write_psbt(file, &psbt)?;
 
-    // let tx_bytes = serialize(&cpfp_transaction);
-    // let hex_tx = tx_bytes.to_hex();
-    // println!("cpfp_transaction: {}", hex_tx);
+            print_json(
+                &json!({"file": file, "txid": psbt.unsigned_tx.txid(), "complete": complete}),
+            );
+        }
+        Commands::BroadcastPsbt { wallet_name, file } => {
+            let psbt = utils::read_psbt(file)?;
 
-    let covenant_result = utils::broadcast_tx(&cfg, &covenant_transaction);
-    match covenant_result {
-        Ok(_) => {},
-        Err(err) => {
-            if err.to_string() == "Electrum(Protocol(String(\"sendrawtransaction RPC error: {\\\"code\\\":-26,\\\"message\\\":\\\"non-BIP68-final\\\"}\")))" {
-                println!("The previous covenant transaction {} has not yet been confirmed, causing non-BIP68-final validation error. Please wait for at least one confirmation.", previous_covenant_txid);
-                return;
-            } else {
-                panic!("{}", err);
+            match api::broadcast_psbt(cfg, wallet_name, psbt)? {
+                BroadcastOutcome::Mining(outcome) => print_mining_outcome(&outcome),
+                BroadcastOutcome::Transaction { txid, fee } => {
+                    print_json(&json!({"txid": txid, "fee": fee}))
+                }
+            }
+        }
+        Commands::Send {
+            wallet_name,
+            address,
+            amount,
+            fee_rate,
+            send_all: _,
+            utxos,
+            utxo_exclude,
+            rbf,
+            dry_run,
+        } => {
+            print_json(&api::send(
+                cfg,
+                wallet_name,
+                address,
+                *amount,
+                *fee_rate,
+                utxos,
+                utxo_exclude,
+                *rbf,
+                *dry_run,
+            )?);
+        }
+        Commands::ListTransactions { wallet_name } => {
+            print_json(&api::list_transactions(cfg, wallet_name)?);
+        }
+        Commands::ListUnspent { wallet_name } => {
+            print_json(&api::list_unspent(cfg, wallet_name)?);
+        }
+        Commands::Consolidate {
+            wallet_name,
+            threshold,
+            max_fee_rate,
+            target_blocks,
+            dry_run,
+        } => {
+            match api::consolidate(
+                cfg,
+                wallet_name,
+                *threshold,
+                *max_fee_rate,
+                *target_blocks,
+                *dry_run,
+            )? {
+                ConsolidationOutcome::FeeRateTooHigh { fee_rate } => println!(
+                    "The estimated fee rate {} sat/vB is above {} sat/vB. Not consolidating.",
+                    fee_rate, max_fee_rate
+                ),
+                ConsolidationOutcome::NothingToConsolidate => println!(
+                    "Fewer than two confirmed UTXOs below {} sats. Nothing to consolidate.",
+                    threshold
+                ),
+                ConsolidationOutcome::Consolidated(obj) => print_json(&obj),
             }
+        }
+        Commands::PrepareMining {
+            wallet_name,
+            count,
+            amount,
+            fee_rate,
+        } => {
+            print_json(&api::prepare_mining(
+                cfg,
+                wallet_name,
+                *count,
+                *amount,
+                *fee_rate,
+            )?);
+        }
+        Commands::Label { command } => {
+            let obj = match command {
+                LabelCommands::Set {
+                    wallet_name,
+                    label_type,
+                    reference,
+                    label,
+                } => api::set_label(cfg, wallet_name, label_type, reference, label)?,
+                LabelCommands::Get {
+                    wallet_name,
+                    label_type,
+                    reference,
+                } => api::get_label(cfg, wallet_name, label_type, reference)?,
+                LabelCommands::List { wallet_name } => api::list_labels(cfg, wallet_name)?,
+                LabelCommands::Export { wallet_name, file } => {
+                    api::export_labels(cfg, wallet_name, file)?
+                }
+                LabelCommands::Import { wallet_name, file } => {
+                    api::import_labels(cfg, wallet_name, file)?
+                }
+            };
+            print_json(&obj);
+        }
+        Commands::ExportCovenant { file } => {
+            print_json(&api::export_covenant(cfg, file)?);
+        }
+        Commands::ImportCovenant { file } => {
+            print_json(&api::import_covenant(cfg, file)?);
+        }
+        Commands::SetCovenantNetwork { network } => {
+            print_json(&api::set_covenant_network(cfg, *network)?);
+        }
+        Commands::MigrateDataDir { network } => {
+            print_json(&api::migrate_data_dir(cfg, *network)?);
+        }
+        Commands::ListBlocks { from, to } => {
+            print_json(&api::list_blocks(cfg, *from, *to)?);
+        }
+        Commands::ShowBlock { block } => match api::show_block(cfg, block)? {
+            Some(obj) => print_json(&obj),
+            None => println!("Block {} not found.", block),
         },
-    }
-
-    loop {
-        let cpfp_result = utils::broadcast_tx(&cfg, &cpfp_transaction);
-        match cpfp_result {
-            Ok(_) => { break; },
-            Err(err) => {
-                if err.to_string() != "Electrum(Protocol(String(\"sendrawtransaction RPC error: {\\\"code\\\":-25,\\\"message\\\":\\\"bad-txns-inputs-missingorspent\\\"}\")))" {
-                    panic!("{}", err);
+        Commands::MiningReport {
+            wallet,
+            since,
+            until,
+        } => {
+            print_json(&api::mining_report(cfg, wallet, *since, *until)?);
+        }
+        Commands::Watch {
+            interval,
+            once,
+            mine,
+            data_message,
+            fee_amount,
+        } => {
+            let mining = match (mine, data_message, fee_amount) {
+                (Some(wallet_name), Some(data_message), Some(fee_amount)) => {
+                    Some((wallet_name.as_str(), data_message.as_str(), *fee_amount))
                 }
-            },
+                _ => None,
+            };
+            watch(cfg, Duration::from_secs(*interval), *once, mining)?;
+        }
+        Commands::Serve { bind, cookie_file } => {
+            let cookie_path = match cookie_file {
+                Some(cookie_file) => PathBuf::from(cookie_file),
+                None => server::default_cookie_path(cfg)?,
+            };
+            server::serve(cfg, cfg_path, bind, &cookie_path)?;
         }
     }
 
-    let obj = json!({"covenant_transaction_id": covenant_transaction.txid(), "cpfp_transaction_id": cpfp_transaction.txid()});
+    Ok(())
+}
 
-    println!("{}", serde_json::to_string_pretty(&obj).unwrap());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bdk::bitcoin::hashes::Hash;
+
+    #[test]
+    fn mine_pending_tip_retries_until_the_step_is_final() {
+        let tip = Txid::from_inner([1; 32]);
+        let next_tip = Txid::from_inner([2; 32]);
+        let not_final = || {
+            Ok(MiningOutcome::CovenantNotFinal {
+                previous_covenant_txid: tip,
+            })
+        };
+        let broadcast = || {
+            Ok(MiningOutcome::Broadcast {
+                covenant_txid: next_tip,
+                cpfp_txid: next_tip,
+            })
+        };
+
+        let mut done_tip = None;
+
+        // The tip found at startup is mined, and retried while it is not
+        // final or the backend fails.
+        assert!(mine_pending_tip(&mut done_tip, tip, not_final).is_some());
+        assert!(
+            mine_pending_tip(&mut done_tip, tip, || Err(SpacechainError::Backend(
+                "timeout".to_string()
+            )))
+            .is_some()
+        );
+        assert_eq!(done_tip, None);
+
+        assert!(mine_pending_tip(&mut done_tip, tip, broadcast).is_some());
+        assert_eq!(done_tip, Some(tip));
+        assert!(mine_pending_tip(&mut done_tip, tip, || panic!("mined twice")).is_none());
+
+        // A terminal error gives up on the tip until it moves.
+        assert!(mine_pending_tip(&mut done_tip, next_tip, || Err(
+            SpacechainError::InsufficientFunds {
+                needed: 1,
+                available: 0
+            }
+        ))
+        .is_some());
+        assert_eq!(done_tip, Some(next_tip));
+        assert!(mine_pending_tip(&mut done_tip, next_tip, || panic!("mined twice")).is_none());
+    }
 }
@@ -0,0 +1,373 @@
+use bdk::{
+    bitcoin::{
+        blockdata::{opcodes::all::OP_RETURN, script::Builder},
+        consensus::{deserialize, serialize},
+        hashes::{sha256, Hash, HashEngine},
+        psbt::PartiallySignedTransaction,
+        secp256k1::{ecdsa, schnorr, Message, PublicKey, Secp256k1, XOnlyPublicKey},
+        util::sighash::{Prevouts, SighashCache},
+        Address, EcdsaSighashType, OutPoint, PackedLockTime, SchnorrSighashType, Script, Sequence,
+        Transaction, TxIn, TxOut, Txid, WPubkeyHash, Witness,
+    },
+    database::SqliteDatabase,
+    miniscript::psbt::PsbtExt,
+    SignOptions, Wallet,
+};
+
+use crate::error::{Result, SpacechainError};
+use crate::{external_signer, wallet_manager};
+
+/// Tag of the BIP322 message hash.
+const BIP322_TAG: &[u8] = b"BIP0322-signed-message";
+
+/// Tagged hash of `message`, as defined by BIP340.
+fn message_hash(message: &str) -> sha256::Hash {
+    let tag_hash = sha256::Hash::hash(BIP322_TAG);
+
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag_hash[..]);
+    engine.input(&tag_hash[..]);
+    engine.input(message.as_bytes());
+
+    sha256::Hash::from_engine(engine)
+}
+
+/// The virtual transaction committing to the message and paying `script_pubkey`.
+fn to_spend_transaction(script_pubkey: &Script, message: &str) -> Transaction {
+    let script_sig = Builder::new()
+        .push_int(0)
+        .push_slice(&message_hash(message)[..])
+        .into_script();
+
+    Transaction {
+        version: 0,
+        lock_time: PackedLockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: Txid::all_zeros(),
+                vout: 0xFFFFFFFF,
+            },
+            script_sig,
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: 0,
+            script_pubkey: script_pubkey.clone(),
+        }],
+    }
+}
+
+/// The virtual transaction spending `to_spend`, whose witness is the signature.
+fn to_sign_transaction(to_spend: &Transaction, witness: Witness) -> Transaction {
+    Transaction {
+        version: 0,
+        lock_time: PackedLockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: to_spend.txid(),
+                vout: 0,
+            },
+            script_sig: Script::new(),
+            sequence: Sequence::ZERO,
+            witness,
+        }],
+        output: vec![TxOut {
+            value: 0,
+            script_pubkey: Builder::new().push_opcode(OP_RETURN).into_script(),
+        }],
+    }
+}
+
+/// Signs `message` with the key of `address` using the BIP322 simple format.
+/// Returns the base64 encoded witness.
+pub fn sign_message(
+    wallet: &Wallet<SqliteDatabase>,
+    address: &Address,
+    message: &str,
+    gap_limit: usize,
+) -> Result<String> {
+    let (keychain, index) = wallet_manager::find_address(wallet, address, gap_limit)?
+        .ok_or_else(|| {
+            SpacechainError::InvalidInput(format!(
+                "Address {} does not belong to this wallet.",
+                address
+            ))
+        })?;
+
+    let signing_error = |err: &dyn std::fmt::Display| {
+        SpacechainError::Signing(format!("Could not sign the message with {}: {}", address, err))
+    };
+
+    let descriptor = wallet
+        .get_descriptor_for_keychain(keychain)
+        .at_derivation_index(index);
+
+    let to_spend = to_spend_transaction(&address.script_pubkey(), message);
+
+    let mut psbt = PartiallySignedTransaction::from_unsigned_tx(to_sign_transaction(
+        &to_spend,
+        Witness::new(),
+    ))
+    .map_err(|err| signing_error(&err))?;
+
+    psbt.inputs[0].witness_utxo = Some(to_spend.output[0].clone());
+    psbt.inputs[0].non_witness_utxo = Some(to_spend.clone());
+
+    psbt.update_input_with_descriptor(0, &descriptor)
+        .map_err(|err| signing_error(&err))?;
+
+    let sign_options = SignOptions {
+        trust_witness_utxo: true,
+        ..Default::default()
+    };
+
+    let finalized =
+        external_signer::sign(wallet, &mut psbt, sign_options).map_err(|err| match err {
+            SpacechainError::Signing(reason) => signing_error(&reason),
+            err => signing_error(&err),
+        })?;
+
+    let witness = match psbt.inputs[0].final_script_witness.clone() {
+        Some(witness) if finalized => witness,
+        _ => return Err(signing_error(&"missing signature")),
+    };
+
+    Ok(base64::encode(serialize(&witness)))
+}
+
+/// Verifies a BIP322 simple signature of `message` by `address`.
+/// P2WPKH and P2TR key path addresses are supported.
+pub fn verify_message(address: &Address, message: &str, signature: &str) -> Result<bool> {
+    let witness: Witness = match base64::decode(signature)
+        .ok()
+        .and_then(|bytes| deserialize(&bytes).ok())
+    {
+        Some(witness) => witness,
+        None => return Ok(false),
+    };
+
+    let script_pubkey = address.script_pubkey();
+
+    let to_spend = to_spend_transaction(&script_pubkey, message);
+    let to_sign = to_sign_transaction(&to_spend, witness.clone());
+
+    let secp = Secp256k1::verification_only();
+
+    let mut sighash_cache = SighashCache::new(&to_sign);
+
+    let valid = if script_pubkey.is_v0_p2wpkh() {
+        if witness.len() != 2 {
+            return Ok(false);
+        }
+
+        let signature = witness.to_vec()[0].clone();
+        let pubkey = witness.to_vec()[1].clone();
+
+        let (sighash_type, signature) = match signature.split_last() {
+            Some((sighash_type, signature)) => (*sighash_type, signature.to_vec()),
+            None => return Ok(false),
+        };
+
+        if sighash_type != EcdsaSighashType::All as u8 {
+            return Ok(false);
+        }
+
+        let pubkey = match PublicKey::from_slice(&pubkey) {
+            Ok(pubkey) => pubkey,
+            Err(_) => return Ok(false),
+        };
+
+        let pubkey_hash = WPubkeyHash::hash(&pubkey.serialize());
+
+        if Script::new_v0_p2wpkh(&pubkey_hash) != script_pubkey {
+            return Ok(false);
+        }
+
+        let signature = match ecdsa::Signature::from_der(&signature) {
+            Ok(signature) => signature,
+            Err(_) => return Ok(false),
+        };
+
+        let script_code = Script::new_p2pkh(&pubkey_hash.as_hash().into());
+
+        let sighash = match sighash_cache.segwit_signature_hash(
+            0,
+            &script_code,
+            0,
+            EcdsaSighashType::All,
+        ) {
+            Ok(sighash) => sighash,
+            Err(_) => return Ok(false),
+        };
+
+        // A sighash is always 32 bytes long.
+        let msg = Message::from_slice(&sighash[..]).unwrap();
+
+        secp.verify_ecdsa(&msg, &signature, &pubkey).is_ok()
+    } else if script_pubkey.is_v1_p2tr() {
+        if witness.len() != 1 {
+            return Ok(false);
+        }
+
+        let signature = witness.to_vec()[0].clone();
+
+        let (sighash_type, signature) = match signature.len() {
+            64 => (SchnorrSighashType::Default, signature),
+            65 if signature[64] == SchnorrSighashType::All as u8 => {
+                (SchnorrSighashType::All, signature[..64].to_vec())
+            }
+            _ => return Ok(false),
+        };
+
+        let pubkey = match XOnlyPublicKey::from_slice(&script_pubkey[2..]) {
+            Ok(pubkey) => pubkey,
+            Err(_) => return Ok(false),
+        };
+
+        let signature = match schnorr::Signature::from_slice(&signature) {
+            Ok(signature) => signature,
+            Err(_) => return Ok(false),
+        };
+
+        let sighash = match sighash_cache.taproot_key_spend_signature_hash(
+            0,
+            &Prevouts::All(&[to_spend.output[0].clone()]),
+            sighash_type,
+        ) {
+            Ok(sighash) => sighash,
+            Err(_) => return Ok(false),
+        };
+
+        // A sighash is always 32 bytes long.
+        let msg = Message::from_slice(&sighash[..]).unwrap();
+
+        secp.verify_schnorr(&signature, &msg, &pubkey).is_ok()
+    } else {
+        return Err(SpacechainError::InvalidInput(format!(
+            "Address {} is not a P2WPKH or P2TR address. Only the BIP322 simple format is supported.",
+            address
+        )));
+    };
+
+    Ok(valid)
+}
+
+/// Returns the address of the first wallet coin spent by `txid`, e.g. the
+/// coin that funded a CPFP transaction.
+pub fn find_funding_address(wallet: &Wallet<SqliteDatabase>, txid: &Txid) -> Result<Address> {
+    let tx = wallet
+        .get_tx(txid, true)?
+        .and_then(|details| details.transaction)
+        .ok_or_else(|| {
+            SpacechainError::InvalidInput(format!(
+                "Transaction {} not found in this wallet.",
+                txid
+            ))
+        })?;
+
+    for input in tx.input.iter() {
+        let previous_tx = wallet
+            .get_tx(&input.previous_output.txid, true)?
+            .and_then(|details| details.transaction);
+
+        let previous_out = previous_tx
+            .as_ref()
+            .and_then(|previous_tx| previous_tx.output.get(input.previous_output.vout as usize));
+
+        if let Some(previous_out) = previous_out {
+            if wallet.is_mine(&previous_out.script_pubkey)? {
+                if let Ok(address) =
+                    Address::from_script(&previous_out.script_pubkey, wallet.network())
+                {
+                    return Ok(address);
+                }
+            }
+        }
+    }
+
+    Err(SpacechainError::InvalidInput(format!(
+        "Transaction {} does not spend any coin of this wallet.",
+        txid
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bdk::bitcoin::Network;
+
+    use super::*;
+
+    // Test vectors from BIP322.
+    const PRIVATE_KEY: &str = "L3VFeEujGtevx9w18HD1fhRbCH67Az2dpCymeRE1SoPK6XQtaN2k";
+    const P2WPKH_ADDRESS: &str = "bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l";
+    const P2TR_ADDRESS: &str = "bc1ppv609nr0vr25u07u95waq5lucwfm6tde4nydujnu8npg4q75mr5sxq8lt3";
+    const EMPTY_SIGNATURE: &str = "AkcwRAIgM2gBAQqvZX15ZiysmKmQpDrG83avLIT492QBzLnQIxYCIBaTpOaD20qRlEylyxFSeEA2ba9YOixpX8z46TSDtS40ASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+    const HELLO_WORLD_SIGNATURE: &str = "AkcwRAIgZRfIY3p7/DoVTty6YZbWS71bc5Vct9p9Fia83eRmw2QCICK/ENGfwLtptFluMGs2KsqoNSk89pO7F29zJLUx9a/sASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+    const P2TR_HELLO_WORLD_SIGNATURE: &str = "AUHd69PrJQEv+oKTfZ8l+WROBHuy9HKrbFCJu7U1iK2iiEy1vMU5EfMtjc+VSHM7aU0SDbak5IUZRVno2P5mjSafAQ==";
+
+    #[test]
+    fn message_hash_matches_bip322() {
+        assert_eq!(
+            message_hash("").to_string(),
+            "c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1"
+        );
+        assert_eq!(
+            message_hash("Hello World").to_string(),
+            "f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a"
+        );
+    }
+
+    #[test]
+    fn verify_message_accepts_bip322_signatures() {
+        let p2wpkh = Address::from_str(P2WPKH_ADDRESS).unwrap();
+        let p2tr = Address::from_str(P2TR_ADDRESS).unwrap();
+
+        assert!(verify_message(&p2wpkh, "", EMPTY_SIGNATURE).unwrap());
+        assert!(verify_message(&p2wpkh, "Hello World", HELLO_WORLD_SIGNATURE).unwrap());
+        assert!(verify_message(&p2tr, "Hello World", P2TR_HELLO_WORLD_SIGNATURE).unwrap());
+    }
+
+    #[test]
+    fn verify_message_rejects_wrong_messages_and_addresses() {
+        let p2wpkh = Address::from_str(P2WPKH_ADDRESS).unwrap();
+        let p2tr = Address::from_str(P2TR_ADDRESS).unwrap();
+
+        assert!(!verify_message(&p2wpkh, "", HELLO_WORLD_SIGNATURE).unwrap());
+        assert!(!verify_message(&p2wpkh, "Hello World", EMPTY_SIGNATURE).unwrap());
+        assert!(!verify_message(&p2tr, "", P2TR_HELLO_WORLD_SIGNATURE).unwrap());
+        assert!(!verify_message(&p2wpkh, "Hello World", "not base64").unwrap());
+    }
+
+    #[test]
+    fn sign_message_matches_bip322() {
+        let path = std::env::temp_dir().join(format!(
+            "spacechains-test-message-{}.db",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let wallet = Wallet::new(
+            &format!("wpkh({})", PRIVATE_KEY),
+            None,
+            Network::Bitcoin,
+            SqliteDatabase::new(&path),
+        )
+        .unwrap();
+
+        let address = Address::from_str(P2WPKH_ADDRESS).unwrap();
+
+        assert_eq!(
+            sign_message(&wallet, &address, "", 20).unwrap(),
+            EMPTY_SIGNATURE
+        );
+        assert_eq!(
+            sign_message(&wallet, &address, "Hello World", 20).unwrap(),
+            HELLO_WORLD_SIGNATURE
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
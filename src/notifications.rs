@@ -0,0 +1,484 @@
+//! Notification hooks. Events are sent as JSON payloads to the shell
+//! command and the URL of the config file, if set.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use bdk::bitcoin::{hashes::hex::ToHex, OutPoint, Transaction, Txid};
+use rusqlite::{params, Connection};
+use serde_json::{json, Value};
+
+use crate::config_file::ConfigFile;
+use crate::error::{Result, SpacechainError};
+use crate::explorer::{self, ConfirmedBlock};
+use crate::{accounting, covenant, wallet_manager};
+
+pub const EVENT_CPFP_BROADCAST: &str = "cpfp_broadcast";
+pub const EVENT_CPFP_REPLACED: &str = "cpfp_replaced";
+pub const EVENT_BLOCK_CONFIRMED: &str = "block_confirmed";
+pub const EVENT_REORG: &str = "reorg";
+pub const EVENT_COVENANT_CHAIN_EXHAUSTION: &str = "covenant_chain_exhaustion";
+pub const EVENT_RIVAL_CPFP: &str = "rival_cpfp";
+
+/// Key of the `notification_metadata` row recording that `check_events`
+/// ran once and recorded the blocks already confirmed.
+const METADATA_INITIALIZED: &str = "initialized";
+
+/// Timeout of the HTTP POST of a notification.
+const NOTIFY_URL_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn db_error(err: rusqlite::Error) -> SpacechainError {
+    SpacechainError::Keystore(err.to_string())
+}
+
+fn notify_error(msg: String) -> SpacechainError {
+    SpacechainError::Notification(msg)
+}
+
+fn run_notify_command(command: &str, event: &str, payload: &str) -> Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("SPACECHAINS_EVENT", event)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|err| SpacechainError::io(command, err))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(payload.as_bytes())
+            .map_err(|err| SpacechainError::io(command, err))?;
+    }
+
+    let status = child
+        .wait()
+        .map_err(|err| SpacechainError::io(command, err))?;
+
+    if !status.success() {
+        return Err(notify_error(format!("{} exited with {}", command, status)));
+    }
+
+    Ok(())
+}
+
+/// POSTs `payload` to a plain `http://host[:port][/path]` URL.
+fn post_notify_url(url: &str, payload: &str) -> Result<()> {
+    let invalid_url = || notify_error(format!("Invalid notification URL {}.", url));
+
+    let rest = url.strip_prefix("http://").ok_or_else(invalid_url)?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+
+    let mut stream = TcpStream::connect(&address).map_err(|err| SpacechainError::io(url, err))?;
+    stream
+        .set_read_timeout(Some(NOTIFY_URL_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(NOTIFY_URL_TIMEOUT)))
+        .map_err(|err| SpacechainError::io(url, err))?;
+
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        payload.len(),
+        payload
+    );
+    stream
+        .write_all(request.as_bytes())
+        .map_err(|err| SpacechainError::io(url, err))?;
+
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .map_err(|err| SpacechainError::io(url, err))?;
+
+    let status_line = response.lines().next().unwrap_or("");
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(notify_error(format!("{} answered {}", url, status_line))),
+    }
+}
+
+/// Sends an event to the notification hooks and returns its payload.
+/// Failing hooks are reported on stderr, so that they never interrupt
+/// mining.
+pub fn notify(cfg: &ConfigFile, event: &str, mut payload: Value) -> Value {
+    payload["event"] = json!(event);
+    let body = payload.to_string();
+
+    if let Some(command) = &cfg.notify_command {
+        if let Err(err) = run_notify_command(command, event, &body) {
+            eprintln!("Notification {} failed: {}", event, err);
+        }
+    }
+
+    if let Some(url) = &cfg.notify_url {
+        if let Err(err) = post_notify_url(url, &body) {
+            eprintln!("Notification {} failed: {}", event, err);
+        }
+    }
+
+    payload
+}
+
+fn open_notified_blocks_table(database_file: &PathBuf) -> Result<Connection> {
+    let conn = Connection::open(database_file).map_err(db_error)?;
+
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS notified_blocks (height INT PRIMARY KEY, covenant_txid TEXT NOT NULL, cpfp_txid TEXT, confirmation_height INT NOT NULL);
+        CREATE TABLE IF NOT EXISTS notification_metadata (key TEXT UNIQUE NOT NULL, value TEXT NOT NULL);",
+    )
+    .map_err(db_error)?;
+
+    Ok(conn)
+}
+
+fn is_initialized(conn: &Connection) -> Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM notification_metadata WHERE key = ?1)",
+        [METADATA_INITIALIZED],
+        |row| row.get(0),
+    )
+    .map_err(db_error)
+}
+
+/// Returns the blocks seen by the last `check_events`.
+fn load_notified_blocks(conn: &Connection) -> Result<Vec<ConfirmedBlock>> {
+    let mut stmt = conn
+        .prepare("SELECT height, covenant_txid, cpfp_txid, confirmation_height FROM notified_blocks ORDER BY height")
+        .map_err(db_error)?;
+
+    let rows: Vec<(usize, String, Option<String>, u32)> = stmt
+        .query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })
+        .map_err(db_error)?
+        .collect::<rusqlite::Result<_>>()
+        .map_err(db_error)?;
+
+    let parse_txid = |txid: &str| {
+        txid.parse::<Txid>()
+            .map_err(|_| SpacechainError::Keystore(format!("Invalid transaction id {}.", txid)))
+    };
+
+    rows.into_iter()
+        .map(|(height, covenant_txid, cpfp_txid, confirmation_height)| {
+            Ok(ConfirmedBlock {
+                height,
+                covenant_txid: parse_txid(&covenant_txid)?,
+                cpfp_txid: cpfp_txid.as_deref().map(parse_txid).transpose()?,
+                confirmation_height,
+            })
+        })
+        .collect()
+}
+
+/// Returns a `cpfp_replaced` event for each of our attempts at `block`,
+/// unless its CPFP transaction is unknown or ours.
+fn replaced_attempt_events(
+    block: &ConfirmedBlock,
+    attempts: &[(String, Txid)],
+) -> Vec<(&'static str, Value)> {
+    let winning_cpfp_txid = match block.cpfp_txid {
+        Some(txid) => txid,
+        None => return Vec::new(),
+    };
+
+    if attempts
+        .iter()
+        .any(|(_, attempt_txid)| *attempt_txid == winning_cpfp_txid)
+    {
+        return Vec::new();
+    }
+
+    attempts
+        .iter()
+        .map(|(wallet_name, attempt_txid)| {
+            (
+                EVENT_CPFP_REPLACED,
+                json!({
+                    "wallet_name": wallet_name,
+                    "covenant_step": block.height,
+                    "covenant_txid": block.covenant_txid,
+                    "cpfp_txid": attempt_txid,
+                    "winning_cpfp_txid": winning_cpfp_txid,
+                    "confirmation_height": block.confirmation_height,
+                }),
+            )
+        })
+        .collect()
+}
+
+/// Compares the confirmed spacechain blocks with the ones seen by the last
+/// call, and sends the `block_confirmed`, `cpfp_replaced`, `reorg` and
+/// `covenant_chain_exhaustion` events. Returns the events sent.
+///
+/// The CPFP transaction is only looked up for new blocks, and for blocks
+/// recorded before it could be found. Finding it later is not a reorg: the
+/// block is updated and its `cpfp_replaced` events are sent then.
+///
+/// The first call only records the blocks already confirmed, and sends
+/// `covenant_chain_exhaustion` if the chain is already below the threshold.
+pub fn check_events(cfg: &ConfigFile, database_file: &PathBuf) -> Result<Vec<Value>> {
+    let conn = open_notified_blocks_table(database_file)?;
+
+    let notified_blocks = load_notified_blocks(&conn)?;
+    let confirmed_blocks = explorer::get_confirmed_blocks(cfg, |height, confirmation_height| {
+        notified_blocks
+            .iter()
+            .find(|block| {
+                block.height == height && block.confirmation_height == confirmation_height
+            })
+            .and_then(|block| block.cpfp_txid)
+    })?;
+    let first_check = !is_initialized(&conn)?;
+
+    let mut events: Vec<(&str, Value)> = Vec::new();
+
+    for notified_block in notified_blocks.iter() {
+        let confirmed_block = confirmed_blocks
+            .iter()
+            .find(|block| block.height == notified_block.height);
+
+        if confirmed_block == Some(notified_block) {
+            continue;
+        }
+
+        if let Some(block) = confirmed_block.filter(|block| {
+            notified_block.cpfp_txid.is_none()
+                && block.covenant_txid == notified_block.covenant_txid
+                && block.confirmation_height == notified_block.confirmation_height
+        }) {
+            conn.execute(
+                "UPDATE notified_blocks SET cpfp_txid = ?2 WHERE height = ?1",
+                params![block.height, block.cpfp_txid.map(|txid| txid.to_string())],
+            )
+            .map_err(db_error)?;
+
+            let attempts = accounting::load_broadcast_attempts(database_file, block.height)?;
+            events.extend(replaced_attempt_events(block, &attempts));
+            continue;
+        }
+
+        events.push((
+            EVENT_REORG,
+            json!({
+                "height": notified_block.height,
+                "covenant_txid": notified_block.covenant_txid,
+                "cpfp_txid": notified_block.cpfp_txid,
+                "confirmation_height": notified_block.confirmation_height,
+                "new_cpfp_txid": confirmed_block.and_then(|block| block.cpfp_txid),
+                "new_confirmation_height": confirmed_block.map(|block| block.confirmation_height),
+            }),
+        ));
+
+        conn.execute(
+            "DELETE FROM notified_blocks WHERE height = ?1",
+            params![notified_block.height],
+        )
+        .map_err(db_error)?;
+
+        accounting::unresolve_attempts(database_file, notified_block.height)?;
+    }
+
+    for block in confirmed_blocks.iter() {
+        let inserted = conn
+            .execute(
+                "INSERT OR IGNORE INTO notified_blocks (height, covenant_txid, cpfp_txid, confirmation_height) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    block.height,
+                    block.covenant_txid.to_string(),
+                    block.cpfp_txid.map(|txid| txid.to_string()),
+                    block.confirmation_height
+                ],
+            )
+            .map_err(db_error)?;
+
+        if inserted == 0 || first_check {
+            continue;
+        }
+
+        let attempts = accounting::load_broadcast_attempts(database_file, block.height)?;
+        let ours = block.cpfp_txid.map(|cpfp_txid| {
+            attempts
+                .iter()
+                .any(|(_, attempt_txid)| *attempt_txid == cpfp_txid)
+        });
+
+        events.push((
+            EVENT_BLOCK_CONFIRMED,
+            json!({
+                "height": block.height,
+                "covenant_txid": block.covenant_txid,
+                "cpfp_txid": block.cpfp_txid,
+                "confirmation_height": block.confirmation_height,
+                "ours": ours,
+            }),
+        ));
+
+        events.extend(replaced_attempt_events(block, &attempts));
+    }
+
+    // The event fires once, when the remaining steps cross the threshold.
+    let previous_confirmed_steps = if first_check {
+        0
+    } else {
+        notified_blocks.len()
+    };
+
+    if confirmed_blocks.len() > previous_confirmed_steps {
+        let total_steps = covenant::load_covenant_chain(cfg)?.len();
+        let previous_remaining_steps = total_steps.saturating_sub(previous_confirmed_steps);
+        let remaining_steps = total_steps.saturating_sub(confirmed_blocks.len());
+
+        if remaining_steps <= cfg.notify_remaining_steps
+            && previous_remaining_steps > cfg.notify_remaining_steps
+        {
+            events.push((
+                EVENT_COVENANT_CHAIN_EXHAUSTION,
+                json!({
+                    "total_steps": total_steps,
+                    "confirmed_steps": confirmed_blocks.len(),
+                    "remaining_steps": remaining_steps,
+                    "tip_confirmation_height": confirmed_blocks.last().map(|block| block.confirmation_height),
+                }),
+            ));
+        }
+    }
+
+    if first_check {
+        conn.execute(
+            "INSERT OR IGNORE INTO notification_metadata (key, value) VALUES (?1, '1')",
+            [METADATA_INITIALIZED],
+        )
+        .map_err(db_error)?;
+    }
+
+    Ok(events
+        .into_iter()
+        .map(|(event, payload)| notify(cfg, event, payload))
+        .collect())
+}
+
+/// Spots the CPFP transactions of other miners, which spend an anchor
+/// output of the covenant chain.
+pub struct RivalCpfpWatcher {
+    /// Step and transaction id of the covenant transaction of each anchor.
+    anchors: HashMap<OutPoint, (usize, Txid)>,
+    /// Transactions already checked, as bitcoind announces transactions both
+    /// when they enter the mempool and when they confirm.
+    seen: HashSet<Txid>,
+}
+
+impl RivalCpfpWatcher {
+    pub fn new(cfg: &ConfigFile) -> Result<Self> {
+        let bump_script = covenant::load_bump_script(cfg)?;
+
+        let mut anchors = HashMap::new();
+
+        for (step, (_, covenant_tx)) in covenant::load_covenant_chain(cfg)?.iter().enumerate() {
+            let covenant_txid = covenant_tx.txid();
+            for (vout, _, _) in wallet_manager::find_anchor_outputs(covenant_tx, &bump_script) {
+                let outpoint = OutPoint {
+                    txid: covenant_txid,
+                    vout,
+                };
+                anchors.insert(outpoint, (step, covenant_txid));
+            }
+        }
+
+        Ok(RivalCpfpWatcher {
+            anchors,
+            seen: HashSet::new(),
+        })
+    }
+
+    /// Sends the `rival_cpfp` event if `tx` spends an anchor output and is
+    /// not one of our CPFP transactions. Returns the event sent.
+    pub fn check(
+        &mut self,
+        cfg: &ConfigFile,
+        database_file: &PathBuf,
+        tx: &Transaction,
+    ) -> Result<Option<Value>> {
+        let (covenant_step, covenant_txid) = match tx
+            .input
+            .iter()
+            .find_map(|input| self.anchors.get(&input.previous_output))
+        {
+            Some(anchor) => *anchor,
+            None => return Ok(None),
+        };
+
+        let txid = tx.txid();
+
+        if !self.seen.insert(txid) {
+            return Ok(None);
+        }
+
+        let ours = accounting::load_broadcast_attempts(database_file, covenant_step)?
+            .iter()
+            .any(|(_, cpfp_txid)| *cpfp_txid == txid);
+
+        if ours {
+            return Ok(None);
+        }
+
+        let payload = explorer::get_op_return_payload(tx);
+
+        Ok(Some(notify(
+            cfg,
+            EVENT_RIVAL_CPFP,
+            json!({
+                "covenant_step": covenant_step,
+                "covenant_txid": covenant_txid,
+                "cpfp_txid": txid,
+                "payload": payload.as_ref().map(|payload| String::from_utf8_lossy(payload).to_string()),
+                "payload_hex": payload.as_ref().map(|payload| payload.to_hex()),
+            }),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bdk::bitcoin::hashes::Hash;
+
+    fn txid(byte: u8) -> Txid {
+        Txid::from_inner([byte; 32])
+    }
+
+    #[test]
+    fn replaced_attempt_events_need_a_known_competing_cpfp() {
+        let mut block = ConfirmedBlock {
+            height: 3,
+            covenant_txid: txid(1),
+            cpfp_txid: None,
+            confirmation_height: 100,
+        };
+        let attempts = vec![("w1".to_string(), txid(2)), ("w2".to_string(), txid(3))];
+
+        assert!(replaced_attempt_events(&block, &attempts).is_empty());
+
+        block.cpfp_txid = Some(txid(3));
+        assert!(replaced_attempt_events(&block, &attempts).is_empty());
+
+        block.cpfp_txid = Some(txid(4));
+        let events = replaced_attempt_events(&block, &attempts);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].0, EVENT_CPFP_REPLACED);
+        assert_eq!(events[0].1["wallet_name"], "w1");
+        assert_eq!(events[1].1["winning_cpfp_txid"], json!(txid(4)));
+    }
+}
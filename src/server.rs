@@ -0,0 +1,341 @@
+//! JSON-RPC server behind the `serve` command. Requests are JSON-RPC
+//! objects POSTed over HTTP, authenticated like bitcoind with the user and
+//! password written to a cookie file when the server starts.
+
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use bdk::bitcoin::{hashes::hex::ToHex, secp256k1::rand::random};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::api;
+use crate::config_file::ConfigFile;
+use crate::error::{Result, SpacechainError};
+use crate::{backup, covenant, utils};
+
+/// User name of the cookie file, as used by bitcoind.
+const COOKIE_USER: &str = "__cookie__";
+
+/// JSON-RPC error code of an unknown method.
+const METHOD_NOT_FOUND: i32 = -32601;
+
+/// JSON-RPC error code of a request that is not valid JSON-RPC.
+const INVALID_REQUEST: i32 = -32600;
+
+/// Directory of the data directory that `backup` writes its files to.
+const BACKUP_DIR: &str = "backups";
+
+/// Methods served, in the order of `help`.
+const METHODS: [&str; 8] = [
+    "getbalance",
+    "getnewaddress",
+    "mine",
+    "backup",
+    "config",
+    "getcovenanttip",
+    "help",
+    "stop",
+];
+
+/// Writes a new random cookie to `path`, readable by the current user
+/// only, and returns the `user:password` it contains.
+fn write_cookie(path: &Path) -> Result<String> {
+    let password: [u8; 32] = random();
+    let cookie = format!("{}:{}", COOKIE_USER, password.to_hex());
+
+    let path_str = path.to_string_lossy();
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options
+        .open(path)
+        .map_err(|err| SpacechainError::io(&path_str, err))?;
+    file.write_all(cookie.as_bytes())
+        .map_err(|err| SpacechainError::io(&path_str, err))?;
+
+    Ok(cookie)
+}
+
+/// Compares two byte strings in a time that does not depend on where they
+/// differ, so that the cookie cannot be guessed byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn is_authorized(request: &Request, cookie: &str) -> bool {
+    let expected = format!("Basic {}", base64::encode(cookie));
+
+    request.headers().iter().any(|header| {
+        header.field.equiv("Authorization")
+            && constant_time_eq(header.value.as_str().as_bytes(), expected.as_bytes())
+    })
+}
+
+/// Returns the path `backup` writes `file` to. Only plain file names are
+/// accepted, so that requests cannot write outside of the backup directory.
+fn get_backup_path(cfg: &ConfigFile, file: &str) -> Result<String> {
+    if Path::new(file).file_name() != Some(std::ffi::OsStr::new(file)) {
+        return Err(SpacechainError::InvalidInput(format!(
+            "Invalid backup file {}. Expected a file name, written to the {} directory of the data directory.",
+            file, BACKUP_DIR
+        )));
+    }
+
+    let dir = utils::get_data_dir(cfg)?.join(BACKUP_DIR);
+    fs::create_dir_all(&dir).map_err(|err| SpacechainError::io(&dir.to_string_lossy(), err))?;
+
+    Ok(dir.join(file).to_string_lossy().to_string())
+}
+
+/// Reads a parameter given either by name or by position.
+fn get_param<'a>(params: &'a Value, name: &str, position: usize) -> Option<&'a Value> {
+    match params {
+        Value::Object(params) => params.get(name),
+        Value::Array(params) => params.get(position),
+        _ => None,
+    }
+    .filter(|value| !value.is_null())
+}
+
+fn get_str_param(params: &Value, name: &str, position: usize) -> Result<Option<String>> {
+    match get_param(params, name, position) {
+        Some(Value::String(value)) => Ok(Some(value.clone())),
+        Some(_) => Err(SpacechainError::InvalidInput(format!(
+            "Parameter {} must be a string.",
+            name
+        ))),
+        None => Ok(None),
+    }
+}
+
+fn get_required_str_param(params: &Value, name: &str, position: usize) -> Result<String> {
+    get_str_param(params, name, position)?
+        .ok_or_else(|| SpacechainError::InvalidInput(format!("Missing parameter {}.", name)))
+}
+
+fn get_u64_param(params: &Value, name: &str, position: usize) -> Result<u64> {
+    get_param(params, name, position)
+        .ok_or_else(|| SpacechainError::InvalidInput(format!("Missing parameter {}.", name)))?
+        .as_u64()
+        .ok_or_else(|| {
+            SpacechainError::InvalidInput(format!("Parameter {} must be an integer.", name))
+        })
+}
+
+fn get_bool_param(params: &Value, name: &str, position: usize) -> Result<bool> {
+    match get_param(params, name, position) {
+        Some(value) => value.as_bool().ok_or_else(|| {
+            SpacechainError::InvalidInput(format!("Parameter {} must be a boolean.", name))
+        }),
+        None => Ok(false),
+    }
+}
+
+fn get_covenant_tip(cfg: &ConfigFile) -> Result<Value> {
+    let (previous_covenant_txid, covenant_transaction) = match api::get_covenant_tip(cfg)? {
+        Some(covenant_tip) => covenant_tip,
+        None => return Ok(Value::Null),
+    };
+
+    let step = covenant::get_covenant_step(cfg, &covenant_transaction.txid())?;
+
+    Ok(json!({
+        "step": step,
+        "covenant_transaction_id": covenant_transaction.txid(),
+        "previous_covenant_transaction_id": previous_covenant_txid,
+    }))
+}
+
+/// Runs a JSON-RPC method, with the same behavior as the CLI command of
+/// the same name.
+fn dispatch(cfg: &ConfigFile, cfg_path: &str, method: &str, params: &Value) -> Result<Value> {
+    match method {
+        "getbalance" => api::get_balance(cfg, &get_required_str_param(params, "wallet_name", 0)?),
+        "getnewaddress" => {
+            api::get_new_address(cfg, &get_required_str_param(params, "wallet_name", 0)?)
+        }
+        "mine" => {
+            let outcome = api::mine(
+                cfg,
+                &get_required_str_param(params, "wallet_name", 0)?,
+                &get_required_str_param(params, "data_message", 1)?,
+                get_u64_param(params, "fee_amount", 2)?,
+                get_bool_param(params, "psbt", 3)?,
+            )?;
+            Ok(api::mining_outcome_to_json(&outcome))
+        }
+        "backup" => {
+            let wallet_name = get_required_str_param(params, "wallet_name", 0)?;
+            let file = get_backup_path(cfg, &get_required_str_param(params, "file", 1)?)?;
+            let encrypt = get_bool_param(params, "encrypt", 2)?;
+            // The server cannot prompt for the passphrase.
+            if encrypt && std::env::var(backup::BACKUP_PASSPHRASE_ENV).is_err() {
+                return Err(SpacechainError::InvalidInput(format!(
+                    "Encrypted backups need the passphrase in {}.",
+                    backup::BACKUP_PASSPHRASE_ENV
+                )));
+            }
+            api::backup_to_file(cfg, &wallet_name, &file, encrypt)
+        }
+        "config" => Ok(json!({"path": cfg_path, "datadir": cfg.datadir, "config": cfg.to_redacted_json()})),
+        "getcovenanttip" => get_covenant_tip(cfg),
+        "help" => Ok(json!(METHODS)),
+        "stop" => Ok(json!("spacechains server stopping")),
+        _ => Err(SpacechainError::InvalidInput(format!(
+            "Method {} not found.",
+            method
+        ))),
+    }
+}
+
+/// Handles the body of a JSON-RPC request. Returns the response and
+/// whether the server has to stop.
+fn handle_call(cfg: &ConfigFile, cfg_path: &str, body: &str) -> (Value, bool) {
+    let call: Value = match serde_json::from_str(body) {
+        Ok(Value::Object(call)) => Value::Object(call),
+        _ => {
+            return (
+                json!({"result": null, "error": {"code": INVALID_REQUEST, "message": "Invalid JSON-RPC request."}, "id": null}),
+                false,
+            )
+        }
+    };
+
+    let id = call.get("id").cloned().unwrap_or(Value::Null);
+    let method = call.get("method").and_then(Value::as_str).unwrap_or("");
+    let params = call.get("params").cloned().unwrap_or(Value::Null);
+
+    if !METHODS.contains(&method) {
+        let message = format!("Method {} not found.", method);
+        return (
+            json!({"result": null, "error": {"code": METHOD_NOT_FOUND, "message": message}, "id": id}),
+            false,
+        );
+    }
+
+    let response = match dispatch(cfg, cfg_path, method, &params) {
+        Ok(result) => json!({"result": result, "error": null, "id": id}),
+        // Application error codes are negative, like those of bitcoind: the
+        // exit code of the CLI, negated.
+        Err(err) => json!({
+            "result": null,
+            "error": {"code": -err.exit_code(), "message": err.to_string()},
+            "id": id,
+        }),
+    };
+
+    (response, method == "stop")
+}
+
+fn json_response(status: u16, body: &Value) -> Response<std::io::Cursor<Vec<u8>>> {
+    // Both header parts are valid ASCII.
+    let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+
+    Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(content_type)
+}
+
+/// Returns the default cookie file path, in the directory of the
+/// configured network.
+pub fn default_cookie_path(cfg: &ConfigFile) -> Result<PathBuf> {
+    let mut path = utils::get_data_dir(cfg)?;
+    path.push(".cookie");
+    Ok(path)
+}
+
+/// A JSON-RPC server bound to its address, with its cookie written.
+pub struct RpcServer {
+    server: Server,
+    cookie: String,
+    cookie_path: PathBuf,
+}
+
+impl RpcServer {
+    /// Binds `bind` and writes a new cookie to `cookie_path`. Port 0 binds
+    /// any free port, see `local_addr`.
+    pub fn bind(bind: &str, cookie_path: &Path) -> Result<Self> {
+        let server = Server::http(bind)
+            .map_err(|err| SpacechainError::io(bind, std::io::Error::other(err)))?;
+
+        let cookie = write_cookie(cookie_path)?;
+
+        Ok(RpcServer {
+            server,
+            cookie,
+            cookie_path: cookie_path.to_path_buf(),
+        })
+    }
+
+    /// Returns the address the server listens on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Serves requests until a `stop` request, then removes the cookie
+    /// file. Requests are handled one at a time.
+    pub fn run(self, cfg: &ConfigFile, cfg_path: &str) -> Result<()> {
+        for mut request in self.server.incoming_requests() {
+            if !is_authorized(&request, &self.cookie) {
+                let _ = request.respond(Response::empty(401).with_header(
+                    // Both header parts are valid ASCII.
+                    Header::from_bytes("WWW-Authenticate", "Basic realm=\"jsonrpc\"").unwrap(),
+                ));
+                continue;
+            }
+
+            if *request.method() != Method::Post {
+                let _ = request.respond(Response::empty(405));
+                continue;
+            }
+
+            let mut body = String::new();
+            if request.as_reader().read_to_string(&mut body).is_err() {
+                let _ = request.respond(Response::empty(400));
+                continue;
+            }
+
+            let (response, stop) = handle_call(cfg, cfg_path, &body);
+
+            let status = if response["error"].is_null() {
+                200
+            } else {
+                500
+            };
+            let _ = request.respond(json_response(status, &response));
+
+            if stop {
+                break;
+            }
+        }
+
+        let _ = fs::remove_file(&self.cookie_path);
+
+        Ok(())
+    }
+}
+
+/// Serves JSON-RPC requests on `bind` until a `stop` request. Requests are
+/// handled one at a time.
+pub fn serve(cfg: &ConfigFile, cfg_path: &str, bind: &str, cookie_path: &Path) -> Result<()> {
+    let server = RpcServer::bind(bind, cookie_path)?;
+
+    let address = server
+        .local_addr()
+        .map(|address| address.to_string())
+        .unwrap_or_else(|| bind.to_string());
+
+    eprintln!(
+        "Listening on {}, cookie file {}",
+        address,
+        cookie_path.to_string_lossy()
+    );
+
+    server.run(cfg, cfg_path)
+}
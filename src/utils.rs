@@ -1,12 +1,22 @@
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 
-use bdk::blockchain::{Blockchain, ElectrumBlockchain};
-use bdk::electrum_client::Client;
+use bdk::bitcoincore_rpc::{
+    self,
+    jsonrpc::{self, simple_http::SimpleHttpTransport},
+    RpcApi,
+};
+use bdk::blockchain::{electrum::ElectrumBlockchainConfig, Blockchain, ElectrumBlockchain};
+use bdk::electrum_client::{Client, ElectrumApi, Param};
 use bdk::{
     bitcoin::{
-        blockdata::{opcodes, script},
+        blockdata::{constants::genesis_block, opcodes, script},
+        consensus::deserialize,
+        hashes::hex::FromHex,
+        psbt::PartiallySignedTransaction,
         secp256k1::Secp256k1,
-        Network, Script, Transaction,
+        BlockHash, OutPoint, Script, Transaction, Txid,
     },
     blockchain::{
         rpc::{Auth, RpcSyncParams},
@@ -14,17 +24,95 @@ use bdk::{
     },
     database::SqliteDatabase,
     wallet::wallet_name_from_descriptor,
-    Error, SyncOptions, Wallet,
+    FeeRate, SyncOptions, Wallet,
 };
 
-use crate::config_file::ConfigFile;
+use crate::config_file::{Backend, ConfigFile};
+use crate::error::{BroadcastRejection, Result, SpacechainError};
+
+const KEYSTORE_DB_FILE: &str = "wallet.db";
+
+/// Most transactions in a package, as limited by Bitcoin Core's default
+/// `limitancestorcount`.
+const MAX_PACKAGE_COUNT: usize = 25;
+
+/// Bitcoin Core reject reasons of the rejections mining reacts to.
+const REJECT_REASONS: [(&str, BroadcastRejection); 2] = [
+    (
+        "bad-txns-inputs-missingorspent",
+        BroadcastRejection::MissingInputs,
+    ),
+    ("non-BIP68-final", BroadcastRejection::NonFinal),
+];
+
+fn backend_error(err: impl std::fmt::Display) -> SpacechainError {
+    SpacechainError::Backend(err.to_string())
+}
+
+fn get_electrum_client(cfg: &ConfigFile) -> Result<Client> {
+    Client::new(&cfg.electrum_url).map_err(backend_error)
+}
+
+/// Returns the rejection of a reject reason found as a whole word in
+/// `message`.
+fn reject_reason(message: &str) -> Option<BroadcastRejection> {
+    message
+        .split(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+        .find_map(|word| {
+            REJECT_REASONS
+                .iter()
+                .find(|(reason, _)| *reason == word)
+                .map(|(_, rejection)| *rejection)
+        })
+}
+
+/// Returns the rejection of an Electrum server error. Servers relay the
+/// reject reason of their node inside the message of the protocol error,
+/// so it is looked up there as a whole word.
+fn electrum_rejection(err: &bdk::Error) -> Option<BroadcastRejection> {
+    let error = match err {
+        bdk::Error::Electrum(bdk::electrum_client::Error::Protocol(error)) => error,
+        _ => return None,
+    };
+
+    reject_reason(error["message"].as_str().or_else(|| error.as_str())?)
+}
 
-pub fn broadcast_tx(cfg: &ConfigFile, transaction: &Transaction) -> Result<(), Error> {
-    let electrum_url = &cfg.electrum_url;
+/// Returns the rejection of a bitcoind `sendrawtransaction` error, which
+/// carries the reject reason as its message: RPC_TRANSACTION_ERROR (-25)
+/// for missing inputs and RPC_TRANSACTION_REJECTED (-26) for the others.
+fn rpc_rejection(err: &bitcoincore_rpc::Error) -> Option<BroadcastRejection> {
+    match err {
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(error))
+            if error.code == -25 || error.code == -26 =>
+        {
+            reject_reason(&error.message)
+        }
+        _ => None,
+    }
+}
 
-    let blockchain = ElectrumBlockchain::from(Client::new(electrum_url).unwrap());
+/// Broadcasts `transaction` through the configured backend.
+pub fn broadcast_tx(cfg: &ConfigFile, transaction: &Transaction) -> Result<()> {
+    match cfg.blockchain {
+        Backend::Electrum => {
+            let blockchain = ElectrumBlockchain::from(get_electrum_client(cfg)?);
 
-    blockchain.broadcast(transaction)
+            blockchain
+                .broadcast(transaction)
+                .map_err(|err| SpacechainError::Broadcast {
+                    rejection: electrum_rejection(&err),
+                    message: err.to_string(),
+                })
+        }
+        Backend::BitcoinRpc => get_rpc_client(cfg)?
+            .send_raw_transaction(transaction)
+            .map(|_| ())
+            .map_err(|err| SpacechainError::Broadcast {
+                rejection: rpc_rejection(&err),
+                message: err.to_string(),
+            }),
+    }
 }
 
 pub fn build_bump_script() -> Script {
@@ -35,38 +123,85 @@ pub fn build_bump_script() -> Script {
         .into_script()
 }
 
-pub fn sync_wallet_electrum(cfg: &ConfigFile, wallet: &Wallet<SqliteDatabase>) {
-    let electrum_url = &cfg.electrum_url;
+/// Pay-to-anchor output script (`OP_1 <0x4e73>`), spendable by anyone
+/// with an empty witness.
+pub fn build_p2a_script() -> Script {
+    script::Builder::new()
+        .push_opcode(opcodes::all::OP_PUSHNUM_1)
+        .push_slice(&[0x4e, 0x73])
+        .into_script()
+}
+
+pub fn sync_wallet_electrum(cfg: &ConfigFile, wallet: &Wallet<SqliteDatabase>) -> Result<()> {
+    let config = ElectrumBlockchainConfig {
+        url: cfg.electrum_url.to_string(),
+        socks5: None,
+        retry: 1,
+        timeout: None,
+        stop_gap: cfg.gap_limit,
+        validate_domain: true,
+    };
 
-    let blockchain = ElectrumBlockchain::from(Client::new(electrum_url).unwrap());
+    let blockchain = ElectrumBlockchain::from_config(&config).map_err(backend_error)?;
 
-    wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+    wallet
+        .sync(&blockchain, SyncOptions::default())
+        .map_err(backend_error)
 }
 
+/// Returns the bitcoind RPC credentials: the user and password if set, else
+/// the cookie file.
+fn get_rpc_auth(cfg: &ConfigFile) -> Auth {
+    match (&cfg.bitcoind_rpc_user, &cfg.bitcoind_rpc_password) {
+        (Some(username), Some(password)) => Auth::UserPass {
+            username: username.clone(),
+            password: password.clone(),
+        },
+        _ => Auth::Cookie {
+            file: cfg.get_bitcoind_auth_file(),
+        },
+    }
+}
+
+/// Syncs a wallet through bitcoind. The wallet is named `wallet_name` in
+/// bitcoind, after `bitcoind_wallet_prefix`. Scripts are imported with
+/// `start_time` as their birthdate, which is where bitcoind starts
+/// rescanning. Unless `force_start_time` is set, BDK uses the last sync
+/// time instead once the wallet has been synced.
+///
+/// BDK connects with its own client, which cannot be given a timeout, so
+/// `bitcoind_timeout` does not apply to the sync.
 pub fn sync_wallet_rpc(
     cfg: &ConfigFile,
     wallet_name: &str,
     wallet: &Wallet<SqliteDatabase>,
-    birthdate: u64,
-) {
+    start_time: u64,
+    force_start_time: bool,
+) -> Result<()> {
     let sync_params = RpcSyncParams {
-        start_time: birthdate,
+        start_script_count: cfg.gap_limit,
+        start_time,
+        force_start_time,
         ..Default::default()
     };
 
     let config = RpcConfig {
         url: cfg.bitcoind_url.to_string(),
-        auth: Auth::Cookie {
-            file: cfg.bitcoind_auth_file.to_string().into(),
-        },
-        network: cfg.get_network().unwrap(),
-        wallet_name: wallet_name.to_string(),
+        auth: get_rpc_auth(cfg),
+        network: cfg.get_network(),
+        wallet_name: format!(
+            "{}{}",
+            cfg.bitcoind_wallet_prefix.as_deref().unwrap_or_default(),
+            wallet_name
+        ),
         sync_params: Some(sync_params),
     };
 
-    let blockchain = RpcBlockchain::from_config(&config).unwrap();
+    let blockchain = RpcBlockchain::from_config(&config).map_err(backend_error)?;
 
-    wallet.sync(&blockchain, SyncOptions::default()).unwrap();
+    wallet
+        .sync(&blockchain, SyncOptions::default())
+        .map_err(backend_error)
 }
 
 pub fn sync_wallet(
@@ -74,51 +209,607 @@ pub fn sync_wallet(
     wallet_name: &str,
     wallet: &Wallet<SqliteDatabase>,
     birthdate: Option<u64>,
-) {
-    match cfg.blockchain.as_str() {
-        "electrum" => {
-            sync_wallet_electrum(cfg, wallet);
+) -> Result<()> {
+    match cfg.blockchain {
+        Backend::Electrum => sync_wallet_electrum(cfg, wallet),
+        Backend::BitcoinRpc => sync_wallet_rpc(cfg, wallet_name, wallet, birthdate.unwrap_or(0), false),
+    }
+}
+
+/// Syncs a wallet, making bitcoind rescan from `start_time`.
+/// Electrum always returns the full history, so a regular sync is enough.
+pub fn rescan_wallet(
+    cfg: &ConfigFile,
+    wallet_name: &str,
+    wallet: &Wallet<SqliteDatabase>,
+    start_time: u64,
+) -> Result<()> {
+    match cfg.blockchain {
+        Backend::Electrum => sync_wallet_electrum(cfg, wallet),
+        Backend::BitcoinRpc => sync_wallet_rpc(cfg, wallet_name, wallet, start_time, true),
+    }
+}
+
+pub fn get_block_time(cfg: &ConfigFile, height: u32) -> Result<u64> {
+    match cfg.blockchain {
+        Backend::Electrum => {
+            let client = get_electrum_client(cfg)?;
+            let header = client
+                .block_header(height as usize)
+                .map_err(backend_error)?;
+            Ok(header.time as u64)
+        }
+        Backend::BitcoinRpc => {
+            let client = get_rpc_client(cfg)?;
+            let block_hash = client
+                .get_block_hash(height as u64)
+                .map_err(backend_error)?;
+            let header = client
+                .get_block_header(&block_hash)
+                .map_err(backend_error)?;
+            Ok(header.time as u64)
+        }
+    }
+}
+
+/// Values below this are block heights, as with `nLockTime`.
+const BIRTHDATE_HEIGHT_THRESHOLD: u64 = 500_000_000;
+
+/// Parses a wallet birthdate given as a `YYYY-MM-DD` date, a unix timestamp
+/// or a block height, and returns it as a unix timestamp.
+pub fn parse_birthdate(cfg: &ConfigFile, birthdate: &str) -> Result<u64> {
+    if let Ok(value) = birthdate.parse::<u64>() {
+        if value < BIRTHDATE_HEIGHT_THRESHOLD {
+            return get_block_time(cfg, value as u32);
+        }
+        return Ok(value);
+    }
+
+    parse_date(birthdate).ok_or_else(|| {
+        SpacechainError::InvalidInput(format!(
+            "Invalid birthdate {}. Expected YYYY-MM-DD from 1970-01-01 to 9999-12-31, a unix timestamp or a block height.",
+            birthdate
+        ))
+    })
+}
+
+/// Parses a `YYYY-MM-DD` date and returns its unix timestamp at midnight
+/// UTC. Returns `None` for invalid dates and dates outside 1970-01-01 to
+/// 9999-12-31, which also keeps the computation from overflowing.
+fn parse_date(date: &str) -> Option<u64> {
+    let parts: Vec<i64> = date
+        .split('-')
+        .map(|part| part.parse::<i64>().ok())
+        .collect::<Option<_>>()?;
+
+    let (year, month, day) = match parts.as_slice() {
+        [year, month, day] => (*year, *month, *day),
+        _ => return None,
+    };
+
+    if !(1970..=9999).contains(&year)
+        || !(1..=12).contains(&month)
+        || day < 1
+        || day > days_in_month(year, month)
+    {
+        return None;
+    }
+
+    u64::try_from(days_from_civil(year, month, day) * 86400).ok()
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Number of days between 1970-01-01 and the given date of the
+/// proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn get_rpc_client(cfg: &ConfigFile) -> Result<bitcoincore_rpc::Client> {
+    let (user, password) = bitcoincore_rpc::Auth::from(get_rpc_auth(cfg))
+        .get_user_pass()
+        .map_err(backend_error)?;
+
+    let mut transport = SimpleHttpTransport::builder()
+        .url(&cfg.bitcoind_url)
+        .map_err(backend_error)?
+        .timeout(Duration::from_secs(cfg.bitcoind_timeout));
+    if let Some(user) = user {
+        transport = transport.auth(user, password);
+    }
+
+    Ok(bitcoincore_rpc::Client::from_jsonrpc(
+        jsonrpc::Client::with_transport(transport.build()),
+    ))
+}
+
+/// Estimates the fee rate needed to confirm within `target` blocks.
+pub fn estimate_fee(cfg: &ConfigFile, target: usize) -> Result<FeeRate> {
+    match cfg.blockchain {
+        Backend::Electrum => {
+            let blockchain = ElectrumBlockchain::from(get_electrum_client(cfg)?);
+            blockchain.estimate_fee(target).map_err(backend_error)
+        }
+        Backend::BitcoinRpc => {
+            let estimate = get_rpc_client(cfg)?
+                .estimate_smart_fee(target as u16, None)
+                .map_err(backend_error)?;
+            match estimate.fee_rate {
+                Some(fee_rate) => Ok(FeeRate::from_sat_per_vb(
+                    fee_rate.to_sat() as f32 / 1000.0,
+                )),
+                None => Ok(FeeRate::default_min_relay_fee()),
+            }
+        }
+    }
+}
+
+pub fn get_block_hash(cfg: &ConfigFile, height: u32) -> Result<BlockHash> {
+    match cfg.blockchain {
+        Backend::Electrum => {
+            let header = get_electrum_client(cfg)?
+                .block_header(height as usize)
+                .map_err(backend_error)?;
+            Ok(header.block_hash())
+        }
+        Backend::BitcoinRpc => get_rpc_client(cfg)?
+            .get_block_hash(height as u64)
+            .map_err(backend_error),
+    }
+}
+
+/// Connects to the configured backend and checks that it follows the
+/// configured network. Returns the backend, its URL and its tip height.
+pub fn check_backend(cfg: &ConfigFile) -> Result<serde_json::Value> {
+    let (url, tip_height) = match cfg.blockchain {
+        Backend::Electrum => {
+            let client = get_electrum_client(cfg)?;
+            let tip = client.block_headers_subscribe().map_err(backend_error)?;
+            (&cfg.electrum_url, tip.height as u64)
+        }
+        Backend::BitcoinRpc => {
+            let auth_file = cfg.get_bitcoind_auth_file();
+            if cfg.bitcoind_rpc_user.is_none() && !auth_file.exists() {
+                return Err(SpacechainError::Config(format!(
+                    "bitcoind cookie file {} does not exist. Is bitcoind running?",
+                    auth_file.to_string_lossy()
+                )));
+            }
+            let tip_height = get_rpc_client(cfg)?
+                .get_block_count()
+                .map_err(backend_error)?;
+            (&cfg.bitcoind_url, tip_height)
+        }
+    };
+
+    let genesis_hash = get_block_hash(cfg, 0)?;
+    if genesis_hash != genesis_block(cfg.get_network()).block_hash() {
+        return Err(SpacechainError::Config(format!(
+            "{} is not a {} {} backend.",
+            url, cfg.network, cfg.blockchain
+        )));
+    }
+
+    Ok(serde_json::json!({
+        "backend": cfg.blockchain,
+        "url": url,
+        "network": cfg.network,
+        "tip_height": tip_height,
+    }))
+}
+
+/// Looks up the transaction that spends `outpoint`, an output of a
+/// covenant transaction, in the block at `height` and returns it with the
+/// fee it paid.
+pub fn find_spending_tx(
+    cfg: &ConfigFile,
+    outpoint: &OutPoint,
+    height: u32,
+) -> Result<Option<(Transaction, u64)>> {
+    match cfg.blockchain {
+        Backend::Electrum => find_spending_tx_electrum(cfg, outpoint, height),
+        Backend::BitcoinRpc => find_spending_tx_rpc(cfg, outpoint, height),
+    }
+}
+
+/// Finds the transaction spending `outpoint` among the ones that follow
+/// the covenant transaction in the block at `height`. The anchor scripts are
+/// shared by every spacechain and pay-to-anchor user, so their history is
+/// not used. The covenant transaction pays no fee, so it is mined in the
+/// same package as its CPFP child, which miners place right after it.
+fn find_spending_tx_electrum(
+    cfg: &ConfigFile,
+    outpoint: &OutPoint,
+    height: u32,
+) -> Result<Option<(Transaction, u64)>> {
+    let client = get_electrum_client(cfg)?;
+
+    let covenant_pos = client
+        .transaction_get_merkle(&outpoint.txid, height as usize)
+        .map_err(backend_error)?
+        .pos;
+
+    let mut spending_tx = None;
+
+    for pos in covenant_pos + 1..=covenant_pos + MAX_PACKAGE_COUNT {
+        let txid = match client.raw_call(
+            "blockchain.transaction.id_from_pos",
+            [Param::Usize(height as usize), Param::Usize(pos)],
+        ) {
+            Ok(txid) => txid,
+            // Past the last transaction of the block.
+            Err(bdk::electrum_client::Error::Protocol(_)) => break,
+            Err(err) => return Err(backend_error(err)),
+        };
+
+        let txid: Txid = txid
+            .as_str()
+            .and_then(|txid| txid.parse().ok())
+            .ok_or_else(|| {
+                backend_error(format!(
+                    "The Electrum server returned an invalid transaction id {}.",
+                    txid
+                ))
+            })?;
+
+        let tx = client.transaction_get(&txid).map_err(backend_error)?;
+
+        if tx
+            .input
+            .iter()
+            .any(|input| input.previous_output == *outpoint)
+        {
+            spending_tx = Some(tx);
+            break;
         }
-        "bitcoin_rpc" => {
-            sync_wallet_rpc(cfg, wallet_name, wallet, birthdate.unwrap_or(0));
+    }
+
+    let tx = match spending_tx {
+        Some(tx) => tx,
+        None => return Ok(None),
+    };
+
+    let previous_txids: Vec<_> = tx
+        .input
+        .iter()
+        .map(|input| input.previous_output.txid)
+        .collect();
+
+    let previous_txs = client
+        .batch_transaction_get(&previous_txids)
+        .map_err(backend_error)?;
+
+    let invalid_tx = || {
+        backend_error(format!(
+            "The Electrum server returned inconsistent data for transaction {}.",
+            tx.txid()
+        ))
+    };
+
+    if previous_txs.len() != tx.input.len() {
+        return Err(invalid_tx());
+    }
+
+    let mut input_value: u64 = 0;
+    for (input, previous_tx) in tx.input.iter().zip(previous_txs.iter()) {
+        if previous_tx.txid() != input.previous_output.txid {
+            return Err(invalid_tx());
         }
-        _ => panic!("Unexpected blockchain."),
+        let spent_output = previous_tx
+            .output
+            .get(input.previous_output.vout as usize)
+            .ok_or_else(invalid_tx)?;
+        input_value = input_value
+            .checked_add(spent_output.value)
+            .ok_or_else(invalid_tx)?;
     }
+
+    let output_value = tx
+        .output
+        .iter()
+        .try_fold(0u64, |sum, out| sum.checked_add(out.value))
+        .ok_or_else(invalid_tx)?;
+
+    let fee = input_value.checked_sub(output_value).ok_or_else(invalid_tx)?;
+
+    Ok(Some((tx, fee)))
 }
 
-pub fn get_keystore_db_path() -> PathBuf {
-    let mut path = dirs::home_dir().unwrap();
+fn find_spending_tx_rpc(
+    cfg: &ConfigFile,
+    outpoint: &OutPoint,
+    height: u32,
+) -> Result<Option<(Transaction, u64)>> {
+    let client = get_rpc_client(cfg)?;
+
+    let block_hash = client
+        .get_block_hash(height as u64)
+        .map_err(backend_error)?;
+
+    // Verbosity 2 includes the fee of each transaction, so the spent
+    // outputs do not have to be fetched separately.
+    let block: serde_json::Value = client
+        .call("getblock", &[block_hash.to_string().into(), 2.into()])
+        .map_err(backend_error)?;
+
+    let invalid_block = || backend_error(format!("Unexpected getblock response for block {}.", block_hash));
 
-    path.push(".spacechains");
+    for tx in block["tx"].as_array().ok_or_else(invalid_block)? {
+        let spends_outpoint = tx["vin"].as_array().ok_or_else(invalid_block)?.iter().any(|input| {
+            input["txid"].as_str() == Some(outpoint.txid.to_string().as_str())
+                && input["vout"].as_u64() == Some(outpoint.vout as u64)
+        });
 
-    std::fs::create_dir_all(path.clone()).unwrap();
+        if spends_outpoint {
+            let fee_btc = tx["fee"].as_f64().ok_or_else(invalid_block)?;
+            let fee = (fee_btc * 100_000_000.0).round() as u64;
+            let tx: Transaction = tx["hex"]
+                .as_str()
+                .and_then(|hex| Vec::<u8>::from_hex(hex).ok())
+                .and_then(|tx_bytes| deserialize(&tx_bytes).ok())
+                .ok_or_else(invalid_block)?;
+            return Ok(Some((tx, fee)));
+        }
+    }
 
-    path.push("wallet.db");
+    Ok(None)
+}
+
+/// Writes `psbt` to `file_path`, base64 encoded.
+pub fn write_psbt(file_path: &str, psbt: &PartiallySignedTransaction) -> Result<()> {
+    std::fs::write(file_path, psbt.to_string()).map_err(|err| SpacechainError::io(file_path, err))
+}
+
+pub fn read_psbt(file_path: &str) -> Result<PartiallySignedTransaction> {
+    let contents =
+        std::fs::read_to_string(file_path).map_err(|err| SpacechainError::io(file_path, err))?;
+
+    PartiallySignedTransaction::from_str(contents.trim())
+        .map_err(|err| SpacechainError::InvalidInput(format!("{}: {}", file_path, err)))
+}
 
-    path
+/// Returns whether the data directory still holds the wallets of versions
+/// without per-network directories.
+pub fn has_legacy_data_dir(cfg: &ConfigFile) -> bool {
+    cfg.datadir.join(KEYSTORE_DB_FILE).is_file()
+}
+
+/// Moves the wallets of versions without per-network directories, kept
+/// directly in the data directory, to the directory of the configured
+/// network. Returns the number of files and wallet directories moved.
+pub fn migrate_legacy_data_dir(cfg: &ConfigFile) -> Result<usize> {
+    let datadir = &cfg.datadir;
+    let legacy_keystore_db = datadir.join(KEYSTORE_DB_FILE);
+
+    if !has_legacy_data_dir(cfg) {
+        return Err(SpacechainError::InvalidInput(format!(
+            "There are no wallets to move in {}.",
+            datadir.to_string_lossy()
+        )));
+    }
+
+    let network_dir = get_data_dir(cfg)?;
+
+    if network_dir.join(KEYSTORE_DB_FILE).exists() {
+        return Err(SpacechainError::Keystore(format!(
+            "{} already has a keystore. Please move the wallets of {} by hand.",
+            network_dir.to_string_lossy(),
+            datadir.to_string_lossy()
+        )));
+    }
+
+    let entries = std::fs::read_dir(datadir)
+        .map_err(|err| SpacechainError::io(&datadir.to_string_lossy(), err))?;
+
+    let mut moved = 0;
+
+    for entry in entries {
+        let path = entry
+            .map_err(|err| SpacechainError::io(&datadir.to_string_lossy(), err))?
+            .path();
+
+        // BDK wallets are directories holding a `database` file.
+        if path != legacy_keystore_db && !path.join("database").is_file() {
+            continue;
+        }
+
+        if let Some(file_name) = path.file_name() {
+            std::fs::rename(&path, network_dir.join(file_name))
+                .map_err(|err| SpacechainError::io(&path.to_string_lossy(), err))?;
+            moved += 1;
+        }
+    }
+
+    Ok(moved)
+}
+
+/// Returns the directory of the configured network in the data directory,
+/// creating it if needed.
+pub fn get_data_dir(cfg: &ConfigFile) -> Result<PathBuf> {
+    let path = cfg.datadir.join(cfg.get_network().to_string());
+
+    std::fs::create_dir_all(&path)
+        .map_err(|err| SpacechainError::io(&path.to_string_lossy(), err))?;
+
+    Ok(path)
+}
+
+pub fn get_keystore_db_path(cfg: &ConfigFile) -> Result<PathBuf> {
+    let mut path = get_data_dir(cfg)?;
+
+    path.push(KEYSTORE_DB_FILE);
+
+    Ok(path)
 }
 
 pub fn get_bdk_wallet_path(
+    cfg: &ConfigFile,
     external_descriptor: &String,
     internal_descriptor: &Option<String>,
-    network: Network,
-) -> PathBuf {
+) -> Result<PathBuf> {
+    let network = cfg.get_network();
+
     let wallet_name = wallet_name_from_descriptor(
         external_descriptor,
         internal_descriptor.as_ref(),
         network,
         &Secp256k1::new(),
-    )
-    .unwrap();
+    )?;
 
-    let mut path = dirs::home_dir().unwrap();
+    let mut path = get_data_dir(cfg)?;
 
-    path.push(".spacechains");
     path.push(wallet_name);
 
-    std::fs::create_dir_all(path.clone()).unwrap();
+    std::fs::create_dir_all(path.clone())
+        .map_err(|err| SpacechainError::io(&path.to_string_lossy(), err))?;
 
     path.push("database");
 
-    path
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_file::{self, ChainNetwork};
+
+    #[test]
+    fn days_from_civil_counts_from_the_epoch() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1970, 3, 1), 59);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+    }
+
+    #[test]
+    fn parse_date_returns_midnight_utc() {
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        assert_eq!(parse_date("2023-02-01"), Some(1675209600));
+        assert_eq!(parse_date("2024-02-29"), Some(1709164800));
+    }
+
+    #[test]
+    fn parse_date_rejects_invalid_dates() {
+        assert_eq!(parse_date("2024-13-01"), None);
+        assert_eq!(parse_date("2024-00-10"), None);
+        assert_eq!(parse_date("2024-13-45"), None);
+        assert_eq!(parse_date("2024-04-31"), None);
+        assert_eq!(parse_date("2023-02-29"), None);
+        assert_eq!(parse_date("1900-02-29"), None);
+        assert_eq!(parse_date("2024-01-00"), None);
+        assert_eq!(parse_date("2024-01"), None);
+        assert_eq!(parse_date("2024-01-01-01"), None);
+        assert_eq!(parse_date("2024-Jan-01"), None);
+    }
+
+    #[test]
+    fn parse_date_rejects_years_after_9999() {
+        assert_eq!(parse_date("9999-12-31"), Some(253402214400));
+        assert_eq!(parse_date("10000-01-01"), None);
+        assert_eq!(parse_date("100000000000000-01-01"), None);
+        assert_eq!(parse_date("9223372036854775807-12-31"), None);
+    }
+
+    fn protocol_error(error: serde_json::Value) -> bdk::Error {
+        bdk::Error::Electrum(bdk::electrum_client::Error::Protocol(error))
+    }
+
+    #[test]
+    fn electrum_rejection_finds_the_reject_reason() {
+        // ElectrumX and Fulcrum.
+        let err = protocol_error(serde_json::json!({
+            "code": 1,
+            "message": "the transaction was rejected by network rules.\n\nnon-BIP68-final\n[0200]"
+        }));
+        assert_eq!(electrum_rejection(&err), Some(BroadcastRejection::NonFinal));
+
+        // electrs.
+        let err = protocol_error(serde_json::json!({
+            "code": -32600,
+            "message": "sendrawtransaction RPC error: {\"code\":-25,\"message\":\"bad-txns-inputs-missingorspent\"}"
+        }));
+        assert_eq!(
+            electrum_rejection(&err),
+            Some(BroadcastRejection::MissingInputs)
+        );
+    }
+
+    #[test]
+    fn electrum_rejection_ignores_other_errors() {
+        let err =
+            protocol_error(serde_json::json!({"code": 1, "message": "min relay fee not met"}));
+        assert_eq!(electrum_rejection(&err), None);
+
+        let err = protocol_error(serde_json::json!({"message": "non-BIP68-finality"}));
+        assert_eq!(electrum_rejection(&err), None);
+
+        assert_eq!(
+            electrum_rejection(&bdk::Error::Generic("non-BIP68-final".to_string())),
+            None
+        );
+    }
+
+    fn rpc_error(code: i32, message: &str) -> bitcoincore_rpc::Error {
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(jsonrpc::error::RpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }))
+    }
+
+    #[test]
+    fn rpc_rejection_reads_the_rpc_error() {
+        assert_eq!(
+            rpc_rejection(&rpc_error(-25, "bad-txns-inputs-missingorspent")),
+            Some(BroadcastRejection::MissingInputs)
+        );
+        assert_eq!(
+            rpc_rejection(&rpc_error(-26, "non-BIP68-final")),
+            Some(BroadcastRejection::NonFinal)
+        );
+        assert_eq!(rpc_rejection(&rpc_error(-26, "min relay fee not met")), None);
+        assert_eq!(rpc_rejection(&rpc_error(-8, "non-BIP68-final")), None);
+        assert_eq!(
+            rpc_rejection(&bitcoincore_rpc::Error::ReturnedError(
+                "non-BIP68-final".to_string()
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_birthdate_accepts_dates_and_timestamps() {
+        let cfg = config_file::default_config(ChainNetwork::Testnet, Backend::Electrum);
+
+        assert_eq!(parse_birthdate(&cfg, "2023-02-01").unwrap(), 1675209600);
+        assert_eq!(parse_birthdate(&cfg, "1675209600").unwrap(), 1675209600);
+        assert!(matches!(
+            parse_birthdate(&cfg, "2023-02-30"),
+            Err(SpacechainError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            parse_birthdate(&cfg, "yesterday"),
+            Err(SpacechainError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn parse_date_rejects_dates_before_the_epoch() {
+        assert_eq!(parse_date("1969-12-31"), None);
+        assert_eq!(parse_date("0001-01-01"), None);
+    }
 }
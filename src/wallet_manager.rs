@@ -1,82 +1,396 @@
-use bdk::bitcoin::{psbt, Network, OutPoint, Script, Sequence, Transaction, Witness};
-use bdk::database::SqliteDatabase;
+use bdk::bitcoin::util::psbt::PartiallySignedTransaction;
+use bdk::bitcoin::{
+    psbt, Address, OutPoint, Script, Sequence, Transaction, TxOut, VarInt, Witness,
+};
 use bdk::database::{any::SqliteDbConfiguration, ConfigurableDatabase};
-use bdk::{SignOptions, Wallet};
+use bdk::database::{Database, SqliteDatabase};
+use bdk::psbt::PsbtUtils;
+use bdk::wallet::AddressIndex;
+use bdk::{FeeRate, KeychainKind, LocalUtxo, SignOptions, TransactionDetails, Wallet};
 
-use crate::utils;
+use crate::config_file::ConfigFile;
+use crate::error::{Result, SpacechainError};
+use crate::{external_signer, utils};
 
 pub fn load_wallet(
+    cfg: &ConfigFile,
     external_descriptor: &String,
     internal_descriptor: &Option<String>,
-    network: Network,
-) -> Wallet<SqliteDatabase> {
-    let path = utils::get_bdk_wallet_path(external_descriptor, internal_descriptor, network);
+) -> Result<Wallet<SqliteDatabase>> {
+    let network = cfg.get_network();
+
+    let path = utils::get_bdk_wallet_path(cfg, external_descriptor, internal_descriptor)?;
 
     let sqlite_db_configuration = SqliteDbConfiguration {
-        path: path.into_os_string().into_string().unwrap(),
+        path: path.to_string_lossy().to_string(),
     };
 
-    let sqlite_database =
-        bdk::database::SqliteDatabase::from_config(&sqlite_db_configuration).unwrap();
+    let sqlite_database = bdk::database::SqliteDatabase::from_config(&sqlite_db_configuration)?;
 
-    Wallet::new(
+    Ok(Wallet::new(
         external_descriptor,
         internal_descriptor.as_ref(),
         network,
         sqlite_database,
-    )
-    .unwrap()
+    )?)
+}
+
+/// Returns the unspent outputs of the wallet, split into confirmed and
+/// unconfirmed ones.
+pub fn split_utxos_by_confirmation(
+    wallet: &Wallet<SqliteDatabase>,
+) -> Result<(Vec<LocalUtxo>, Vec<LocalUtxo>)> {
+    let mut confirmed_utxos: Vec<LocalUtxo> = Vec::new();
+    let mut unconfirmed_utxos: Vec<LocalUtxo> = Vec::new();
+
+    for utxo in wallet.list_unspent()? {
+        let confirmed = wallet
+            .get_tx(&utxo.outpoint.txid, false)?
+            .and_then(|details| details.confirmation_time)
+            .is_some();
+
+        if confirmed {
+            confirmed_utxos.push(utxo);
+        } else {
+            unconfirmed_utxos.push(utxo);
+        }
+    }
+
+    Ok((confirmed_utxos, unconfirmed_utxos))
+}
+
+/// Returns the address at `index` of a keychain, without revealing it.
+pub fn peek_address(
+    wallet: &Wallet<SqliteDatabase>,
+    keychain: KeychainKind,
+    index: u32,
+) -> Result<Address> {
+    let address_info = match keychain {
+        KeychainKind::External => wallet.get_address(AddressIndex::Peek(index)),
+        KeychainKind::Internal => wallet.get_internal_address(AddressIndex::Peek(index)),
+    }?;
+
+    Ok(address_info.address)
+}
+
+/// A revealed address: its keychain, derivation index, whether it has
+/// received funds, and its current balance.
+pub type AddressEntry = (KeychainKind, u32, Address, bool, u64);
+
+/// Lists the revealed addresses of both keychains with whether they have
+/// received funds and their current balance.
+pub fn list_addresses(wallet: &Wallet<SqliteDatabase>) -> Result<Vec<AddressEntry>> {
+    let used_scripts: Vec<Script> = wallet
+        .list_transactions(true)?
+        .iter()
+        .filter_map(|details| details.transaction.as_ref())
+        .flat_map(|tx| tx.output.iter().map(|out| out.script_pubkey.clone()))
+        .collect();
+
+    let utxos = wallet.list_unspent()?;
+
+    let mut addresses: Vec<(KeychainKind, u32, Address, bool, u64)> = Vec::new();
+
+    for keychain in [KeychainKind::External, KeychainKind::Internal] {
+        let last_index = wallet.database().get_last_index(keychain)?;
+
+        let last_index = match last_index {
+            Some(last_index) => last_index,
+            None => continue,
+        };
+
+        for index in 0..=last_index {
+            let address = peek_address(wallet, keychain, index)?;
+            let script = address.script_pubkey();
+
+            let used = used_scripts.contains(&script);
+            let balance: u64 = utxos
+                .iter()
+                .filter(|utxo| utxo.txout.script_pubkey == script)
+                .map(|utxo| utxo.txout.value)
+                .sum();
+
+            addresses.push((keychain, index, address, used, balance));
+        }
+    }
+
+    Ok(addresses)
+}
+
+/// Finds the keychain and index of `address` among the revealed addresses
+/// and the next `gap_limit` ones of each keychain.
+pub fn find_address(
+    wallet: &Wallet<SqliteDatabase>,
+    address: &Address,
+    gap_limit: usize,
+) -> Result<Option<(KeychainKind, u32)>> {
+    for keychain in [KeychainKind::External, KeychainKind::Internal] {
+        let last_index = wallet.database().get_last_index(keychain)?;
+
+        let end = last_index.map(|last_index| last_index + 1).unwrap_or(0) + gap_limit as u32;
+
+        for index in 0..end {
+            if peek_address(wallet, keychain, index)? == *address {
+                return Ok(Some((keychain, index)));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Builds and signs a transaction paying `amount` to `address`, or the whole
+/// balance of the selected coins if `amount` is `None`.
+/// If `utxos` is not empty, only those coins are spent. The coins reserved
+/// in `mining_utxos` are only spent when given in `utxos`.
+#[allow(clippy::too_many_arguments)]
+pub fn create_send_transaction(
+    wallet: &Wallet<SqliteDatabase>,
+    address: &Address,
+    amount: Option<u64>,
+    fee_rate: Option<f32>,
+    utxos: &[OutPoint],
+    utxo_exclude: &[OutPoint],
+    mining_utxos: &[OutPoint],
+    rbf: bool,
+) -> Result<(PartiallySignedTransaction, TransactionDetails)> {
+    let mut tx_builder = wallet.build_tx();
+
+    match amount {
+        Some(amount) => {
+            tx_builder.add_recipient(address.script_pubkey(), amount);
+        }
+        None => {
+            tx_builder.drain_to(address.script_pubkey());
+            if utxos.is_empty() {
+                tx_builder.drain_wallet();
+            }
+        }
+    }
+
+    if !utxos.is_empty() {
+        tx_builder.add_utxos(utxos)?.manually_selected_only();
+    }
+
+    tx_builder.unspendable(
+        utxo_exclude
+            .iter()
+            .chain(mining_utxos.iter().filter(|outpoint| !utxos.contains(outpoint)))
+            .copied()
+            .collect(),
+    );
+
+    if let Some(fee_rate) = fee_rate {
+        tx_builder.fee_rate(FeeRate::from_sat_per_vb(fee_rate));
+    }
+
+    if rbf {
+        tx_builder.enable_rbf();
+    }
+
+    let (mut psbt, details) = tx_builder.finish()?;
+
+    sign_psbt(wallet, &mut psbt)?;
+
+    Ok((psbt, details))
+}
+
+/// Builds and signs a transaction that merges `utxos` into a single output
+/// to a new internal address.
+pub fn create_consolidation_transaction(
+    wallet: &Wallet<SqliteDatabase>,
+    utxos: &[OutPoint],
+    fee_rate: FeeRate,
+) -> Result<(PartiallySignedTransaction, TransactionDetails)> {
+    let change_address = wallet.get_internal_address(AddressIndex::New)?;
+
+    let mut tx_builder = wallet.build_tx();
+
+    tx_builder
+        .add_utxos(utxos)?
+        .manually_selected_only()
+        .drain_to(change_address.script_pubkey())
+        .fee_rate(fee_rate);
+
+    let (mut psbt, details) = tx_builder.finish()?;
+
+    sign_psbt(wallet, &mut psbt)?;
+
+    Ok((psbt, details))
+}
+
+/// Builds and signs a transaction that splits confirmed coins into `count`
+/// outputs of `amount` sats, each to a new internal address. The coins
+/// already reserved in `mining_utxos` are not spent.
+/// Returns the transaction with the outpoints of the new outputs.
+pub fn create_fanout_transaction(
+    wallet: &Wallet<SqliteDatabase>,
+    count: usize,
+    amount: u64,
+    fee_rate: Option<f32>,
+    mining_utxos: &[OutPoint],
+) -> Result<(Transaction, Vec<OutPoint>)> {
+    let (_, unconfirmed_utxos) = split_utxos_by_confirmation(wallet)?;
+
+    let mut tx_builder = wallet.build_tx();
+
+    let mut fanout_scripts: Vec<Script> = Vec::new();
+
+    for _ in 0..count {
+        let address = wallet.get_internal_address(AddressIndex::New)?;
+        tx_builder.add_recipient(address.script_pubkey(), amount);
+        fanout_scripts.push(address.script_pubkey());
+    }
+
+    tx_builder.unspendable(
+        unconfirmed_utxos
+            .iter()
+            .map(|utxo| utxo.outpoint)
+            .chain(mining_utxos.iter().copied())
+            .collect(),
+    );
+
+    if let Some(fee_rate) = fee_rate {
+        tx_builder.fee_rate(FeeRate::from_sat_per_vb(fee_rate));
+    }
+
+    let (mut psbt, _) = tx_builder.finish()?;
+
+    sign_psbt(wallet, &mut psbt)?;
+
+    let transaction = psbt.extract_tx();
+
+    let outpoints = transaction
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(_, out)| fanout_scripts.contains(&out.script_pubkey))
+        .map(|(vout, _)| OutPoint {
+            txid: transaction.txid(),
+            vout: vout as u32,
+        })
+        .collect();
+
+    Ok((transaction, outpoints))
+}
+
+/// Finds the anchor outputs of a covenant transaction that the CPFP child
+/// can spend: P2WSH outputs of `bump_script` and pay-to-anchor outputs.
+/// Each anchor is returned with the witness that satisfies it.
+pub fn find_anchor_outputs(
+    covenant_transaction: &Transaction,
+    bump_script: &Script,
+) -> Vec<(u32, TxOut, Witness)> {
+    let bump_script_pubkey = bump_script.to_v0_p2wsh();
+    let p2a_script_pubkey = utils::build_p2a_script();
+
+    let mut anchors: Vec<(u32, TxOut, Witness)> = Vec::new();
+
+    for (index, out) in covenant_transaction.output.iter().enumerate() {
+        if out.script_pubkey == bump_script_pubkey {
+            let witness = Witness::from_vec(vec![bump_script.as_bytes().to_vec()]);
+            anchors.push((index as u32, out.clone(), witness));
+        } else if out.script_pubkey == p2a_script_pubkey {
+            anchors.push((index as u32, out.clone(), Witness::new()));
+        }
+    }
+
+    anchors
+}
+
+/// Weight of the empty script_sig of a segwit input, i.e. its length byte.
+const EMPTY_SCRIPT_SIG_WEIGHT: usize = 4;
+
+/// Satisfaction weight of an anchor input, which BDK adds to the weight of
+/// the outpoint and sequence: the empty script_sig and the witness. The
+/// pay-to-anchor witness is empty, a single zero item count. The bump
+/// script witness is the script alone: the item count, its length and the
+/// script.
+fn anchor_satisfaction_weight(anchor: &TxOut, bump_script: &Script) -> usize {
+    if anchor.script_pubkey == utils::build_p2a_script() {
+        EMPTY_SCRIPT_SIG_WEIGHT + 1
+    } else {
+        EMPTY_SCRIPT_SIG_WEIGHT
+            + 1
+            + VarInt(bump_script.len() as u64).len()
+            + bump_script.len()
+    }
+}
+
+/// Picks the anchor that is cheapest to spend. The anchor value is sent to
+/// the `OP_RETURN` output, so only the witness size matters.
+fn select_anchor(anchors: Vec<(u32, TxOut, Witness)>) -> Option<(u32, TxOut, Witness)> {
+    anchors
+        .into_iter()
+        .min_by_key(|(_, _, witness)| witness.serialized_len())
 }
+
+/// Builds the CPFP child of `covenant_transaction` and signs it with the
+/// keys of the wallet. Returns the PSBT and whether it is fully signed, which
+/// is not the case for multisig wallets that need other cosigners.
+///
+/// If one of `mining_utxos` is confirmed and covers the fee, it is the only
+/// wallet coin spent, so that consecutive blocks do not depend on each
+/// other. Otherwise, confirmed coins that are not reserved in
+/// `mining_utxos` are selected.
 pub fn create_cpfp_transaction(
     cpfp_wallet: &Wallet<SqliteDatabase>,
     output: &str,
     covenant_transaction: &Transaction,
-    satisfaction_weight: usize,
+    bump_script: &Script,
     fee_amount: u64,
-) -> Transaction {
-    let balance = cpfp_wallet.get_balance().unwrap();
+    mining_utxos: &[OutPoint],
+) -> Result<(PartiallySignedTransaction, bool)> {
+    let (confirmed_utxos, unconfirmed_utxos) = split_utxos_by_confirmation(cpfp_wallet)?;
 
-    if balance.confirmed == 0 || balance.confirmed < fee_amount {
-        panic!("Insufficient funds !")
-    }
+    let mining_utxo = confirmed_utxos
+        .iter()
+        .find(|utxo| mining_utxos.contains(&utxo.outpoint) && utxo.txout.value >= fee_amount);
 
-    let bump_script = utils::build_bump_script().to_v0_p2wsh();
+    if mining_utxo.is_none() {
+        let available: u64 = confirmed_utxos
+            .iter()
+            .filter(|utxo| !mining_utxos.contains(&utxo.outpoint))
+            .map(|utxo| utxo.txout.value)
+            .sum();
 
-    let mut bump_tx_vout: Option<u32> = None;
-    let mut bump_amount: u64 = 0;
-
-    for (index, out) in covenant_transaction.output.iter().enumerate() {
-        if out.script_pubkey == bump_script {
-            bump_tx_vout = Some(index as u32);
-            bump_amount = out.value;
+        if available == 0 || available < fee_amount {
+            return Err(SpacechainError::InsufficientFunds {
+                needed: fee_amount,
+                available,
+            });
         }
     }
 
-    if bump_tx_vout == None {
-        panic!("Bump script not found in the covenant transaction.");
-    }
+    let (bump_tx_vout, bump_txout, bump_witness) =
+        select_anchor(find_anchor_outputs(covenant_transaction, bump_script)).ok_or_else(
+            || {
+                SpacechainError::CovenantDb(format!(
+                    "No anchor output found in the covenant transaction {}.",
+                    covenant_transaction.txid()
+                ))
+            },
+        )?;
+
+    let bump_amount = bump_txout.value;
 
     let outpoint = OutPoint {
         txid: covenant_transaction.txid(),
-        vout: bump_tx_vout.unwrap(),
+        vout: bump_tx_vout,
     };
 
     let mut tx_builder = cpfp_wallet.build_tx();
 
-    let bump_txout = covenant_transaction.output[bump_tx_vout.unwrap() as usize].clone();
-
-    let x = utils::build_bump_script().as_bytes().to_vec();
+    let satisfaction_weight = anchor_satisfaction_weight(&bump_txout, bump_script);
 
     let psbt_input = psbt::Input {
         non_witness_utxo: Some(covenant_transaction.clone()),
         witness_utxo: Some(bump_txout),
-        final_script_witness: Some(Witness::from_vec(vec![x])),
+        final_script_witness: Some(bump_witness),
         ..Default::default()
     };
 
-    tx_builder
-        .add_foreign_utxo(outpoint, psbt_input, satisfaction_weight)
-        .unwrap();
+    tx_builder.add_foreign_utxo(outpoint, psbt_input, satisfaction_weight)?;
 
     let op_return_script = Script::new_op_return(output.as_bytes());
 
@@ -84,11 +398,28 @@ pub fn create_cpfp_transaction(
 
     tx_builder.fee_absolute(fee_amount);
 
+    match mining_utxo {
+        Some(mining_utxo) => {
+            tx_builder
+                .add_utxo(mining_utxo.outpoint)?
+                .manually_selected_only();
+        }
+        None => {
+            tx_builder.unspendable(
+                unconfirmed_utxos
+                    .iter()
+                    .map(|utxo| utxo.outpoint)
+                    .chain(mining_utxos.iter().copied())
+                    .collect(),
+            );
+        }
+    }
+
     tx_builder.current_height(0);
 
     tx_builder.version(2);
 
-    let (mut psbt, _) = tx_builder.finish().unwrap();
+    let (mut psbt, _) = tx_builder.finish()?;
 
     for inp in psbt.unsigned_tx.input.iter_mut() {
         if inp.previous_output.txid == covenant_transaction.txid() {
@@ -96,7 +427,297 @@ pub fn create_cpfp_transaction(
         }
     }
 
-    cpfp_wallet.sign(&mut psbt, SignOptions::default()).unwrap();
+    let finalized = sign_psbt(cpfp_wallet, &mut psbt)?;
+
+    Ok((psbt, finalized))
+}
+
+/// Adds the signatures of the wallet keys to `psbt` and finalizes the inputs
+/// that have enough of them. Returns whether all inputs are finalized.
+pub fn sign_psbt(
+    wallet: &Wallet<SqliteDatabase>,
+    psbt: &mut PartiallySignedTransaction,
+) -> Result<bool> {
+    external_signer::sign(wallet, psbt, SignOptions::default())
+}
+
+/// Returns the fee paid by `psbt`, from the previous outputs of its inputs.
+pub fn get_psbt_fee(psbt: &PartiallySignedTransaction) -> Result<u64> {
+    let mut input_value: u64 = 0;
+
+    for index in 0..psbt.inputs.len() {
+        let utxo = psbt.get_utxo_for(index).ok_or_else(|| {
+            SpacechainError::InvalidInput(format!(
+                "The PSBT does not include the previous output of input {}.",
+                index
+            ))
+        })?;
+        input_value += utxo.value;
+    }
+
+    let output_value: u64 = psbt.unsigned_tx.output.iter().map(|out| out.value).sum();
+
+    input_value.checked_sub(output_value).ok_or_else(|| {
+        SpacechainError::InvalidInput("The PSBT spends more than its inputs.".to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bdk::bitcoin::hashes::Hash;
+    use bdk::bitcoin::{Network, PackedLockTime, TxIn, Txid};
+    use bdk::database::BatchOperations;
+    use bdk::BlockTime;
+
+    const TEST_TPRV: &str = "tprv8ZgxMBicQKsPeDgjzdC36fs6bMjGApWDNLR9erAXMs5skhMv36j9MV5ecvfavji5khqjWaWSFhN3YcCUUdiKH6isR4Pwy3U5y5egddBr16m";
+
+    /// Returns a wallet holding one confirmed UTXO of each of `values`, with
+    /// their outpoints in the same order.
+    fn test_wallet_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "spacechains-test-{}-{}.db",
+            name,
+            std::process::id()
+        ))
+    }
+
+    fn test_wallet(name: &str, values: &[u64]) -> (Wallet<SqliteDatabase>, Vec<OutPoint>) {
+        let path = test_wallet_path(name);
+        let _ = std::fs::remove_file(&path);
+
+        let mut database = SqliteDatabase::new(&path);
+        let external = format!("wpkh({}/84'/1'/0'/0/*)", TEST_TPRV);
+        let internal = format!("wpkh({}/84'/1'/0'/1/*)", TEST_TPRV);
+
+        let script_pubkey = Wallet::new(
+            &external,
+            None,
+            Network::Testnet,
+            SqliteDatabase::new(":memory:"),
+        )
+        .unwrap()
+        .get_address(AddressIndex::Peek(0))
+        .unwrap()
+        .script_pubkey();
+
+        let mut outpoints = Vec::new();
+        for (index, value) in values.iter().enumerate() {
+            // Not a coinbase, which BDK would not spend before maturity.
+            let transaction = Transaction {
+                version: 2,
+                lock_time: PackedLockTime::ZERO,
+                input: vec![TxIn {
+                    previous_output: OutPoint {
+                        txid: Txid::all_zeros(),
+                        vout: index as u32,
+                    },
+                    ..Default::default()
+                }],
+                output: vec![TxOut {
+                    value: *value,
+                    script_pubkey: script_pubkey.clone(),
+                }],
+            };
+            let outpoint = OutPoint {
+                txid: transaction.txid(),
+                vout: 0,
+            };
+            database
+                .set_utxo(&LocalUtxo {
+                    outpoint,
+                    txout: transaction.output[0].clone(),
+                    keychain: KeychainKind::External,
+                    is_spent: false,
+                })
+                .unwrap();
+            database
+                .set_tx(&TransactionDetails {
+                    txid: transaction.txid(),
+                    transaction: Some(transaction),
+                    received: *value,
+                    sent: 0,
+                    fee: Some(0),
+                    confirmation_time: Some(BlockTime {
+                        height: 100,
+                        timestamp: 0,
+                    }),
+                })
+                .unwrap();
+            outpoints.push(outpoint);
+        }
+
+        let wallet = Wallet::new(&external, Some(&internal), Network::Testnet, database).unwrap();
+        wallet.get_address(AddressIndex::New).unwrap();
+
+        (wallet, outpoints)
+    }
+
+    /// A covenant transaction with a single anchor output paying to
+    /// `script_pubkey`.
+    fn test_anchor_transaction(script_pubkey: Script) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: 240,
+                script_pubkey,
+            }],
+        }
+    }
+
+    /// A covenant transaction with a single pay-to-anchor output.
+    fn test_covenant_transaction() -> Transaction {
+        test_anchor_transaction(utils::build_p2a_script())
+    }
+
+    fn spent_outpoints(transaction: &Transaction) -> Vec<OutPoint> {
+        transaction
+            .input
+            .iter()
+            .map(|input| input.previous_output)
+            .collect()
+    }
+
+    #[test]
+    fn cpfp_spends_one_reserved_utxo_or_only_unreserved_ones() {
+        let (wallet, outpoints) = test_wallet("cpfp-reserved", &[50_000, 1_000, 100_000]);
+        let reserved = &outpoints[..2];
+        let covenant_transaction = test_covenant_transaction();
+        let bump_script = utils::build_bump_script();
+
+        let (psbt, finalized) = create_cpfp_transaction(
+            &wallet,
+            "block",
+            &covenant_transaction,
+            &bump_script,
+            10_000,
+            reserved,
+        )
+        .unwrap();
+        assert!(finalized);
+        let spent = spent_outpoints(&psbt.unsigned_tx);
+        assert_eq!(spent.len(), 2);
+        assert!(spent.contains(&outpoints[0]));
+
+        // No reserved UTXO covers the fee, so only the unreserved one is spent.
+        let (psbt, _) = create_cpfp_transaction(
+            &wallet,
+            "block",
+            &covenant_transaction,
+            &bump_script,
+            60_000,
+            reserved,
+        )
+        .unwrap();
+        let spent = spent_outpoints(&psbt.unsigned_tx);
+        assert_eq!(spent.len(), 2);
+        assert!(spent.contains(&outpoints[2]));
+
+        assert!(matches!(
+            create_cpfp_transaction(
+                &wallet,
+                "block",
+                &covenant_transaction,
+                &bump_script,
+                120_000,
+                reserved,
+            ),
+            Err(SpacechainError::InsufficientFunds {
+                needed: 120_000,
+                available: 100_000
+            })
+        ));
+
+        std::fs::remove_file(test_wallet_path("cpfp-reserved")).unwrap();
+    }
+
+    #[test]
+    fn send_spends_reserved_utxos_only_when_selected() {
+        let (wallet, outpoints) = test_wallet("send-reserved", &[50_000, 100_000]);
+        let address = wallet.get_address(AddressIndex::New).unwrap().address;
+
+        let (psbt, _) = create_send_transaction(
+            &wallet,
+            &address,
+            None,
+            Some(1.0),
+            &[],
+            &[],
+            &outpoints[..1],
+            false,
+        )
+        .unwrap();
+        assert_eq!(spent_outpoints(&psbt.unsigned_tx), vec![outpoints[1]]);
+
+        let (psbt, _) = create_send_transaction(
+            &wallet,
+            &address,
+            None,
+            Some(1.0),
+            &outpoints[..1],
+            &[],
+            &outpoints[..1],
+            false,
+        )
+        .unwrap();
+        assert_eq!(spent_outpoints(&psbt.unsigned_tx), vec![outpoints[0]]);
+
+        std::fs::remove_file(test_wallet_path("send-reserved")).unwrap();
+    }
+
+    #[test]
+    fn fanout_does_not_spend_reserved_utxos() {
+        let (wallet, outpoints) = test_wallet("fanout-reserved", &[50_000, 100_000]);
+
+        let (transaction, fanout_outpoints) =
+            create_fanout_transaction(&wallet, 2, 20_000, Some(1.0), &outpoints[..1]).unwrap();
 
-    psbt.extract_tx()
+        assert_eq!(spent_outpoints(&transaction), vec![outpoints[1]]);
+        assert_eq!(fanout_outpoints.len(), 2);
+
+        std::fs::remove_file(test_wallet_path("fanout-reserved")).unwrap();
+    }
+
+    #[test]
+    fn anchor_satisfaction_weight_matches_the_signed_cpfp() {
+        let (wallet, _) = test_wallet("cpfp-weight", &[50_000]);
+        let bump_script = utils::build_bump_script();
+
+        // 139 bytes without witnesses, the marker and flag, the anchor
+        // witness and the 108 byte P2WPKH witness.
+        for (script_pubkey, expected_vsize) in [
+            (utils::build_p2a_script(), 167),
+            (bump_script.to_v0_p2wsh(), 168),
+        ] {
+            let covenant_transaction = test_anchor_transaction(script_pubkey);
+            let (psbt, finalized) = create_cpfp_transaction(
+                &wallet,
+                "block",
+                &covenant_transaction,
+                &bump_script,
+                1_000,
+                &[],
+            )
+            .unwrap();
+            assert!(finalized);
+
+            let cpfp_transaction = psbt.extract_tx();
+            assert_eq!(cpfp_transaction.vsize(), expected_vsize);
+
+            // The weight the anchor input adds is the one given to BDK.
+            let mut without_anchor = cpfp_transaction.clone();
+            without_anchor
+                .input
+                .retain(|input| input.previous_output.txid != covenant_transaction.txid());
+            assert_eq!(
+                cpfp_transaction.weight() - without_anchor.weight(),
+                (32 + 4 + 4) * 4
+                    + anchor_satisfaction_weight(&covenant_transaction.output[0], &bump_script)
+            );
+        }
+
+        std::fs::remove_file(test_wallet_path("cpfp-weight")).unwrap();
+    }
 }
@@ -0,0 +1,114 @@
+//! Subscription to the `hashblock` and `rawtx` ZMQ notifications of
+//! bitcoind, used by `watch` with the `bitcoin_rpc` backend.
+
+use std::time::{Duration, Instant};
+
+use bdk::bitcoin::{consensus::deserialize, hashes::Hash, BlockHash, Transaction};
+
+use crate::config_file::{Backend, ConfigFile};
+use crate::error::{Result, SpacechainError};
+
+const TOPIC_HASHBLOCK: &[u8] = b"hashblock";
+const TOPIC_RAWTX: &[u8] = b"rawtx";
+
+/// A ZMQ notification of bitcoind.
+#[derive(Debug)]
+pub enum ZmqEvent {
+    /// A new block was connected to the tip.
+    Block(BlockHash),
+    /// A transaction entered the mempool or was connected in a block.
+    Transaction(Transaction),
+}
+
+fn zmq_error(err: zmq::Error) -> SpacechainError {
+    SpacechainError::Backend(format!("ZMQ: {}", err))
+}
+
+pub struct ZmqSubscriber {
+    // The socket has to be dropped before its context.
+    socket: zmq::Socket,
+    _context: zmq::Context,
+}
+
+impl ZmqSubscriber {
+    /// Subscribes to the ZMQ endpoints of the config file. Returns `None` if
+    /// none is set.
+    pub fn connect(cfg: &ConfigFile) -> Result<Option<Self>> {
+        if cfg.zmq_hashblock_url.is_none() && cfg.zmq_rawtx_url.is_none() {
+            return Ok(None);
+        }
+
+        if cfg.blockchain != Backend::BitcoinRpc {
+            return Err(SpacechainError::Config(format!(
+                "ZMQ notifications need the bitcoin_rpc backend, not {}.",
+                cfg.blockchain
+            )));
+        }
+
+        let context = zmq::Context::new();
+        let socket = context.socket(zmq::SUB).map_err(zmq_error)?;
+
+        if let Some(url) = &cfg.zmq_hashblock_url {
+            socket.connect(url).map_err(zmq_error)?;
+            socket.set_subscribe(TOPIC_HASHBLOCK).map_err(zmq_error)?;
+        }
+
+        // bitcoind may publish both topics on the same endpoint.
+        if let Some(url) = &cfg.zmq_rawtx_url {
+            if cfg.zmq_hashblock_url.as_ref() != Some(url) {
+                socket.connect(url).map_err(zmq_error)?;
+            }
+            socket.set_subscribe(TOPIC_RAWTX).map_err(zmq_error)?;
+        }
+
+        Ok(Some(ZmqSubscriber {
+            socket,
+            _context: context,
+        }))
+    }
+
+    /// Waits up to `timeout` for the next notification. Returns `None` on
+    /// timeout. Malformed messages are skipped, without extending the wait.
+    pub fn recv(&self, timeout: Duration) -> Result<Option<ZmqEvent>> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let timeout_ms = i64::try_from(remaining.as_millis()).unwrap_or(i64::MAX);
+
+            if self
+                .socket
+                .poll(zmq::POLLIN, timeout_ms)
+                .map_err(zmq_error)?
+                == 0
+            {
+                return Ok(None);
+            }
+
+            // A notification is made of the topic, the body and a sequence
+            // number.
+            let frames = self.socket.recv_multipart(0).map_err(zmq_error)?;
+            let (topic, body) = match frames.as_slice() {
+                [topic, body, ..] => (topic.as_slice(), body.as_slice()),
+                _ => continue,
+            };
+
+            match topic {
+                TOPIC_HASHBLOCK => {
+                    // The hash is sent in the byte order it is displayed in.
+                    let mut hash = body.to_vec();
+                    hash.reverse();
+                    if let Ok(hash) = BlockHash::from_slice(&hash) {
+                        return Ok(Some(ZmqEvent::Block(hash)));
+                    }
+                }
+                TOPIC_RAWTX => {
+                    if let Ok(tx) = deserialize::<Transaction>(body) {
+                        return Ok(Some(ZmqEvent::Transaction(tx)));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+
+use bdk::bitcoin::Network;
+use serde_json::{json, Value};
+
+use spacechains::config_file::{self, Backend, ChainNetwork};
+use spacechains::server::RpcServer;
+use spacechains::{covenant, utils};
+
+/// POSTs a JSON-RPC call and returns the HTTP status and the JSON body.
+fn call(address: SocketAddr, auth: Option<&str>, body: &Value) -> (u16, Value) {
+    let body = body.to_string();
+
+    let mut request = format!(
+        "POST / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+        address,
+        body.len()
+    );
+    if let Some(auth) = auth {
+        request.push_str(&format!(
+            "Authorization: Basic {}\r\n",
+            base64::encode(auth)
+        ));
+    }
+    request.push_str("\r\n");
+    request.push_str(&body);
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let status = response[9..12].parse().unwrap();
+    let body = response
+        .split_once("\r\n\r\n")
+        .map(|(_, body)| serde_json::from_str(body).unwrap_or(Value::Null))
+        .unwrap_or(Value::Null);
+
+    (status, body)
+}
+
+#[test]
+fn serve_help_and_getcovenanttip() {
+    let mut cfg = config_file::default_config(ChainNetwork::Testnet, Backend::Electrum);
+    cfg.datadir =
+        std::env::temp_dir().join(format!("spacechains-test-server-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&cfg.datadir);
+    // Nothing listens there, so that syncing the covenant wallet fails fast.
+    cfg.electrum_url = "tcp://127.0.0.1:1".to_string();
+
+    let data_dir = utils::get_data_dir(&cfg).unwrap();
+    std::fs::copy(
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("convenant.db"),
+        data_dir.join("convenant.db"),
+    )
+    .unwrap();
+    covenant::set_covenant_network(&cfg, Network::Testnet).unwrap();
+
+    let cookie_path = data_dir.join(".cookie");
+    let server = RpcServer::bind("127.0.0.1:0", &cookie_path).unwrap();
+    let address = server.local_addr().unwrap();
+    let cookie = std::fs::read_to_string(&cookie_path).unwrap();
+
+    let handle = std::thread::spawn(move || server.run(&cfg, "spacechains.conf").map(|_| cfg));
+
+    let (status, _) = call(address, None, &json!({"id": 1, "method": "help"}));
+    assert_eq!(status, 401);
+
+    let (status, _) = call(
+        address,
+        Some("__cookie__:wrong"),
+        &json!({"id": 1, "method": "help"}),
+    );
+    assert_eq!(status, 401);
+
+    let (status, response) = call(address, Some(&cookie), &json!({"id": 1, "method": "help"}));
+    assert_eq!(status, 200);
+    assert_eq!(response["id"], 1);
+    assert!(response["result"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("getcovenanttip")));
+
+    // The covenant wallet cannot be synced, which is a backend error.
+    let (status, response) = call(
+        address,
+        Some(&cookie),
+        &json!({"id": 2, "method": "getcovenanttip"}),
+    );
+    assert_eq!(status, 500);
+    assert_eq!(response["id"], 2);
+    assert_eq!(response["error"]["code"], -14);
+
+    let (status, response) = call(
+        address,
+        Some(&cookie),
+        &json!({"id": 3, "method": "backup", "params": ["w1", "../w1.backup"]}),
+    );
+    assert_eq!(status, 500);
+    assert_eq!(response["error"]["code"], -19);
+
+    let (status, response) = call(address, Some(&cookie), &json!({"id": 4, "method": "nope"}));
+    assert_eq!(status, 500);
+    assert_eq!(response["error"]["code"], -32601);
+
+    let (status, _) = call(address, Some(&cookie), &json!({"id": 5, "method": "stop"}));
+    assert_eq!(status, 200);
+
+    let cfg = handle.join().unwrap().unwrap();
+    assert!(!cookie_path.exists());
+
+    std::fs::remove_dir_all(&cfg.datadir).unwrap();
+}
@@ -0,0 +1,187 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use bdk::bitcoin::{
+    consensus::serialize, hashes::hex::ToHex, BlockHash, OutPoint, PackedLockTime, Script,
+    Sequence, Transaction, TxIn, TxOut, Witness,
+};
+
+use spacechains::config_file::{self, Backend, ChainNetwork};
+use spacechains::zmq_subscriber::{ZmqEvent, ZmqSubscriber};
+
+/// Path of the `mock_zmq_publisher` example, which `cargo test` builds
+/// next to the test binaries.
+fn mock_publisher_path() -> PathBuf {
+    let mut path = std::env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.join("examples").join("mock_zmq_publisher")
+}
+
+struct MockPublisher {
+    child: Child,
+    stdin: ChildStdin,
+}
+
+impl MockPublisher {
+    fn start(endpoint: &str) -> Self {
+        let mut child = Command::new(mock_publisher_path())
+            .arg(endpoint)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let stdin = child.stdin.take().unwrap();
+
+        MockPublisher { child, stdin }
+    }
+
+    fn publish(&mut self, line: &str) {
+        writeln!(self.stdin, "{}", line).unwrap();
+        self.stdin.flush().unwrap();
+    }
+}
+
+impl Drop for MockPublisher {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Publishes `line` until the subscriber receives a notification, as a
+/// ZMQ subscription takes a moment to reach the publisher. The extra copies
+/// are then drained.
+fn publish_and_recv(
+    publisher: &mut MockPublisher,
+    subscriber: &ZmqSubscriber,
+    line: &str,
+) -> ZmqEvent {
+    for _ in 0..50 {
+        publisher.publish(line);
+        if let Some(event) = subscriber.recv(Duration::from_millis(100)).unwrap() {
+            while subscriber
+                .recv(Duration::from_millis(200))
+                .unwrap()
+                .is_some()
+            {}
+            return event;
+        }
+    }
+    panic!("No notification received for {}", line);
+}
+
+#[test]
+fn receives_blocks_and_transactions_from_the_mock_publisher() {
+    let socket_path =
+        std::env::temp_dir().join(format!("spacechains-test-zmq-{}.sock", std::process::id()));
+    let endpoint = format!("ipc://{}", socket_path.to_string_lossy());
+
+    let mut cfg = config_file::default_config(ChainNetwork::Testnet, Backend::BitcoinRpc);
+    cfg.zmq_hashblock_url = Some(endpoint.clone());
+    cfg.zmq_rawtx_url = Some(endpoint.clone());
+
+    let mut publisher = MockPublisher::start(&endpoint);
+    let subscriber = ZmqSubscriber::connect(&cfg).unwrap().unwrap();
+
+    let block_hash = "00000000000000000002a7c4c1e48d76c5a37902165a270156b7a8d72728a054";
+    match publish_and_recv(
+        &mut publisher,
+        &subscriber,
+        &format!("hashblock {}", block_hash),
+    ) {
+        ZmqEvent::Block(hash) => assert_eq!(hash, block_hash.parse::<BlockHash>().unwrap()),
+        event => panic!("Unexpected event {:?}", event),
+    }
+
+    let tx = Transaction {
+        version: 2,
+        lock_time: PackedLockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Script::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: 1000,
+            script_pubkey: Script::new(),
+        }],
+    };
+
+    // The malformed transaction is skipped.
+    publisher.publish("rawtx 00");
+    match publish_and_recv(
+        &mut publisher,
+        &subscriber,
+        &format!("rawtx {}", serialize(&tx).to_hex()),
+    ) {
+        ZmqEvent::Transaction(received) => assert_eq!(received.txid(), tx.txid()),
+        event => panic!("Unexpected event {:?}", event),
+    }
+
+    drop(publisher);
+    let _ = std::fs::remove_file(&socket_path);
+}
+
+#[test]
+fn malformed_messages_do_not_extend_the_timeout() {
+    let socket_path = std::env::temp_dir().join(format!(
+        "spacechains-test-zmq-noisy-{}.sock",
+        std::process::id()
+    ));
+    let endpoint = format!("ipc://{}", socket_path.to_string_lossy());
+
+    let mut cfg = config_file::default_config(ChainNetwork::Testnet, Backend::BitcoinRpc);
+    cfg.zmq_rawtx_url = Some(endpoint.clone());
+
+    let mut publisher = MockPublisher::start(&endpoint);
+    let subscriber = ZmqSubscriber::connect(&cfg).unwrap().unwrap();
+
+    // Wait for the subscription to reach the publisher.
+    let tx = Transaction {
+        version: 2,
+        lock_time: PackedLockTime::ZERO,
+        input: vec![],
+        output: vec![TxOut {
+            value: 1000,
+            script_pubkey: Script::new(),
+        }],
+    };
+    publish_and_recv(
+        &mut publisher,
+        &subscriber,
+        &format!("rawtx {}", serialize(&tx).to_hex()),
+    );
+
+    // Keep publishing malformed transactions for longer than the timeout.
+    let noise = std::thread::spawn(move || {
+        for _ in 0..60 {
+            publisher.publish("rawtx 00");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    });
+
+    let start = Instant::now();
+    assert!(subscriber
+        .recv(Duration::from_millis(500))
+        .unwrap()
+        .is_none());
+    assert!(start.elapsed() < Duration::from_millis(1500));
+
+    noise.join().unwrap();
+    let _ = std::fs::remove_file(&socket_path);
+}
+
+#[test]
+fn connect_needs_the_bitcoin_rpc_backend() {
+    let mut cfg = config_file::default_config(ChainNetwork::Testnet, Backend::Electrum);
+    assert!(ZmqSubscriber::connect(&cfg).unwrap().is_none());
+
+    cfg.zmq_hashblock_url = Some("tcp://127.0.0.1:28332".to_string());
+    assert!(ZmqSubscriber::connect(&cfg).is_err());
+}